@@ -0,0 +1,181 @@
+//! Keeping the screen on during set hours, or while told to over MQTT, for
+//! a Kindle used as a dashboard
+
+use crate::config::KeepAwakeConfig;
+use crate::display::RECONNECT_DELAY;
+use libopenlipc_sys::power::{KeepAwake, Power};
+use libopenlipc_sys::LipcBackend;
+use log::{info, warn};
+use mqtt_simple::{Client, QoS};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Retained, `1` while the screen is kept on and `0` otherwise
+pub const KEEP_AWAKE_TOPIC: &str = "keep_awake";
+
+/// What the topic asked for last, `None` until it says anything
+pub type Request = Arc<Mutex<Option<bool>>>;
+
+/// `on` or `off`, and the like. An empty message, which clears a retained
+/// one, takes the request back.
+pub fn parse_request(payload: &[u8]) -> Result<Option<bool>, String> {
+    let payload = String::from_utf8_lossy(payload).trim().to_ascii_lowercase();
+    match payload.as_str() {
+        "1" | "on" | "true" => Ok(Some(true)),
+        "0" | "off" | "false" => Ok(Some(false)),
+        "" => Ok(None),
+        other => Err(format!("Expected on or off, got {}", other)),
+    }
+}
+
+/// Whether the screen should be kept on at `now`
+pub fn should_keep_awake(config: &KeepAwakeConfig, request: Option<bool>, now: u64) -> bool {
+    if !config.enabled {
+        return false;
+    }
+    if config.hours.is_none() && config.topic.is_none() {
+        return true;
+    }
+    let local = now as i64 + config.utc_offset_mins * 60;
+    let minute = (local.rem_euclid(86400) / 60) as u32;
+    config.hours.is_some_and(|h| h.contains(minute)) || request == Some(true)
+}
+
+/// Holds the screensaver off while it should be, and lets it be otherwise
+pub struct KeepAwakeController<'a, B: LipcBackend> {
+    power: Power<'a, B>,
+    guard: Option<KeepAwake<'a, B>>,
+    request: Request,
+}
+
+impl<'a, B: LipcBackend> KeepAwakeController<'a, B> {
+    pub fn new(r: &'a B) -> Self {
+        KeepAwakeController {
+            power: Power::new(r),
+            guard: None,
+            request: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Where the topic's requests go
+    pub fn request(&self) -> Request {
+        self.request.clone()
+    }
+
+    pub fn is_awake(&self) -> bool {
+        self.guard.is_some()
+    }
+
+    /// Holds or lets go of the screen as `config` says for `now`, waking it
+    /// up when it starts being held. Returns the new state if it changed.
+    pub fn tick(&mut self, config: &KeepAwakeConfig, now: u64) -> Result<Option<bool>, String> {
+        let request = *self.request.lock().unwrap();
+        let wanted = should_keep_awake(config, request, now);
+        if wanted == self.is_awake() {
+            return Ok(None);
+        }
+        if wanted {
+            self.guard = Some(self.power.keep_awake()?);
+            if !self.power.is_active().unwrap_or(true) {
+                self.power.wake_up()?;
+            }
+        } else {
+            self.guard = None;
+        }
+        Ok(Some(wanted))
+    }
+
+    /// Lets the screensaver come again, returning whether the screen was
+    /// kept on until now
+    pub fn release(&mut self) -> bool {
+        self.guard.take().is_some()
+    }
+}
+
+/// Keeps `request` up to date with what comes on `topic`, the full topic,
+/// in the background
+pub fn spawn(broker: String, client_id: String, topic: String, request: Request) {
+    thread::spawn(move || loop {
+        let res = (|| -> Result<(), Box<dyn std::error::Error>> {
+            let mut client = Client::new(client_id.clone(), broker.clone())?;
+            let mut client = client.connect(30)?;
+            client.subscribe(&topic, QoS::AtLeastOnce)?;
+            loop {
+                let msg = client.next_message()?;
+                match parse_request(&msg.payload) {
+                    Ok(r) => {
+                        info!("Keep awake requested: {:?}", r);
+                        *request.lock().unwrap() = r;
+                    }
+                    Err(e) => warn!("{}: {}", topic, e),
+                }
+            }
+        })();
+        if let Err(e) = res {
+            warn!("Keep awake: {}", e);
+        }
+        thread::sleep(RECONNECT_DELAY);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Hours;
+
+    #[test]
+    fn test_should_keep_awake() {
+        assert_eq!(parse_request(b"ON\n"), Ok(Some(true)));
+        assert_eq!(parse_request(b"0"), Ok(Some(false)));
+        assert_eq!(parse_request(b""), Ok(None));
+        assert!(parse_request(b"maybe").is_err());
+
+        let mut config = KeepAwakeConfig::default();
+        assert!(!should_keep_awake(&config, Some(true), 0));
+        config.enabled = true;
+        assert!(should_keep_awake(&config, None, 0));
+
+        config.hours = Some(Hours {
+            from: 8 * 60,
+            until: 22 * 60,
+        });
+        config.utc_offset_mins = 120;
+        // 07:00 UTC is 09:00 local
+        assert!(should_keep_awake(&config, None, 7 * 3600));
+        assert!(!should_keep_awake(&config, None, 21 * 3600));
+        assert!(should_keep_awake(&config, Some(true), 21 * 3600));
+        assert!(!should_keep_awake(&config, Some(false), 21 * 3600));
+    }
+
+    #[cfg(feature = "mock")]
+    #[test]
+    fn test_controller() {
+        use libopenlipc_sys::catalog::powerd;
+        use libopenlipc_sys::{LipcResult, MockLipc};
+
+        let r = MockLipc::new();
+        r.set_prop(powerd::SERVICE, "preventScreenSaver", LipcResult::NUM(0));
+        r.set_prop(
+            powerd::SERVICE,
+            "state",
+            LipcResult::STR(String::from("screenSaver")),
+        );
+        let config = KeepAwakeConfig {
+            enabled: true,
+            topic: Some(String::from("keep_awake/set")),
+            ..Default::default()
+        };
+        let mut awake = KeepAwakeController::new(&r);
+        assert_eq!(awake.tick(&config, 0), Ok(None));
+
+        *awake.request().lock().unwrap() = Some(true);
+        assert_eq!(awake.tick(&config, 0), Ok(Some(true)));
+        assert_eq!(r.get_int(powerd::PREVENT_SCREEN_SAVER), Ok(1));
+        assert_eq!(r.get_int(powerd::WAKE_UP), Ok(1));
+        assert_eq!(awake.tick(&config, 10), Ok(None));
+
+        assert!(awake.release());
+        assert_eq!(r.get_int(powerd::PREVENT_SCREEN_SAVER), Ok(0));
+        assert!(!awake.release());
+    }
+}
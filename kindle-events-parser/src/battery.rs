@@ -0,0 +1,263 @@
+//! Battery health: temperature, charging state, charge rate estimated over a
+//! sliding window, and alerts when something looks wrong
+
+use crate::config::BatteryConfig;
+use crate::publisher::Kind;
+use libopenlipc_sys::catalog::powerd;
+use libopenlipc_sys::LipcBackend;
+use serde_json::json;
+use std::collections::VecDeque;
+
+pub const ALERT_TOPIC: &str = "battery/alert";
+
+/// What the values published on `topic` by `BatteryMonitor::sample` are
+pub fn kind(topic: &str) -> Kind {
+    if topic == ALERT_TOPIC {
+        Kind::Json
+    } else {
+        Kind::Number
+    }
+}
+
+/// Below this, the rate is mostly noise from the level being an integer
+const MIN_RATE_SPAN_SECS: u64 = 600;
+/// How far above the threshold the level must go before a low battery alert
+/// can be raised again
+const LOW_HYSTERESIS: i32 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reading {
+    pub level: i32,
+    pub temperature: Option<i32>,
+    pub charging: bool,
+}
+
+/// Reads the battery state from powerd
+pub fn read<B: LipcBackend>(r: &B) -> Result<Reading, String> {
+    Ok(Reading {
+        level: r.get_int(powerd::BATT_LEVEL)?,
+        // Not every model has it
+        temperature: r.get_int(powerd::BATT_TEMPERATURE).ok(),
+        charging: r.get_int(powerd::IS_CHARGING)? != 0,
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Alert {
+    Low,
+    FastDrain,
+    HighTemperature,
+}
+
+impl Alert {
+    fn name(&self) -> &'static str {
+        match self {
+            Alert::Low => "low_battery",
+            Alert::FastDrain => "fast_drain",
+            Alert::HighTemperature => "high_temperature",
+        }
+    }
+}
+
+pub struct BatteryMonitor {
+    config: BatteryConfig,
+    /// (unix timestamp, level), oldest first, all with the same charging state
+    samples: VecDeque<(u64, i32)>,
+    charging: Option<bool>,
+    /// Alerts currently raised, so each is only published once
+    raised: Vec<Alert>,
+}
+
+impl BatteryMonitor {
+    pub fn new(config: BatteryConfig) -> Self {
+        BatteryMonitor {
+            config,
+            samples: VecDeque::new(),
+            charging: None,
+            raised: vec![],
+        }
+    }
+
+    /// Change of the level, in percent per hour; negative while discharging
+    pub fn rate(&self) -> Option<f64> {
+        let (t0, l0) = self.samples.front()?;
+        let (t1, l1) = self.samples.back()?;
+        let span = t1.saturating_sub(*t0);
+        if span < MIN_RATE_SPAN_SECS {
+            return None;
+        }
+        Some(f64::from(l1 - l0) * 3600.0 / span as f64)
+    }
+
+    /// Takes `reading` into account and returns the (topic, message) pairs to
+    /// publish
+    pub fn sample(&mut self, reading: Reading, now: u64) -> Vec<(String, String)> {
+        if self.charging != Some(reading.charging) {
+            // The rate while charging says nothing about the rate on battery
+            self.samples.clear();
+            self.charging = Some(reading.charging);
+        }
+        if self.samples.back().is_some_and(|(t, _)| *t > now) {
+            // The clock went back, as after an NTP sync on resume
+            self.samples.clear();
+        }
+        self.samples.push_back((now, reading.level));
+        while let Some((t, _)) = self.samples.front() {
+            if now.saturating_sub(*t) <= self.config.window_secs {
+                break;
+            }
+            self.samples.pop_front();
+        }
+
+        let mut out = vec![(
+            String::from("battery/charging"),
+            (reading.charging as i32).to_string(),
+        )];
+        if let Some(temperature) = reading.temperature {
+            out.push((String::from("battery/temperature"), temperature.to_string()));
+        }
+        let rate = self.rate();
+        if let Some(rate) = rate {
+            out.push((String::from("battery/rate"), format!("{:.1}", rate)));
+            if rate < 0.0 {
+                let minutes = f64::from(reading.level) / -rate * 60.0;
+                out.push((
+                    String::from("battery/time_to_empty"),
+                    format!("{:.0}", minutes),
+                ));
+            } else if rate > 0.0 {
+                let minutes = f64::from(100 - reading.level) / rate * 60.0;
+                out.push((
+                    String::from("battery/time_to_full"),
+                    format!("{:.0}", minutes),
+                ));
+            }
+        }
+
+        let low = !reading.charging && reading.level <= self.config.low_percent;
+        let low_cleared =
+            reading.charging || reading.level > self.config.low_percent + LOW_HYSTERESIS;
+        let draining = rate.is_some_and(|r| -r >= self.config.fast_drain_percent_per_hour);
+        let hot = reading
+            .temperature
+            .is_some_and(|t| t >= self.config.high_temperature);
+        for (alert, active, cleared) in [
+            (Alert::Low, low, low_cleared),
+            (Alert::FastDrain, draining, !draining),
+            (Alert::HighTemperature, hot, !hot),
+        ]
+        .iter()
+        {
+            let raised = self.raised.contains(alert);
+            if *active && !raised {
+                self.raised.push(*alert);
+                let msg = json!({
+                    "alert": alert.name(),
+                    "level": reading.level,
+                    "temperature": reading.temperature,
+                    "rate": rate,
+                });
+                out.push((String::from(ALERT_TOPIC), msg.to_string()));
+            } else if *cleared && raised {
+                self.raised.retain(|a| a != alert);
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn reading(level: i32) -> Reading {
+        Reading {
+            level,
+            temperature: Some(80),
+            charging: false,
+        }
+    }
+
+    fn alerts(out: &[(String, String)]) -> Vec<String> {
+        out.iter()
+            .filter(|(topic, _)| topic == ALERT_TOPIC)
+            .map(|(_, m)| {
+                let v: Value = serde_json::from_str(m).unwrap();
+                v["alert"].as_str().unwrap().to_string()
+            })
+            .collect()
+    }
+
+    fn value<'a>(out: &'a [(String, String)], topic: &str) -> Option<&'a str> {
+        out.iter()
+            .find(|(t, _)| t == topic)
+            .map(|(_, m)| m.as_str())
+    }
+
+    #[test]
+    fn test_rate() {
+        let mut m = BatteryMonitor::new(BatteryConfig::default());
+        let out = m.sample(reading(80), 0);
+        assert_eq!(value(&out, "battery/rate"), None);
+        assert_eq!(value(&out, "battery/temperature"), Some("80"));
+        assert_eq!(value(&out, "battery/charging"), Some("0"));
+
+        m.sample(reading(79), 900);
+        let out = m.sample(reading(78), 1800);
+        assert_eq!(value(&out, "battery/rate"), Some("-4.0"));
+        assert_eq!(value(&out, "battery/time_to_empty"), Some("1170"));
+
+        // Only the last hour counts
+        m.sample(reading(78), 3600);
+        let out = m.sample(reading(78), 5400);
+        assert_eq!(value(&out, "battery/rate"), Some("0.0"));
+
+        let out = m.sample(
+            Reading {
+                charging: true,
+                ..reading(78)
+            },
+            5460,
+        );
+        assert_eq!(value(&out, "battery/rate"), None);
+        assert_eq!(value(&out, "battery/charging"), Some("1"));
+    }
+
+    #[test]
+    fn test_clock_going_back() {
+        let mut m = BatteryMonitor::new(BatteryConfig::default());
+        m.sample(reading(80), 10_000);
+        m.sample(reading(79), 10_900);
+        // Starts over rather than underflowing
+        let out = m.sample(reading(79), 5_000);
+        assert_eq!(value(&out, "battery/rate"), None);
+        m.sample(reading(78), 5_900);
+        assert_eq!(m.rate(), Some(-4.0));
+    }
+
+    #[test]
+    fn test_alerts() {
+        let mut m = BatteryMonitor::new(BatteryConfig::default());
+        assert!(alerts(&m.sample(reading(16), 0)).is_empty());
+        assert_eq!(alerts(&m.sample(reading(15), 60)), vec!["low_battery"]);
+        // Raised only once
+        assert!(alerts(&m.sample(reading(14), 120)).is_empty());
+        // Lost 4% in 10 minutes
+        assert_eq!(alerts(&m.sample(reading(12), 660)), vec!["fast_drain"]);
+
+        let hot = Reading {
+            temperature: Some(115),
+            ..reading(12)
+        };
+        assert_eq!(alerts(&m.sample(hot, 720)), vec!["high_temperature"]);
+
+        // Charging clears the low battery alert, but not below the threshold
+        let charging = Reading {
+            charging: true,
+            ..reading(12)
+        };
+        m.sample(charging, 780);
+        assert_eq!(alerts(&m.sample(reading(12), 840)), vec!["low_battery"]);
+    }
+}
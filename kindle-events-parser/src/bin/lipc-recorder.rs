@@ -0,0 +1,150 @@
+use kindle_events_screen::config::{LogConfig, LogTarget};
+use kindle_events_screen::{logging, recording};
+use std::env;
+use std::process::exit;
+
+const USAGE: &str = "Usage:
+    lipc-recorder record <file> [service...]
+    lipc-recorder replay <file> [--speed <factor>] [--publish]
+
+record appends every event of the given services (by default, all the usual
+com.lab126 ones) to <file>, one JSON object per line.
+
+replay feeds a recording through the same routing as the daemon, keeping the
+original timing divided by --speed (0 for no waiting). Messages are printed
+instead of published unless --publish is given.";
+
+#[cfg(feature = "native")]
+fn record(file: &str, services: &[String]) -> Result<(), String> {
+    record_on(&libopenlipc_sys::rLIPC::new()?, file, services)
+}
+
+#[cfg(all(feature = "dbus", not(feature = "native")))]
+fn record(file: &str, services: &[String]) -> Result<(), String> {
+    record_on(&libopenlipc_sys::DbusLipc::new()?, file, services)
+}
+
+#[cfg(any(feature = "native", feature = "dbus"))]
+fn record_on<B: libopenlipc_sys::LipcBackend>(
+    r: &B,
+    file: &str,
+    services: &[String],
+) -> Result<(), String> {
+    use std::fs::OpenOptions;
+    use std::sync::{Arc, Mutex};
+
+    let out = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(file)
+        .map_err(|e| format!("Failed to open {}: {}", file, e))?;
+    let services: Vec<&str> = if services.is_empty() {
+        recording::DEFAULT_SERVICES.to_vec()
+    } else {
+        services.iter().map(String::as_str).collect()
+    };
+
+    recording::record(r, &services, Arc::new(Mutex::new(out)))?;
+    println!("Recording {:?} to {}, ctrl-c to stop", services, file);
+    loop {
+        std::thread::sleep(std::time::Duration::from_secs(60));
+    }
+}
+
+#[cfg(not(any(feature = "native", feature = "dbus")))]
+fn record(_file: &str, _services: &[String]) -> Result<(), String> {
+    Err(String::from(
+        "Built without the `native` or `dbus` feature, there is no LIPC bus to record",
+    ))
+}
+
+#[cfg(feature = "mock")]
+fn replay(file: &str, speed: f64, publish: bool) -> Result<(), String> {
+    use kindle_events_screen::config::{Config, DEFAULT_CONFIG_PATH};
+    use kindle_events_screen::publisher::{Kind, Message, Publisher};
+    use kindle_events_screen::{device, run_and_match, subscribe_all};
+    use libopenlipc_sys::MockLipc;
+    use log::warn;
+    use std::fs::File;
+    use std::io::BufReader;
+
+    let input = File::open(file).map_err(|e| format!("Failed to open {}: {}", file, e))?;
+    let events = recording::read_recording(BufReader::new(input))?;
+
+    let bus = MockLipc::new();
+    let config = Config::load(DEFAULT_CONFIG_PATH)?;
+    let publisher = Publisher::new(
+        &config,
+        &device::device_id(&bus, config.mqtt.device_id.as_deref()),
+    )?;
+    subscribe_all(&bus, &config.events.filters, move |ev| {
+        if let Some((topic, m)) = run_and_match(ev) {
+            if !publish {
+                println!("Would publish {} to {}", m, publisher.topic(topic));
+            } else if let Err(e) = publisher.send_message(&Message {
+                topic,
+                source: &ev.source,
+                event: &ev.name,
+                value: m.as_str(),
+                kind: Kind::Number,
+            }) {
+                warn!("Failed to publish: {}", e);
+            }
+        }
+    })?;
+    println!("Replaying {} events from {}", events.len(), file);
+    recording::replay(&events, &bus, speed);
+    Ok(())
+}
+
+#[cfg(not(feature = "mock"))]
+fn replay(_file: &str, _speed: f64, _publish: bool) -> Result<(), String> {
+    Err(String::from(
+        "Built without the `mock` feature, there is no bus to replay into",
+    ))
+}
+
+fn main() {
+    let log = LogConfig {
+        target: Some(LogTarget::Stderr),
+        ..LogConfig::default()
+    };
+    // Only fails if a logger is already installed
+    let _ = logging::init(&log, false);
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.as_slice() {
+        [cmd, file, services @ ..] if cmd == "record" => record(file, services),
+        [cmd, file, opts @ ..] if cmd == "replay" => {
+            let mut speed = 1.0;
+            let mut publish = false;
+            let mut opts = opts.iter();
+            while let Some(opt) = opts.next() {
+                match opt.as_str() {
+                    "--publish" => publish = true,
+                    "--speed" => match opts.next().map(|s| s.parse()) {
+                        Some(Ok(s)) => speed = s,
+                        _ => {
+                            eprintln!("--speed needs a number\n\n{}", USAGE);
+                            exit(1);
+                        }
+                    },
+                    _ => {
+                        eprintln!("Unknown option {}\n\n{}", opt, USAGE);
+                        exit(1);
+                    }
+                }
+            }
+            replay(file, speed, publish)
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            exit(1);
+        }
+    };
+
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
@@ -0,0 +1,238 @@
+//! Command line of the daemon: subcommands, and the global flags that
+//! override the configuration file
+
+use crate::config::{Config, LogLevel, DEFAULT_CONFIG_PATH};
+use libopenlipc_sys::LipcBackend;
+
+pub const USAGE: &str = "Usage:
+    kindle-events-screen [options] [command]
+
+Commands:
+    run [--daemon]              listen and publish, the default
+    once                        read the polled properties and the battery,
+                                publish them and exit
+    dump                        print every event until interrupted
+    get <service> <property>    print a property
+    set <service> <property> <value>
+                                set a property, as an int if <value> is one
+    check-config                validate the configuration and print it
+    help                        show this
+
+Options:
+    -c, --config <path>         default: /mnt/us/kindle-events/config.toml
+    -b, --broker <host>         overrides mqtt.broker
+    -v, --verbose               log more, can be repeated
+    -q, --quiet                 log less, can be repeated";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Run {
+        daemon: bool,
+    },
+    Once,
+    Dump,
+    Get {
+        service: String,
+        property: String,
+    },
+    Set {
+        service: String,
+        property: String,
+        value: String,
+    },
+    CheckConfig,
+    Help,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Args {
+    pub config_path: String,
+    pub broker: Option<String>,
+    /// How many levels above (or below, if negative) the configured log level
+    pub verbosity: i32,
+    pub command: Command,
+}
+
+/// Parses the arguments, without the program name. Options can come before
+/// or after the command.
+pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Args, String> {
+    let mut config_path = String::from(DEFAULT_CONFIG_PATH);
+    let mut broker = None;
+    let mut verbosity = 0;
+    let mut daemon = false;
+    let mut positional = vec![];
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-c" | "--config" => {
+                config_path = args.next().ok_or("--config needs a path")?;
+            }
+            "-b" | "--broker" => {
+                broker = Some(args.next().ok_or("--broker needs a host")?);
+            }
+            "-v" | "--verbose" => verbosity += 1,
+            "-q" | "--quiet" => verbosity -= 1,
+            "--daemon" => daemon = true,
+            "-h" | "--help" => positional.insert(0, String::from("help")),
+            s if s.starts_with('-') && s.len() > 1 => {
+                return Err(format!("Unknown option {}", s));
+            }
+            _ => positional.push(arg),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    let command = match (positional.next().as_deref(), positional.len()) {
+        (None, _) | (Some("run"), 0) => Command::Run { daemon },
+        (Some("once"), 0) => Command::Once,
+        (Some("dump"), 0) => Command::Dump,
+        (Some("get"), 2) => Command::Get {
+            service: positional.next().unwrap(),
+            property: positional.next().unwrap(),
+        },
+        (Some("set"), 3) => Command::Set {
+            service: positional.next().unwrap(),
+            property: positional.next().unwrap(),
+            value: positional.next().unwrap(),
+        },
+        (Some("check-config"), 0) => Command::CheckConfig,
+        (Some("help"), _) => Command::Help,
+        (Some(cmd @ ("run" | "once" | "dump" | "get" | "set" | "check-config")), _) => {
+            return Err(format!("Wrong number of arguments for {}", cmd));
+        }
+        (Some(cmd), _) => return Err(format!("Unknown command {}", cmd)),
+    };
+    if daemon && command != (Command::Run { daemon }) {
+        return Err(String::from("--daemon only applies to run"));
+    }
+
+    Ok(Args {
+        config_path,
+        broker,
+        verbosity,
+        command,
+    })
+}
+
+fn shift(level: LogLevel, by: i32) -> LogLevel {
+    const LEVELS: [LogLevel; 6] = [
+        LogLevel::Off,
+        LogLevel::Error,
+        LogLevel::Warn,
+        LogLevel::Info,
+        LogLevel::Debug,
+        LogLevel::Trace,
+    ];
+    let i = LEVELS.iter().position(|l| *l == level).unwrap() as i32 + by;
+    LEVELS[i.clamp(0, LEVELS.len() as i32 - 1) as usize]
+}
+
+impl Args {
+    /// Reads the configuration file, with the command line overrides applied
+    pub fn load_config(&self) -> Result<Config, String> {
+        let mut config = Config::load(&self.config_path)?;
+        if let Some(broker) = &self.broker {
+            config.mqtt.broker = broker.clone();
+        }
+        config.log.level = shift(config.log.level, self.verbosity);
+        Ok(config)
+    }
+}
+
+/// Reads a property, whichever its type
+pub fn get<B: LipcBackend>(r: &B, service: &str, property: &str) -> Result<String, String> {
+    match r.get_int_prop(service, property) {
+        Ok(v) => Ok(v.to_string()),
+        Err(_) => r.get_str_prop(service, property),
+    }
+}
+
+/// Sets a property, as an int if `value` is one
+pub fn set<B: LipcBackend>(
+    r: &B,
+    service: &str,
+    property: &str,
+    value: &str,
+) -> Result<(), String> {
+    match value.parse() {
+        Ok(v) => r.set_int_prop(service, property, v),
+        Err(_) => r.set_str_prop(service, property, value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &str) -> Result<Args, String> {
+        parse(s.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn test_parse() {
+        let a = args("").unwrap();
+        assert_eq!(a.command, Command::Run { daemon: false });
+        assert_eq!(a.config_path, DEFAULT_CONFIG_PATH);
+        assert_eq!(
+            args("--daemon").unwrap().command,
+            Command::Run { daemon: true }
+        );
+
+        let a = args("-c /tmp/k.toml get com.lab126.powerd battLevel -v -v").unwrap();
+        assert_eq!(a.config_path, "/tmp/k.toml");
+        assert_eq!(a.verbosity, 2);
+        assert_eq!(
+            a.command,
+            Command::Get {
+                service: String::from("com.lab126.powerd"),
+                property: String::from("battLevel"),
+            }
+        );
+        let a = args("--broker 10.0.0.2 -q once").unwrap();
+        assert_eq!(a.broker.as_deref(), Some("10.0.0.2"));
+        assert_eq!((a.verbosity, a.command), (-1, Command::Once));
+        assert_eq!(args("check-config --help").unwrap().command, Command::Help);
+
+        assert!(args("get com.lab126.powerd").is_err());
+        assert!(args("once --daemon").is_err());
+        assert!(args("frobnicate").is_err());
+        assert!(args("--config").is_err());
+        assert!(args("-x").is_err());
+    }
+
+    #[test]
+    fn test_overrides() {
+        let a = args("-c /nonexistent.toml -b 10.0.0.2 -q").unwrap();
+        let config = a.load_config().unwrap();
+        assert_eq!(config.mqtt.broker, "10.0.0.2");
+        assert_eq!(config.log.level, LogLevel::Warn);
+        assert_eq!(shift(LogLevel::Info, 5), LogLevel::Trace);
+        assert_eq!(shift(LogLevel::Info, -5), LogLevel::Off);
+    }
+
+    #[cfg(feature = "mock")]
+    #[test]
+    fn test_get_set() {
+        use libopenlipc_sys::MockLipc;
+
+        let r = MockLipc::new();
+        set(&r, "com.lab126.powerd", "deferSuspend", "3000").unwrap();
+        set(
+            &r,
+            "com.lab126.appmgrd",
+            "start",
+            "app://com.lab126.booklet.home",
+        )
+        .unwrap();
+        assert_eq!(
+            get(&r, "com.lab126.powerd", "deferSuspend").unwrap(),
+            "3000"
+        );
+        assert_eq!(
+            get(&r, "com.lab126.appmgrd", "start").unwrap(),
+            "app://com.lab126.booklet.home"
+        );
+        assert!(get(&r, "com.lab126.powerd", "nothing").is_err());
+    }
+}
@@ -0,0 +1,52 @@
+//! Wall clock helpers; everything is in UTC, the Kindle has no notion of the
+//! local timezone that we could rely on
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Seconds since the epoch
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// `YYYY-MM-DD` for a count of days since the epoch
+pub fn date(days: i64) -> String {
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", y, m, d)
+}
+
+/// `YYYY-MM-DDTHH:MM:SSZ` for a unix timestamp
+pub fn iso8601(ts: u64) -> String {
+    let secs = ts % 86400;
+    format!(
+        "{}T{:02}:{:02}:{:02}Z",
+        date((ts / 86400) as i64),
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dates() {
+        assert_eq!(date(0), "1970-01-01");
+        assert_eq!(date(19782), "2024-02-29");
+        assert_eq!(iso8601(0), "1970-01-01T00:00:00Z");
+        assert_eq!(iso8601(1_709_251_199), "2024-02-29T23:59:59Z");
+    }
+}
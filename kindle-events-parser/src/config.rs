@@ -0,0 +1,643 @@
+//! Configuration of the daemon, read from a TOML file
+
+use crate::filter::Filter;
+use libopenlipc_sys::catalog::acxreaderplugin;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+
+pub const DEFAULT_CONFIG_PATH: &str = "/mnt/us/kindle-events/config.toml";
+pub const DEFAULT_STATS_PATH: &str = "/mnt/us/kindle-events/stats.json";
+pub const DEFAULT_LOG_PATH: &str = "/var/tmp/kindle-events.log";
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PropType {
+    Int,
+    Str,
+}
+
+/// A property read periodically and published when it changes
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PollConfig {
+    pub service: String,
+    pub property: String,
+    #[serde(rename = "type")]
+    pub kind: PropType,
+    pub topic: String,
+    pub interval_secs: u64,
+    /// Publish even if the value did not change, once it is this old
+    #[serde(default)]
+    pub max_age_secs: Option<u64>,
+    /// Up to this many seconds are randomly added to every interval
+    #[serde(default)]
+    pub jitter_secs: u64,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadFormat {
+    /// Just the value, e.g. `67`
+    #[default]
+    Raw,
+    /// The value along with the event it came from and when
+    Json,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(deny_unknown_fields, default)]
+pub struct PayloadConfig {
+    pub format: PayloadFormat,
+    /// Overrides `format` for some topics
+    pub topics: HashMap<String, PayloadFormat>,
+}
+
+impl PayloadConfig {
+    pub fn format_for(&self, topic: &str) -> PayloadFormat {
+        self.topics.get(topic).copied().unwrap_or(self.format)
+    }
+}
+
+/// Where to publish; topics are prefixed with `kindle/<device_id>`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields, default)]
+pub struct MqttConfig {
+    pub broker: String,
+    /// By default, the serial number of the Kindle
+    pub device_id: Option<String>,
+    pub payload: PayloadConfig,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        MqttConfig {
+            broker: String::from("192.168.20.125"),
+            device_id: None,
+            payload: PayloadConfig::default(),
+        }
+    }
+}
+
+/// An output for published messages; `topics` are patterns, relative to the
+/// device's prefix and with MQTT wildcards, of what the sink gets. Without
+/// them, it gets everything.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum SinkConfig {
+    /// The broker in `[mqtt]`
+    Mqtt {
+        #[serde(default)]
+        topics: Vec<String>,
+    },
+    Http {
+        url: String,
+        #[serde(default)]
+        topics: Vec<String>,
+    },
+    File {
+        path: String,
+        #[serde(default)]
+        topics: Vec<String>,
+    },
+    Stdout {
+        #[serde(default)]
+        topics: Vec<String>,
+    },
+    /// Numeric values in InfluxDB line protocol, to `udp://host:port` or an
+    /// `http://` write endpoint
+    Influx {
+        url: String,
+        #[serde(default)]
+        topics: Vec<String>,
+    },
+    /// Numeric values served on `http://<listen>/metrics`
+    Prometheus {
+        listen: String,
+        #[serde(default)]
+        topics: Vec<String>,
+    },
+}
+
+impl SinkConfig {
+    /// The same sink, getting every topic
+    pub fn without_topics(&self) -> SinkConfig {
+        let mut s = self.clone();
+        match &mut s {
+            SinkConfig::Mqtt { topics }
+            | SinkConfig::Http { topics, .. }
+            | SinkConfig::File { topics, .. }
+            | SinkConfig::Stdout { topics }
+            | SinkConfig::Influx { topics, .. }
+            | SinkConfig::Prometheus { topics, .. } => topics.clear(),
+        }
+        s
+    }
+
+    pub fn topics(&self) -> &[String] {
+        match self {
+            SinkConfig::Mqtt { topics }
+            | SinkConfig::Http { topics, .. }
+            | SinkConfig::File { topics, .. }
+            | SinkConfig::Stdout { topics }
+            | SinkConfig::Influx { topics, .. }
+            | SinkConfig::Prometheus { topics, .. } => topics,
+        }
+    }
+}
+
+fn default_sinks() -> Vec<SinkConfig> {
+    vec![SinkConfig::Mqtt { topics: vec![] }]
+}
+
+/// Which events are published
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(deny_unknown_fields, default)]
+pub struct EventsConfig {
+    /// Expressions of the `filter` module; only change on restart
+    pub filters: Filter,
+}
+
+/// Holding back messages on topics that change too often; what is held
+/// back is published later, so the last value always gets out
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct LimitConfig {
+    /// Patterns, relative to the device's prefix and with MQTT wildcards
+    pub topics: Vec<String>,
+    /// Publish once no new value came for this long
+    #[serde(default)]
+    pub debounce_secs: u64,
+    /// Publish at most once this often
+    #[serde(default)]
+    pub throttle_secs: u64,
+    /// Skip values equal to the last one published
+    #[serde(default)]
+    pub dedupe: bool,
+}
+
+/// Battery monitoring, and when to raise alerts
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields, default)]
+pub struct BatteryConfig {
+    pub enabled: bool,
+    pub interval_secs: u64,
+    /// How far back the charge rate is estimated from
+    pub window_secs: u64,
+    pub low_percent: i32,
+    /// Draining faster than this, in percent per hour, raises an alert
+    pub fast_drain_percent_per_hour: f64,
+    /// In the unit powerd reports, which is Fahrenheit
+    pub high_temperature: i32,
+}
+
+impl Default for BatteryConfig {
+    fn default() -> Self {
+        BatteryConfig {
+            enabled: true,
+            interval_secs: 60,
+            window_secs: 3600,
+            low_percent: 15,
+            fast_drain_percent_per_hour: 10.0,
+            high_temperature: 113,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DisplayOutput {
+    #[default]
+    Eips,
+    /// Writing to the framebuffer device directly
+    Framebuffer,
+    /// A PGM file, for testing
+    File,
+}
+
+/// Showing what is published on a topic on the screen
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields, default)]
+pub struct DisplayConfig {
+    pub enabled: bool,
+    /// Relative to the device's prefix
+    pub topic: String,
+    pub output: DisplayOutput,
+    /// The framebuffer device, or the file
+    pub path: String,
+    /// Of the screen, for eips and files; the framebuffer knows its own
+    pub width: u32,
+    pub height: u32,
+    /// Of text, in multiples of the 10x20 font
+    pub font_size: u32,
+    /// A dashboard layout file; `topic` is not used then
+    pub layout: Option<String>,
+}
+
+impl Default for DisplayConfig {
+    fn default() -> Self {
+        DisplayConfig {
+            enabled: false,
+            topic: String::from("display"),
+            output: DisplayOutput::default(),
+            path: String::from("/dev/fb0"),
+            width: 1072,
+            height: 1448,
+            font_size: 3,
+            layout: None,
+        }
+    }
+}
+
+/// What happens around suspend, when the wifi goes away
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields, default)]
+pub struct WakeConfig {
+    /// How long suspend is held off when the screensaver comes on, for the
+    /// last messages to go out; 0 to leave suspend alone
+    pub defer_suspend_secs: i32,
+    /// Messages kept while offline, the oldest are dropped past it
+    pub max_pending: usize,
+}
+
+impl Default for WakeConfig {
+    fn default() -> Self {
+        WakeConfig {
+            defer_suspend_secs: 10,
+            max_pending: 1000,
+        }
+    }
+}
+
+/// A span of the day, like `08:00-22:00`, going past midnight if it ends
+/// before it starts
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Hours {
+    /// Minutes since midnight
+    pub from: u32,
+    pub until: u32,
+}
+
+fn minutes(hhmm: &str) -> Option<u32> {
+    let (h, m) = hhmm.trim().split_once(':')?;
+    let (h, m): (u32, u32) = (h.parse().ok()?, m.parse().ok()?);
+    if h > 24 || m > 59 || (h == 24 && m > 0) {
+        return None;
+    }
+    Some(h * 60 + m)
+}
+
+impl TryFrom<String> for Hours {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.split_once('-')
+            .and_then(|(from, until)| {
+                Some(Hours {
+                    from: minutes(from)?,
+                    until: minutes(until)?,
+                })
+            })
+            .ok_or_else(|| format!("Expected hours like 08:00-22:00, got {}", s))
+    }
+}
+
+impl Hours {
+    /// Whether `minute`, since midnight, is in the span
+    pub fn contains(&self, minute: u32) -> bool {
+        if self.from <= self.until {
+            self.from <= minute && minute < self.until
+        } else {
+            minute >= self.from || minute < self.until
+        }
+    }
+}
+
+/// Keeping the screen on, for a Kindle used as a dashboard. With neither
+/// `hours` nor `topic`, it is kept on all the time.
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(deny_unknown_fields, default)]
+pub struct KeepAwakeConfig {
+    pub enabled: bool,
+    /// When the screensaver is kept away, in local time
+    pub hours: Option<Hours>,
+    /// Relative to the device's prefix; `on` keeps the screen on outside of
+    /// `hours` too, `off` stops that. Only changes on restart.
+    pub topic: Option<String>,
+    /// For `hours`, which are in UTC otherwise
+    pub utc_offset_mins: i64,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogTarget {
+    Stderr,
+    /// The Kindle's own logging, readable with `logread`
+    Syslog,
+    File,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields, default)]
+pub struct LogConfig {
+    pub level: LogLevel,
+    /// Syslog when running as a daemon, stderr otherwise
+    pub target: Option<LogTarget>,
+    /// Where the file target writes
+    pub path: String,
+    /// Past this size, the file is moved to `<path>.1` and started over
+    pub max_bytes: u64,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: LogLevel::default(),
+            target: None,
+            path: String::from(DEFAULT_LOG_PATH),
+            max_bytes: 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(deny_unknown_fields, default)]
+pub struct DaemonConfig {
+    /// Written while running, and used to refuse starting twice
+    pub pid_file: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub daemon: DaemonConfig,
+    #[serde(default)]
+    pub log: LogConfig,
+    #[serde(default)]
+    pub mqtt: MqttConfig,
+    #[serde(default = "default_sinks")]
+    pub sink: Vec<SinkConfig>,
+    /// The first one matching a topic applies
+    #[serde(default)]
+    pub limit: Vec<LimitConfig>,
+    #[serde(default)]
+    pub battery: BatteryConfig,
+    #[serde(default)]
+    pub wake: WakeConfig,
+    #[serde(default)]
+    pub display: DisplayConfig,
+    #[serde(default)]
+    pub keep_awake: KeepAwakeConfig,
+    #[serde(default)]
+    pub events: EventsConfig,
+    #[serde(default = "default_polls")]
+    pub poll: Vec<PollConfig>,
+    /// Where the reading statistics are kept
+    #[serde(default = "default_stats_path")]
+    pub stats_path: String,
+}
+
+fn default_stats_path() -> String {
+    String::from(DEFAULT_STATS_PATH)
+}
+
+fn default_polls() -> Vec<PollConfig> {
+    vec![PollConfig {
+        service: String::from(acxreaderplugin::ALL_READER_DATA.service),
+        property: String::from(acxreaderplugin::ALL_READER_DATA.name),
+        kind: PropType::Str,
+        topic: String::from("book"),
+        interval_secs: 300,
+        max_age_secs: None,
+        jitter_secs: 0,
+    }]
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            daemon: DaemonConfig::default(),
+            log: LogConfig::default(),
+            mqtt: MqttConfig::default(),
+            sink: default_sinks(),
+            limit: vec![],
+            battery: BatteryConfig::default(),
+            wake: WakeConfig::default(),
+            display: DisplayConfig::default(),
+            keep_awake: KeepAwakeConfig::default(),
+            events: EventsConfig::default(),
+            poll: default_polls(),
+            stats_path: default_stats_path(),
+        }
+    }
+}
+
+impl Config {
+    pub fn parse(s: &str) -> Result<Config, String> {
+        toml::from_str(s).map_err(|e| e.to_string())
+    }
+
+    /// Reads the configuration at `path`, or the defaults if there is no file
+    pub fn load(path: &str) -> Result<Config, String> {
+        match fs::read_to_string(path) {
+            Ok(s) => Config::parse(&s).map_err(|e| format!("{}: {}", path, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(format!("Failed to read {}: {}", path, e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_defaults() {
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+        assert_eq!(
+            Config::load("/nonexistent/config.toml").unwrap(),
+            Config::default()
+        );
+    }
+
+    #[test]
+    fn test_polls() {
+        let config = Config::parse(
+            r#"
+[[poll]]
+service = "com.lab126.powerd"
+property = "battTemperature"
+type = "int"
+topic = "battery/temperature"
+interval_secs = 60
+max_age_secs = 600
+jitter_secs = 5
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.poll,
+            vec![PollConfig {
+                service: String::from("com.lab126.powerd"),
+                property: String::from("battTemperature"),
+                kind: PropType::Int,
+                topic: String::from("battery/temperature"),
+                interval_secs: 60,
+                max_age_secs: Some(600),
+                jitter_secs: 5,
+            }]
+        );
+
+        assert!(Config::parse("[[poll]]\nservice = \"x\"").is_err());
+        assert!(Config::parse("pol = []").is_err());
+    }
+
+    #[test]
+    fn test_payload() {
+        let config = Config::parse(
+            r#"
+[mqtt.payload]
+format = "json"
+topics = { battery = "raw" }
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.mqtt.payload.format_for("battery"),
+            PayloadFormat::Raw
+        );
+        assert_eq!(
+            config.mqtt.payload.format_for("screen"),
+            PayloadFormat::Json
+        );
+        assert_eq!(
+            Config::default().mqtt.payload.format_for("screen"),
+            PayloadFormat::Raw
+        );
+        assert!(Config::parse("[mqtt.payload]\nformat = \"xml\"").is_err());
+    }
+
+    #[test]
+    fn test_sinks() {
+        let config = Config::parse(
+            r#"
+[[sink]]
+type = "http"
+url = "http://192.168.1.2:8123/api/webhook/kindle"
+topics = ["battery/#"]
+
+[[sink]]
+type = "stdout"
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.sink,
+            vec![
+                SinkConfig::Http {
+                    url: String::from("http://192.168.1.2:8123/api/webhook/kindle"),
+                    topics: vec![String::from("battery/#")],
+                },
+                SinkConfig::Stdout { topics: vec![] },
+            ]
+        );
+        assert_eq!(config.sink[0].topics(), ["battery/#"]);
+        assert!(Config::parse("[[sink]]\ntype = \"file\"").is_err());
+        assert!(Config::parse("[[sink]]\ntype = \"stdout\"\nurl = \"x\"").is_err());
+    }
+
+    #[test]
+    fn test_keep_awake() {
+        let config = Config::parse(
+            r#"
+[keep_awake]
+enabled = true
+hours = "22:30-07:00"
+topic = "keep_awake/set"
+"#,
+        )
+        .unwrap();
+        let hours = config.keep_awake.hours.unwrap();
+        assert_eq!(
+            hours,
+            Hours {
+                from: 22 * 60 + 30,
+                until: 7 * 60
+            }
+        );
+        assert!(hours.contains(23 * 60));
+        assert!(hours.contains(0));
+        assert!(!hours.contains(7 * 60));
+        assert!(!hours.contains(12 * 60));
+        assert!(Hours::try_from(String::from("08:00-24:00"))
+            .unwrap()
+            .contains(23 * 60 + 59));
+        assert!(Config::parse("[keep_awake]\nhours = \"8-22\"").is_err());
+        assert!(Config::parse("[keep_awake]\nhours = \"08:00-25:00\"").is_err());
+    }
+
+    #[test]
+    fn test_limits() {
+        let config = Config::parse(
+            r#"
+[[limit]]
+topics = ["battery", "connected"]
+debounce_secs = 5
+throttle_secs = 60
+dedupe = true
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.limit,
+            vec![LimitConfig {
+                topics: vec![String::from("battery"), String::from("connected")],
+                debounce_secs: 5,
+                throttle_secs: 60,
+                dedupe: true,
+            }]
+        );
+        assert!(Config::parse("[[limit]]\ndedupe = true").is_err());
+    }
+
+    #[test]
+    fn test_events() {
+        let config = Config::parse(
+            r#"
+[events]
+filters = ["com.lab126.powerd battLevelChanged where level < 20"]
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.events.filters,
+            Filter::parse(&["com.lab126.powerd battLevelChanged where level < 20"]).unwrap()
+        );
+        assert!(Config::parse("[events]\nfilters = [\"com.lab126.powerd /(/\"]").is_err());
+    }
+
+    #[test]
+    fn test_log() {
+        let config = Config::parse("[log]\nlevel = \"debug\"\ntarget = \"file\"").unwrap();
+        assert_eq!(config.log.level, LogLevel::Debug);
+        assert_eq!(config.log.target, Some(LogTarget::File));
+        assert_eq!(config.log.path, DEFAULT_LOG_PATH);
+        assert!(Config::parse("[log]\nlevel = \"loud\"").is_err());
+    }
+}
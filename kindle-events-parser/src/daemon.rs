@@ -0,0 +1,152 @@
+//! Running in the background: detaching from the terminal, the PID file and
+//! the signals used to stop or reload the daemon
+
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static STOP: AtomicBool = AtomicBool::new(false);
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    // Only async-signal-safe things can be done here
+    match signal {
+        libc::SIGHUP => RELOAD.store(true, Ordering::SeqCst),
+        _ => STOP.store(true, Ordering::SeqCst),
+    }
+}
+
+/// SIGTERM and SIGINT ask to stop, SIGHUP to reload the configuration
+pub fn install_signal_handlers() -> Result<(), String> {
+    for signal in &[libc::SIGTERM, libc::SIGINT, libc::SIGHUP] {
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as usize;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(*signal, &action, std::ptr::null_mut()) != 0 {
+                return Err(format!(
+                    "Failed to handle signal {}: {}",
+                    signal,
+                    io::Error::last_os_error()
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether SIGTERM or SIGINT was received
+pub fn should_stop() -> bool {
+    STOP.load(Ordering::SeqCst)
+}
+
+/// Whether SIGHUP was received since the last call
+pub fn take_reload() -> bool {
+    RELOAD.swap(false, Ordering::SeqCst)
+}
+
+/// Forks into the background, in a new session, with the standard streams
+/// on /dev/null. Only the child returns.
+pub fn daemonize() -> Result<(), String> {
+    let err = |what: &str| format!("{} failed: {}", what, io::Error::last_os_error());
+    unsafe {
+        match libc::fork() {
+            -1 => return Err(err("fork")),
+            0 => (),
+            _ => libc::_exit(0),
+        }
+        if libc::setsid() == -1 {
+            return Err(err("setsid"));
+        }
+        // Don't keep whatever directory we were started from busy
+        let root = CString::new("/").unwrap();
+        libc::chdir(root.as_ptr());
+
+        let null = CString::new("/dev/null").unwrap();
+        let fd = libc::open(null.as_ptr(), libc::O_RDWR);
+        if fd == -1 {
+            return Err(err("open /dev/null"));
+        }
+        for std_fd in 0..3 {
+            libc::dup2(fd, std_fd);
+        }
+        if fd > 2 {
+            libc::close(fd);
+        }
+    }
+    Ok(())
+}
+
+fn is_running(pid: libc::pid_t) -> bool {
+    // EPERM means it exists, but belongs to someone else
+    (unsafe { libc::kill(pid, 0) } == 0)
+        || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Holds the PID file for as long as the daemon runs, removing it on drop
+#[derive(Debug)]
+pub struct PidFile {
+    path: String,
+}
+
+impl PidFile {
+    /// Writes our PID to `path`, unless it names a process that is still
+    /// running
+    pub fn create(path: &str) -> Result<PidFile, String> {
+        if let Ok(contents) = fs::read_to_string(path) {
+            if let Ok(pid) = contents.trim().parse() {
+                if pid != std::process::id() as libc::pid_t && is_running(pid) {
+                    return Err(format!("Already running as PID {} ({})", pid, path));
+                }
+            }
+        }
+        fs::write(path, format!("{}\n", std::process::id()))
+            .map_err(|e| format!("Failed to write {}: {}", path, e))?;
+        Ok(PidFile {
+            path: path.to_string(),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pid_file() {
+        let path = std::env::temp_dir().join(format!("kindle-events-{}.pid", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let pid_file = PidFile::create(path).unwrap();
+        assert_eq!(
+            fs::read_to_string(path).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+        drop(pid_file);
+        assert!(fs::metadata(path).is_err());
+
+        // PID 1 is always running
+        fs::write(path, "1\n").unwrap();
+        assert!(PidFile::create(path).is_err());
+        // A stale file is taken over
+        fs::write(path, format!("{}\n", i32::MAX)).unwrap();
+        drop(PidFile::create(path).unwrap());
+    }
+
+    #[test]
+    fn test_signals() {
+        install_signal_handlers().unwrap();
+        assert!(!take_reload());
+        unsafe { libc::raise(libc::SIGHUP) };
+        assert!(take_reload());
+        assert!(!take_reload());
+        assert!(!should_stop());
+    }
+}
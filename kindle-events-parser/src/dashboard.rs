@@ -0,0 +1,557 @@
+//! A status screen made of regions, each showing what comes on an MQTT
+//! topic: the weather, the calendar, sensor values... Only the regions that
+//! changed are refreshed, with a full refresh now and then against ghosting.
+
+use crate::clock::{date, unix_now};
+use crate::config::DisplayConfig;
+use crate::display::{self, render_text, Canvas, Rect, TextScreen, RECONNECT_DELAY};
+use crate::framebuffer::{self, Framebuffer};
+use crate::sinks::topic_matches;
+use log::{info, warn};
+use mqtt_simple::{Client, QoS};
+use serde::Deserialize;
+use serde_json::Value;
+use std::fs;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The layout file
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Layout {
+    /// Seconds between renders, for `{time}` and anything else that changes
+    /// without a message
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+    /// Seconds between full refreshes of the screen; 0 for never
+    #[serde(default = "default_full_refresh_secs")]
+    pub full_refresh_secs: u64,
+    /// Bits per pixel: 1 for black and white, 4 for the 16 grays of the
+    /// e-ink screen
+    #[serde(default = "default_depth")]
+    pub depth: u8,
+    /// For `{time}` and `{date}`, which are in UTC otherwise
+    #[serde(default)]
+    pub utc_offset_mins: i64,
+    #[serde(default, rename = "region")]
+    pub regions: Vec<Region>,
+}
+
+fn default_interval_secs() -> u64 {
+    60
+}
+
+fn default_full_refresh_secs() -> u64 {
+    3600
+}
+
+fn default_depth() -> u8 {
+    4
+}
+
+/// A part of the screen and what to show in it
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    /// The full topic, wildcards allowed; the region shows the last message
+    /// on any matching topic. Without one, only the template is shown.
+    pub topic: Option<String>,
+    pub title: Option<String>,
+    /// Text with `{field}` replaced by that field of the JSON message,
+    /// `{a.b}` for nested ones, `{value}` by the whole message, `{time}` and
+    /// `{date}` by the current ones. Each line of it is a line on screen.
+    pub template: Option<String>,
+    /// Defaults to the display's
+    pub font_size: Option<u32>,
+    #[serde(default)]
+    pub border: bool,
+}
+
+impl Region {
+    pub fn rect(&self) -> Rect {
+        Rect {
+            x: self.x,
+            y: self.y,
+            width: self.width,
+            height: self.height,
+        }
+    }
+}
+
+fn overlap(a: Rect, b: Rect) -> bool {
+    a.x < b.x + b.width && b.x < a.x + a.width && a.y < b.y + b.height && b.y < a.y + a.height
+}
+
+impl Layout {
+    pub fn parse(s: &str) -> Result<Layout, String> {
+        toml::from_str(s).map_err(|e| e.to_string())
+    }
+
+    pub fn load(path: &str) -> Result<Layout, String> {
+        let s = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        Self::parse(&s).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    /// Checks that the regions fit on a `width` x `height` screen, without
+    /// overlapping
+    pub fn validate(&self, width: u32, height: u32) -> Result<(), String> {
+        if ![1, 2, 4, 8].contains(&self.depth) {
+            return Err(format!("depth should be 1, 2, 4 or 8, not {}", self.depth));
+        }
+        if self.interval_secs == 0 {
+            return Err(String::from("interval_secs should be at least 1"));
+        }
+        for (i, region) in self.regions.iter().enumerate() {
+            let r = region.rect();
+            if r.width == 0 || r.height == 0 {
+                return Err(format!("Region {} is empty", i + 1));
+            }
+            let right = r.x.checked_add(r.width);
+            let bottom = r.y.checked_add(r.height);
+            if right.is_none_or(|x| x > width) || bottom.is_none_or(|y| y > height) {
+                return Err(format!(
+                    "Region {} does not fit on the {}x{} screen",
+                    i + 1,
+                    width,
+                    height
+                ));
+            }
+            if let Some(j) = self.regions[..i]
+                .iter()
+                .position(|other| overlap(other.rect(), r))
+            {
+                return Err(format!("Regions {} and {} overlap", j + 1, i + 1));
+            }
+        }
+        Ok(())
+    }
+
+    /// The topics to subscribe to, without duplicates
+    pub fn topics(&self) -> Vec<&str> {
+        let mut topics: Vec<&str> = vec![];
+        for topic in self.regions.iter().filter_map(|r| r.topic.as_deref()) {
+            if !topics.contains(&topic) {
+                topics.push(topic);
+            }
+        }
+        topics
+    }
+}
+
+/// A value of a JSON message as text; arrays are one element per line
+fn text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        Value::Array(values) => values.iter().map(text).collect::<Vec<_>>().join("\n"),
+        other => other.to_string(),
+    }
+}
+
+/// `template` with its placeholders replaced, `-` standing for what is not
+/// known yet
+pub fn fill(template: &str, payload: Option<&[u8]>, now: u64, utc_offset_mins: i64) -> String {
+    let local = now as i64 + utc_offset_mins * 60;
+    let raw = payload.map(String::from_utf8_lossy);
+    let json: Option<Value> = raw.as_ref().and_then(|s| serde_json::from_str(s).ok());
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        out.push_str(&rest[..start]);
+        let key = &rest[start + 1..end];
+        let value = match key {
+            "time" => Some(format!(
+                "{:02}:{:02}",
+                local.rem_euclid(86400) / 3600,
+                local.rem_euclid(3600) / 60
+            )),
+            "date" => Some(date(local.div_euclid(86400))),
+            "value" => raw.as_ref().map(|s| s.trim().to_string()),
+            _ => json
+                .as_ref()
+                .and_then(|json| key.split('.').try_fold(json, |v, k| v.get(k)))
+                .map(text),
+        };
+        out.push_str(value.as_deref().unwrap_or("-"));
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// The screen, with the last message of each region
+pub struct Dashboard {
+    layout: Layout,
+    font_size: u32,
+    values: Vec<Option<Vec<u8>>>,
+    canvas: Canvas,
+    /// What each region showed the last time, to tell what changed
+    shown: Vec<Option<Canvas>>,
+}
+
+impl Dashboard {
+    pub fn new(layout: Layout, width: u32, height: u32, font_size: u32) -> Result<Self, String> {
+        layout.validate(width, height)?;
+        let count = layout.regions.len();
+        Ok(Dashboard {
+            layout,
+            font_size,
+            values: vec![None; count],
+            canvas: Canvas::new(width, height),
+            shown: vec![None; count],
+        })
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    pub fn canvas(&self) -> &Canvas {
+        &self.canvas
+    }
+
+    /// Keeps `payload` for the regions bound to `topic`. Returns whether
+    /// any was.
+    pub fn update(&mut self, topic: &str, payload: &[u8]) -> bool {
+        let mut bound = false;
+        for (region, value) in self.layout.regions.iter().zip(self.values.iter_mut()) {
+            if let Some(pattern) = &region.topic {
+                if topic_matches(pattern, topic) {
+                    *value = Some(payload.to_vec());
+                    bound = true;
+                }
+            }
+        }
+        bound
+    }
+
+    fn render_region(&self, region: &Region, payload: Option<&[u8]>, now: u64) -> Canvas {
+        let (width, height) = (region.width, region.height);
+        let font_size = region.font_size.unwrap_or(self.font_size);
+        let mut canvas = match (payload, &region.template) {
+            (Some(payload), None) if display::is_image(payload) => Canvas::decode(payload)
+                .map(|image| image.fit(width, height))
+                .unwrap_or_else(|e| {
+                    let screen = TextScreen {
+                        lines: vec![e],
+                        ..Default::default()
+                    };
+                    render_text(&screen, width, height, font_size)
+                }),
+            (payload, template) => {
+                let text = match template {
+                    Some(template) => fill(template, payload, now, self.layout.utc_offset_mins),
+                    None => payload
+                        .map(|p| {
+                            let raw = String::from_utf8_lossy(p);
+                            match serde_json::from_str(&raw) {
+                                Ok(value) => text(&value),
+                                Err(_) => raw.into_owned(),
+                            }
+                        })
+                        .unwrap_or_else(|| String::from("-")),
+                };
+                let screen = TextScreen {
+                    title: region.title.clone(),
+                    lines: text.lines().map(String::from).collect(),
+                    font_size: None,
+                };
+                render_text(&screen, width, height, font_size)
+            }
+        };
+        canvas.quantize(self.layout.depth);
+        if region.border {
+            canvas.fill_rect(0, 0, width, 2.min(height), 0);
+            canvas.fill_rect(0, height.saturating_sub(2), width, 2.min(height), 0);
+            canvas.fill_rect(0, 0, 2.min(width), height, 0);
+            canvas.fill_rect(width.saturating_sub(2), 0, 2.min(width), height, 0);
+        }
+        canvas
+    }
+
+    /// Draws every region as of `now`. Returns those that changed since the
+    /// last render, the only ones needing a refresh.
+    pub fn render(&mut self, now: u64) -> Vec<Rect> {
+        let mut changed = vec![];
+        for i in 0..self.layout.regions.len() {
+            let region = &self.layout.regions[i];
+            let canvas = self.render_region(region, self.values[i].as_deref(), now);
+            if self.shown[i].as_ref() == Some(&canvas) {
+                continue;
+            }
+            self.canvas.draw(&canvas, region.x, region.y);
+            changed.push(region.rect());
+            self.shown[i] = Some(canvas);
+        }
+        changed
+    }
+
+    /// The whole screen as a PNG, as it was last rendered
+    pub fn to_png(&self) -> Result<Vec<u8>, String> {
+        self.canvas.to_png()
+    }
+}
+
+/// Passes what comes on `topics` to `tx` until the connection breaks, or
+/// nobody listens anymore
+fn receive(
+    broker: &str,
+    client_id: &str,
+    topics: &[String],
+    tx: &Sender<(String, Vec<u8>)>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = Client::new(client_id.to_string(), broker.to_string())?;
+    let mut client = client.connect(30)?;
+    for topic in topics {
+        client.subscribe(topic, QoS::AtLeastOnce)?;
+    }
+    info!("Dashboard showing {}", topics.join(", "));
+    loop {
+        let msg = client.next_message()?;
+        if tx.send((msg.topic, msg.payload)).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Renders on every message and every `interval_secs`, refreshing what
+/// changed, until the receiver is gone. A failed refresh is logged and
+/// made up for by refreshing the whole screen next time.
+fn show(mut dashboard: Dashboard, fb: &mut dyn Framebuffer, rx: Receiver<(String, Vec<u8>)>) {
+    let interval = Duration::from_secs(dashboard.layout().interval_secs);
+    let full_refresh = match dashboard.layout().full_refresh_secs {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    dashboard.render(unix_now());
+    let mut failed = log_failure(fb.show(dashboard.canvas()));
+    let mut last_full = Instant::now();
+    let mut next_render = Instant::now() + interval;
+    loop {
+        match rx.recv_timeout(next_render.saturating_duration_since(Instant::now())) {
+            Ok((topic, payload)) => {
+                dashboard.update(&topic, &payload);
+                // Retained messages come all at once on connection
+                for (topic, payload) in rx.try_iter() {
+                    dashboard.update(&topic, &payload);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => next_render = Instant::now() + interval,
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+        let changed = dashboard.render(unix_now());
+        let res = if failed || full_refresh.is_some_and(|d| last_full.elapsed() >= d) {
+            last_full = Instant::now();
+            fb.show(dashboard.canvas())
+        } else {
+            changed
+                .into_iter()
+                .try_for_each(|rect| fb.show_region(dashboard.canvas(), rect))
+        };
+        failed = log_failure(res);
+    }
+}
+
+/// Whether `res` is a failure, logging it if so
+fn log_failure(res: Result<(), String>) -> bool {
+    match res {
+        Ok(()) => false,
+        Err(e) => {
+            warn!("Dashboard: {}", e);
+            true
+        }
+    }
+}
+
+/// Shows `layout` in the background, connecting again whenever the
+/// connection breaks
+pub fn spawn(config: DisplayConfig, layout: Layout, broker: String, client_id: String) {
+    let topics: Vec<String> = layout.topics().into_iter().map(String::from).collect();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut fb = match framebuffer::open(&config) {
+            Ok(fb) => fb,
+            Err(e) => {
+                warn!("No display: {}", e);
+                return;
+            }
+        };
+        let (width, height) = fb.size();
+        match Dashboard::new(layout, width, height, config.font_size) {
+            Ok(dashboard) => show(dashboard, fb.as_mut(), rx),
+            Err(e) => warn!("Dashboard: {}", e),
+        }
+    });
+    if topics.is_empty() {
+        return;
+    }
+    thread::spawn(move || loop {
+        match receive(&broker, &client_id, &topics, &tx) {
+            Ok(()) => return,
+            Err(e) => warn!("Dashboard: {}", e),
+        }
+        thread::sleep(RECONNECT_DELAY);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LAYOUT: &str = r#"
+depth = 1
+utc_offset_mins = 120
+
+[[region]]
+x = 0
+y = 0
+width = 200
+height = 100
+template = "{time}"
+
+[[region]]
+x = 0
+y = 100
+width = 200
+height = 200
+topic = "home/weather"
+title = "Weather"
+template = "{temp} C\n{wind.speed} km/h"
+border = true
+
+[[region]]
+x = 200
+y = 0
+width = 100
+height = 300
+topic = "home/+/image"
+"#;
+
+    #[test]
+    fn test_fill() {
+        let weather = br#"{"temp": 21.5, "wind": {"speed": 12}, "sky": "clear"}"#;
+        assert_eq!(
+            fill("{temp} C, {sky}, {wind.speed}", Some(weather), 0, 0),
+            "21.5 C, clear, 12"
+        );
+        assert_eq!(fill("{rain} {value}", None, 0, 0), "- -");
+        assert_eq!(fill("{value}!", Some(b"on\n"), 0, 0), "on!");
+        assert_eq!(
+            fill("{date} {time}", None, 1_709_251_199, 0),
+            "2024-02-29 23:59"
+        );
+        assert_eq!(
+            fill("{date} {time}", None, 1_709_251_199, 90),
+            "2024-03-01 01:29"
+        );
+        assert_eq!(fill("{unclosed", None, 0, 0), "{unclosed");
+    }
+
+    #[test]
+    fn test_layout() {
+        let layout = Layout::parse(LAYOUT).unwrap();
+        assert_eq!(layout.interval_secs, 60);
+        assert_eq!(layout.topics(), vec!["home/weather", "home/+/image"]);
+        assert!(layout.validate(300, 300).is_ok());
+        assert!(layout.validate(299, 300).is_err());
+
+        let mut overlapping = layout.clone();
+        overlapping.regions[1].y = 50;
+        assert_eq!(
+            overlapping.validate(300, 300),
+            Err(String::from("Regions 1 and 2 overlap"))
+        );
+        assert!(Layout::parse("[[region]]\nx = 0").is_err());
+
+        let mut huge = layout.clone();
+        huge.regions[1].x = u32::MAX;
+        assert!(huge.validate(300, 300).is_err());
+    }
+
+    /// Fails its first full refresh, and records the others
+    struct FlakyFramebuffer {
+        shows: Vec<Option<Rect>>,
+        fail: bool,
+    }
+
+    impl Framebuffer for FlakyFramebuffer {
+        fn size(&self) -> (u32, u32) {
+            (300, 300)
+        }
+
+        fn show(&mut self, _canvas: &Canvas) -> Result<(), String> {
+            if std::mem::take(&mut self.fail) {
+                return Err(String::from("eips failed"));
+            }
+            self.shows.push(None);
+            Ok(())
+        }
+
+        fn show_region(&mut self, _canvas: &Canvas, region: Rect) -> Result<(), String> {
+            self.shows.push(Some(region));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_show_goes_on_after_failure() {
+        let layout = Layout::parse(LAYOUT).unwrap();
+        let rects: Vec<Rect> = layout.regions.iter().map(Region::rect).collect();
+        let dashboard = Dashboard::new(layout, 300, 300, 1).unwrap();
+        let mut fb = FlakyFramebuffer {
+            shows: vec![],
+            fail: true,
+        };
+        let (tx, rx) = mpsc::channel();
+        let sender = thread::spawn(move || {
+            tx.send((String::from("home/weather"), b"{}".to_vec()))
+                .unwrap();
+            thread::sleep(Duration::from_millis(50));
+            tx.send((String::from("home/weather"), br#"{"temp": 20}"#.to_vec()))
+                .unwrap();
+        });
+        show(dashboard, &mut fb, rx);
+        sender.join().unwrap();
+        // The whole screen again after the failure, then only what changed
+        assert_eq!(fb.shows, vec![None, Some(rects[1])]);
+    }
+
+    #[test]
+    fn test_render() {
+        let layout = Layout::parse(LAYOUT).unwrap();
+        let rects: Vec<Rect> = layout.regions.iter().map(Region::rect).collect();
+        let mut dashboard = Dashboard::new(layout, 300, 300, 1).unwrap();
+        assert_eq!(dashboard.render(0), rects);
+        assert_eq!(dashboard.render(30), vec![]);
+        // A minute later, only the clock changes
+        assert_eq!(dashboard.render(60), vec![rects[0]]);
+
+        assert!(dashboard.update("home/weather", br#"{"temp": 20}"#));
+        assert!(!dashboard.update("office/weather", br#"{"temp": 25}"#));
+        assert_eq!(dashboard.render(60), vec![rects[1]]);
+
+        let mut image = Canvas::new(10, 10);
+        image.fill_rect(0, 0, 10, 10, 0);
+        assert!(dashboard.update("home/door/image", &image.to_png().unwrap()));
+        assert_eq!(dashboard.render(60), vec![rects[2]]);
+        let region = dashboard.canvas().crop(rects[2]);
+        assert_eq!(region.get(50, 150), 0);
+        assert_eq!(region.get(50, 100), 0xff);
+
+        // Headless, the way it would be on the screen
+        let png = dashboard.to_png().unwrap();
+        let decoded = Canvas::decode(&png).unwrap();
+        assert_eq!(&decoded, dashboard.canvas());
+        assert!(decoded.pixels.iter().all(|p| *p == 0 || *p == 0xff));
+        assert!(decoded.pixels.contains(&0));
+    }
+}
@@ -0,0 +1,70 @@
+//! A stable id for the Kindle, used as the MQTT client id and topic prefix so
+//! several devices can share a broker
+
+use libopenlipc_sys::catalog::system;
+use libopenlipc_sys::LipcBackend;
+use std::fs;
+
+pub const SERIAL_PATH: &str = "/proc/usid";
+
+/// Lowercase, with anything that is not alphanumeric, `-` or `_` dropped, so
+/// the id can go in a topic
+fn sanitize(id: &str) -> String {
+    id.trim()
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// The Kindle's serial number, as exposed in `/proc`
+pub fn serial() -> Option<String> {
+    fs::read_to_string(SERIAL_PATH).ok()
+}
+
+/// The id of this device: `configured` if there is one, otherwise the serial
+/// number, from LIPC or else from `/proc`
+pub fn device_id<B: LipcBackend>(r: &B, configured: Option<&str>) -> String {
+    let candidates = configured
+        .map(String::from)
+        .into_iter()
+        .chain(r.get_str(system::USID).ok())
+        .chain(serial());
+    for candidate in candidates {
+        let id = sanitize(&candidate);
+        if !id.is_empty() {
+            return id;
+        }
+    }
+    log::warn!("Could not find a serial number, set mqtt.device_id");
+    String::from("unknown")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sanitize() {
+        assert_eq!(sanitize(" G000AB12\n"), "g000ab12");
+        assert_eq!(sanitize("living room/#"), "livingroom");
+        assert_eq!(sanitize("+/#"), "");
+    }
+
+    #[cfg(feature = "mock")]
+    #[test]
+    fn test_device_id() {
+        use libopenlipc_sys::{LipcResult, MockLipc};
+
+        let r = MockLipc::new();
+        r.set_prop(
+            system::USID.service,
+            system::USID.name,
+            LipcResult::STR(String::from("G000AB12")),
+        );
+        assert_eq!(device_id(&r, None), "g000ab12");
+        assert_eq!(device_id(&r, Some("Bedroom")), "bedroom");
+        // Nothing usable configured
+        assert_eq!(device_id(&r, Some("#")), "g000ab12");
+    }
+}
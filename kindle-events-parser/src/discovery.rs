@@ -0,0 +1,149 @@
+//! Home Assistant MQTT discovery, so the Kindle's sensors show up without
+//! any YAML
+
+use crate::display::RECONNECT_DELAY;
+use crate::publisher::Publisher;
+use log::warn;
+use mqtt_simple::{Client, QoS};
+use serde_json::{json, Value};
+use std::thread;
+
+pub const AVAILABILITY_TOPIC: &str = "availability";
+const DISCOVERY_PREFIX: &str = "homeassistant";
+/// The broker gives up on the presence connection after one and a half
+/// times this
+const PRESENCE_KEEPALIVE: u8 = 60;
+
+struct Entity {
+    component: &'static str,
+    object_id: &'static str,
+    name: &'static str,
+    state_topic: &'static str,
+    /// Extra fields of the config
+    extra: Value,
+}
+
+fn entities() -> Vec<Entity> {
+    vec![
+        Entity {
+            component: "sensor",
+            object_id: "battery",
+            name: "Battery",
+            state_topic: "battery",
+            extra: json!({
+                "device_class": "battery",
+                "unit_of_measurement": "%",
+                "state_class": "measurement",
+            }),
+        },
+        Entity {
+            component: "binary_sensor",
+            object_id: "screen",
+            name: "Screen",
+            state_topic: "screen",
+            extra: json!({
+                "icon": "mdi:book-open-variant",
+                "payload_on": "1",
+                "payload_off": "0",
+            }),
+        },
+        Entity {
+            component: "binary_sensor",
+            object_id: "connected",
+            name: "Connected",
+            state_topic: "connected",
+            extra: json!({
+                "device_class": "connectivity",
+                "payload_on": "1",
+                "payload_off": "0",
+            }),
+        },
+    ]
+}
+
+/// The (topic, config) pairs to publish, retained, for the Kindle with
+/// `device_id`
+pub fn discovery_messages(publisher: &Publisher, device_id: &str) -> Vec<(String, String)> {
+    let node_id = format!("kindle_{}", device_id);
+    entities()
+        .into_iter()
+        .map(|e| {
+            let mut config = json!({
+                "name": e.name,
+                "unique_id": format!("{}_{}", node_id, e.object_id),
+                "state_topic": publisher.topic(e.state_topic),
+                "availability_topic": publisher.topic(AVAILABILITY_TOPIC),
+                "device": {
+                    "identifiers": [node_id],
+                    "name": format!("Kindle {}", device_id),
+                    "manufacturer": "Amazon",
+                },
+            });
+            if let (Some(config), Value::Object(extra)) = (config.as_object_mut(), e.extra) {
+                config.extend(extra);
+            }
+            let topic = format!(
+                "{}/{}/{}/{}/config",
+                DISCOVERY_PREFIX, e.component, node_id, e.object_id
+            );
+            (topic, config.to_string())
+        })
+        .collect()
+}
+
+/// Holds a connection to the broker whose last will sets `topic`, the full
+/// availability topic, to `offline`: after a crash or a power loss, the
+/// broker says it on the Kindle's behalf
+pub fn spawn_presence(broker: String, client_id: String, topic: String) {
+    thread::spawn(move || loop {
+        let res = (|| -> Result<(), Box<dyn std::error::Error>> {
+            let mut client = Client::new(client_id.clone(), broker.clone())?;
+            client.set_will(&topic, "offline", true);
+            let mut client = client.connect(PRESENCE_KEEPALIVE)?;
+            client.publish(&topic, "online", true, QoS::AtMostOnce)?;
+            // Nothing is subscribed to, this only keeps the connection alive
+            loop {
+                client.next_message()?;
+            }
+        })();
+        if let Err(e) = res {
+            warn!("Presence: {}", e);
+        }
+        thread::sleep(RECONNECT_DELAY);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn test_discovery_messages() {
+        let publisher = Publisher::new(&Config::default(), "g000ab12").unwrap();
+        let msgs = discovery_messages(&publisher, "g000ab12");
+        let topics: Vec<&str> = msgs.iter().map(|(t, _)| t.as_str()).collect();
+        assert_eq!(
+            topics,
+            vec![
+                "homeassistant/sensor/kindle_g000ab12/battery/config",
+                "homeassistant/binary_sensor/kindle_g000ab12/screen/config",
+                "homeassistant/binary_sensor/kindle_g000ab12/connected/config",
+            ]
+        );
+
+        let battery: Value = serde_json::from_str(&msgs[0].1).unwrap();
+        assert_eq!(battery["unique_id"], "kindle_g000ab12_battery");
+        assert_eq!(battery["device_class"], "battery");
+        assert_eq!(battery["state_topic"], "kindle/g000ab12/battery");
+        assert_eq!(
+            battery["availability_topic"],
+            "kindle/g000ab12/availability"
+        );
+        assert_eq!(battery["device"]["identifiers"][0], "kindle_g000ab12");
+
+        let connected: Value = serde_json::from_str(&msgs[2].1).unwrap();
+        assert_eq!(connected["device_class"], "connectivity");
+        assert_eq!(connected["payload_on"], "1");
+    }
+}
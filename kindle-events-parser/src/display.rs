@@ -0,0 +1,607 @@
+//! The Kindle as a status screen: text or images received on an MQTT topic
+//! are laid out on a canvas the size of the screen, then shown on it
+
+use crate::config::DisplayConfig;
+use crate::framebuffer::{self, Framebuffer};
+use embedded_graphics::mono_font::iso_8859_15::FONT_10X20;
+use embedded_graphics::mono_font::MonoTextStyle;
+use embedded_graphics::pixelcolor::BinaryColor;
+use embedded_graphics::prelude::*;
+use embedded_graphics::text::{Baseline, Text};
+use log::{info, warn};
+use mqtt_simple::{Client, QoS};
+use serde::Deserialize;
+use std::convert::Infallible;
+use std::thread;
+use std::time::Duration;
+
+const WHITE: u8 = 0xff;
+const BLACK: u8 = 0;
+/// Waiting for the wifi to come back, most of the time
+pub(crate) const RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// 8 bit grayscale pixels, 0 being black, row by row
+#[derive(Debug, Clone, PartialEq)]
+pub struct Canvas {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+/// A part of the screen, in pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Refusing anything bigger than a screen many times over, which is more
+/// likely a broken header than a picture
+fn image_size(width: u32, height: u32) -> Result<usize, String> {
+    match width.checked_mul(height) {
+        Some(size) if size <= 1 << 24 => Ok(size as usize),
+        _ => Err(format!("Image too big: {}x{}", width, height)),
+    }
+}
+
+/// Splits the header of a netpbm image into its tokens, skipping comments,
+/// and returns them with what follows
+fn pnm_header(bytes: &[u8], count: usize) -> Result<(Vec<u32>, &[u8]), String> {
+    let mut values = vec![];
+    let mut i = 2;
+    while values.len() < count {
+        match bytes.get(i) {
+            None => return Err(String::from("Truncated image header")),
+            Some(b'#') => {
+                while bytes.get(i).is_some_and(|b| *b != b'\n') {
+                    i += 1;
+                }
+            }
+            Some(b) if b.is_ascii_whitespace() => i += 1,
+            Some(_) => {
+                let start = i;
+                while bytes.get(i).is_some_and(|b| b.is_ascii_digit()) {
+                    i += 1;
+                }
+                let token = std::str::from_utf8(&bytes[start..i]).unwrap();
+                values.push(token.parse().map_err(|_| "Invalid image header")?);
+            }
+        }
+    }
+    // A single whitespace separates the header from binary data
+    Ok((values, bytes.get(i + 1..).unwrap_or(&[])))
+}
+
+impl Canvas {
+    /// All white
+    pub fn new(width: u32, height: u32) -> Canvas {
+        Canvas {
+            width,
+            height,
+            pixels: vec![WHITE; (width * height) as usize],
+        }
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> u8 {
+        self.pixels[(y * self.width + x) as usize]
+    }
+
+    /// Pixels outside of the canvas are ignored
+    pub fn set(&mut self, x: u32, y: u32, value: u8) {
+        if x < self.width && y < self.height {
+            self.pixels[(y * self.width + x) as usize] = value;
+        }
+    }
+
+    pub fn fill_rect(&mut self, x: u32, y: u32, width: u32, height: u32, value: u8) {
+        for y in y..(y + height).min(self.height) {
+            for x in x..(x + width).min(self.width) {
+                self.set(x, y, value);
+            }
+        }
+    }
+
+    /// Copies `other` with its top left corner at `x`, `y`
+    pub fn draw(&mut self, other: &Canvas, x: u32, y: u32) {
+        for oy in 0..other.height {
+            for ox in 0..other.width {
+                self.set(x + ox, y + oy, other.get(ox, oy));
+            }
+        }
+    }
+
+    /// A copy of `region`, which has to be inside the canvas
+    pub fn crop(&self, region: Rect) -> Canvas {
+        let mut pixels = Vec::with_capacity((region.width * region.height) as usize);
+        for y in region.y..region.y + region.height {
+            let start = (y * self.width + region.x) as usize;
+            pixels.extend(&self.pixels[start..start + region.width as usize]);
+        }
+        Canvas {
+            width: region.width,
+            height: region.height,
+            pixels,
+        }
+    }
+
+    /// Leaves only the shades of gray there are with `bits` per pixel, 1 to
+    /// 8, rounding to the nearest
+    pub fn quantize(&mut self, bits: u8) {
+        let levels = (1u32 << bits.clamp(1, 8)) - 1;
+        for p in self.pixels.iter_mut() {
+            // Nearest level, spread back over 0-255
+            *p = ((*p as u32 * levels + 127) / 255 * 255 / levels) as u8;
+        }
+    }
+
+    /// Scaled down to fit in `width` x `height` if it's bigger, keeping the
+    /// aspect ratio, and centered on a white canvas of that size
+    pub fn fit(&self, width: u32, height: u32) -> Canvas {
+        let scaled = if self.width > width || self.height > height {
+            // Same ratio for both, the smallest one
+            let (num, den) =
+                if width as u64 * self.height as u64 <= height as u64 * self.width as u64 {
+                    (width, self.width)
+                } else {
+                    (height, self.height)
+                };
+            let w = (self.width as u64 * num as u64 / den as u64).max(1) as u32;
+            let h = (self.height as u64 * num as u64 / den as u64).max(1) as u32;
+            let mut scaled = Canvas::new(w, h);
+            for y in 0..h {
+                for x in 0..w {
+                    let sx = (x as u64 * self.width as u64 / w as u64) as u32;
+                    let sy = (y as u64 * self.height as u64 / h as u64) as u32;
+                    scaled.set(x, y, self.get(sx, sy));
+                }
+            }
+            scaled
+        } else {
+            self.clone()
+        };
+        let mut canvas = Canvas::new(width, height);
+        canvas.draw(
+            &scaled,
+            (width - scaled.width) / 2,
+            (height - scaled.height) / 2,
+        );
+        canvas
+    }
+
+    /// Reads a PNG, or a PBM or PGM image, binary or plain
+    pub fn decode(bytes: &[u8]) -> Result<Canvas, String> {
+        match bytes.get(..2) {
+            Some(b"\x89P") => Self::decode_png(bytes),
+            Some(b"P1") | Some(b"P4") => Self::decode_pbm(bytes),
+            Some(b"P2") | Some(b"P5") => Self::decode_pgm(bytes),
+            _ => Err(String::from("Only PNG, PBM and PGM images are supported")),
+        }
+    }
+
+    fn decode_png(bytes: &[u8]) -> Result<Canvas, String> {
+        let mut decoder = png::Decoder::new(bytes);
+        decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+        let mut reader = decoder
+            .read_info()
+            .map_err(|e| format!("Invalid PNG: {}", e))?;
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let info = reader
+            .next_frame(&mut buf)
+            .map_err(|e| format!("Invalid PNG: {}", e))?;
+        let channels = info.color_type.samples();
+        let pixels = buf[..info.buffer_size()]
+            .chunks(channels)
+            .map(|p| {
+                let (luma, alpha) = match p {
+                    [l] => (*l as u32, 255),
+                    [l, a] => (*l as u32, *a as u32),
+                    [r, g, b] => (luma(*r, *g, *b), 255),
+                    [r, g, b, a, ..] => (luma(*r, *g, *b), *a as u32),
+                    [] => (255, 255),
+                };
+                // On white
+                ((luma * alpha + 255 * (255 - alpha)) / 255) as u8
+            })
+            .collect();
+        Ok(Canvas {
+            width: info.width,
+            height: info.height,
+            pixels,
+        })
+    }
+
+    fn decode_pbm(bytes: &[u8]) -> Result<Canvas, String> {
+        let (header, data) = pnm_header(bytes, 2)?;
+        let (width, height) = (header[0], header[1]);
+        let size = image_size(width, height)?;
+        let row_bytes = width.div_ceil(8) as usize;
+        if &bytes[..2] == b"P4" && data.len() < row_bytes * height as usize {
+            return Err(String::from("Truncated PBM image"));
+        }
+        let pixels: Vec<u8> = if &bytes[..2] == b"P1" {
+            // Whitespace between the bits is optional
+            let bits = data.iter().filter(|b| **b == b'0' || **b == b'1');
+            bits.map(|b| if *b == b'1' { BLACK } else { WHITE })
+                .take(size)
+                .collect()
+        } else {
+            data.chunks(row_bytes)
+                .take(height as usize)
+                .flat_map(|row| {
+                    (0..width as usize).map(move |x| {
+                        let bit = row.get(x / 8).is_some_and(|b| b & (0x80 >> (x % 8)) != 0);
+                        if bit {
+                            BLACK
+                        } else {
+                            WHITE
+                        }
+                    })
+                })
+                .collect()
+        };
+        if pixels.len() != size {
+            return Err(String::from("Truncated PBM image"));
+        }
+        Ok(Canvas {
+            width,
+            height,
+            pixels,
+        })
+    }
+
+    fn decode_pgm(bytes: &[u8]) -> Result<Canvas, String> {
+        let (header, data) = pnm_header(bytes, 3)?;
+        let (width, height, max) = (header[0], header[1], header[2]);
+        if max == 0 || max > 255 {
+            return Err(format!("Unsupported PGM maximum value {}", max));
+        }
+        let size = image_size(width, height)?;
+        let values: Vec<u32> = if &bytes[..2] == b"P2" {
+            String::from_utf8_lossy(data)
+                .split_ascii_whitespace()
+                .take(size)
+                .map(|v| v.parse().map_err(|_| "Invalid PGM value"))
+                .collect::<Result<_, _>>()?
+        } else {
+            data.iter().take(size).map(|v| *v as u32).collect()
+        };
+        if values.len() != size {
+            return Err(String::from("Truncated PGM image"));
+        }
+        Ok(Canvas {
+            width,
+            height,
+            pixels: values
+                .into_iter()
+                .map(|v| (v.min(max) * 255 / max) as u8)
+                .collect(),
+        })
+    }
+
+    /// Binary PGM
+    pub fn to_pgm(&self) -> Vec<u8> {
+        let mut out = format!("P5\n{} {}\n255\n", self.width, self.height).into_bytes();
+        out.extend(&self.pixels);
+        out
+    }
+
+    pub fn to_png(&self) -> Result<Vec<u8>, String> {
+        let mut out = vec![];
+        let mut encoder = png::Encoder::new(&mut out, self.width, self.height);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder
+            .write_header()
+            .and_then(|mut w| w.write_image_data(&self.pixels))
+            .map_err(|e| format!("Failed to encode PNG: {}", e))?;
+        Ok(out)
+    }
+}
+
+fn luma(r: u8, g: u8, b: u8) -> u32 {
+    (r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000
+}
+
+/// Draws on a canvas, each pixel of the font being a `scale` x `scale`
+/// square, from `origin`
+struct Scaled<'a> {
+    canvas: &'a mut Canvas,
+    origin: (u32, u32),
+    scale: u32,
+}
+
+impl OriginDimensions for Scaled<'_> {
+    fn size(&self) -> Size {
+        Size::new(
+            self.canvas.width.saturating_sub(self.origin.0) / self.scale,
+            self.canvas.height.saturating_sub(self.origin.1) / self.scale,
+        )
+    }
+}
+
+impl DrawTarget for Scaled<'_> {
+    type Color = BinaryColor;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Infallible>
+    where
+        I: IntoIterator<Item = Pixel<BinaryColor>>,
+    {
+        for Pixel(p, color) in pixels {
+            if p.x < 0 || p.y < 0 {
+                continue;
+            }
+            let value = if color.is_on() { BLACK } else { WHITE };
+            self.canvas.fill_rect(
+                self.origin.0 + p.x as u32 * self.scale,
+                self.origin.1 + p.y as u32 * self.scale,
+                self.scale,
+                self.scale,
+                value,
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Text to show: a title, with lines below it
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct TextScreen {
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub lines: Vec<String>,
+    /// How many times the size of the 10x20 font
+    #[serde(default)]
+    pub font_size: Option<u32>,
+}
+
+impl TextScreen {
+    /// A JSON object, or plain text with the title on the first line
+    pub fn parse(payload: &str) -> TextScreen {
+        if let Ok(screen) = serde_json::from_str(payload) {
+            return screen;
+        }
+        let mut lines = payload.lines();
+        TextScreen {
+            title: lines.next().map(String::from),
+            lines: lines.map(String::from).collect(),
+            font_size: None,
+        }
+    }
+}
+
+/// Splits `text` in lines of at most `width` characters, between words if
+/// possible
+pub fn wrap(text: &str, width: usize) -> Vec<String> {
+    let width = width.max(1);
+    let mut lines = vec![];
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        let mut word: Vec<char> = word.chars().collect();
+        let len = line.chars().count();
+        if len > 0 && len + 1 + word.len() > width {
+            lines.push(std::mem::take(&mut line));
+        }
+        while word.len() > width {
+            let rest = word.split_off(width);
+            lines.push(word.into_iter().collect());
+            word = rest;
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.extend(word);
+    }
+    if !line.is_empty() || lines.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+/// Draws `screen` on a white canvas; what does not fit is left out
+pub fn render_text(screen: &TextScreen, width: u32, height: u32, font_size: u32) -> Canvas {
+    let mut canvas = Canvas::new(width, height);
+    let scale = screen.font_size.unwrap_or(font_size).clamp(1, 16);
+    let margin = width / 20;
+    let usable = width.saturating_sub(2 * margin);
+    let (char_w, char_h) = (
+        FONT_10X20.character_size.width,
+        FONT_10X20.character_size.height,
+    );
+    let style = MonoTextStyle::new(&FONT_10X20, BinaryColor::On);
+
+    let mut y = margin;
+    let write = |canvas: &mut Canvas, text: &str, scale: u32, y: &mut u32| {
+        for line in wrap(text, (usable / (char_w * scale)) as usize) {
+            if *y + char_h * scale > height {
+                return;
+            }
+            let mut target = Scaled {
+                canvas,
+                origin: (margin, *y),
+                scale,
+            };
+            let _ =
+                Text::with_baseline(&line, Point::zero(), style, Baseline::Top).draw(&mut target);
+            *y += char_h * scale * 5 / 4;
+        }
+    };
+    if let Some(title) = &screen.title {
+        write(&mut canvas, title, scale + 1, &mut y);
+        // A rule under the title
+        canvas.fill_rect(margin, y, usable, scale.max(2), BLACK);
+        y += char_h * scale / 2;
+    }
+    for line in &screen.lines {
+        write(&mut canvas, line, scale, &mut y);
+    }
+    canvas
+}
+
+pub(crate) fn is_image(payload: &[u8]) -> bool {
+    match payload {
+        [0x89, b'P', b'N', b'G', ..] => true,
+        [b'P', b'1'..=b'5', c, ..] => c.is_ascii_whitespace(),
+        _ => false,
+    }
+}
+
+/// What to show for a message: images are fitted to the screen, anything
+/// else is taken as text
+pub fn render(payload: &[u8], width: u32, height: u32, font_size: u32) -> Result<Canvas, String> {
+    if is_image(payload) {
+        return Ok(Canvas::decode(payload)?.fit(width, height));
+    }
+    let text = std::str::from_utf8(payload).map_err(|_| "Neither an image nor text")?;
+    Ok(render_text(
+        &TextScreen::parse(text),
+        width,
+        height,
+        font_size,
+    ))
+}
+
+/// Shows what comes on `topic` until the connection breaks
+fn show_messages(
+    broker: &str,
+    client_id: &str,
+    topic: &str,
+    fb: &mut dyn Framebuffer,
+    font_size: u32,
+    last: &mut Option<Vec<u8>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = Client::new(client_id.to_string(), broker.to_string())?;
+    let mut client = client.connect(30)?;
+    client.subscribe(topic, QoS::AtLeastOnce)?;
+    info!("Showing what comes on {}", topic);
+    loop {
+        let msg = client.next_message()?;
+        // Retained messages come again on every reconnection
+        if last.as_ref() == Some(&msg.payload) {
+            continue;
+        }
+        let (width, height) = fb.size();
+        match render(&msg.payload, width, height, font_size) {
+            Ok(canvas) => fb.show(&canvas)?,
+            Err(e) => warn!("Nothing to show: {}", e),
+        }
+        *last = Some(msg.payload);
+    }
+}
+
+/// Shows what is published on `topic`, the full topic, in the background.
+/// The connection is made again whenever it breaks.
+pub fn spawn(config: DisplayConfig, broker: String, client_id: String, topic: String) {
+    thread::spawn(move || {
+        let mut fb = match framebuffer::open(&config) {
+            Ok(fb) => fb,
+            Err(e) => {
+                warn!("No display: {}", e);
+                return;
+            }
+        };
+        let mut last = None;
+        loop {
+            let res = show_messages(
+                &broker,
+                &client_id,
+                &topic,
+                fb.as_mut(),
+                config.font_size,
+                &mut last,
+            );
+            if let Err(e) = res {
+                warn!("Display: {}", e);
+            }
+            thread::sleep(RECONNECT_DELAY);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framebuffer::FileFramebuffer;
+
+    /// A 4x2 checkerboard
+    fn checkers() -> Canvas {
+        Canvas {
+            width: 4,
+            height: 2,
+            pixels: vec![0, 255, 0, 255, 255, 0, 255, 0],
+        }
+    }
+
+    #[test]
+    fn test_decode() {
+        let plain = b"P1\n# checkers\n4 2\n1 0 1 0\n0101\n";
+        assert_eq!(Canvas::decode(plain).unwrap(), checkers());
+        let binary = b"P4 4 2\n\xa0\x50";
+        assert_eq!(Canvas::decode(binary).unwrap(), checkers());
+        assert_eq!(Canvas::decode(&checkers().to_pgm()).unwrap(), checkers());
+        let png = checkers().to_png().unwrap();
+        assert_eq!(Canvas::decode(&png).unwrap(), checkers());
+
+        assert!(Canvas::decode(b"P4 4 2\n\xa0").is_err());
+        assert!(render(b"P4 4 2\n\xa0", 10, 10, 1).is_err());
+        assert!(Canvas::decode(b"hello").is_err());
+    }
+
+    #[test]
+    fn test_fit() {
+        let big = Canvas::new(40, 10);
+        let fitted = Canvas {
+            pixels: vec![0; 400],
+            ..big
+        }
+        .fit(20, 20);
+        assert_eq!((fitted.width, fitted.height), (20, 20));
+        // 20x5 in the middle
+        assert_eq!(fitted.get(0, 6), WHITE);
+        assert_eq!(fitted.get(0, 7), BLACK);
+        assert_eq!(fitted.get(19, 11), BLACK);
+        assert_eq!(fitted.get(19, 12), WHITE);
+    }
+
+    #[test]
+    fn test_wrap() {
+        assert_eq!(
+            wrap("the quick brown fox", 10),
+            vec!["the quick", "brown fox"]
+        );
+        assert_eq!(wrap("abcdefghij klm", 4), vec!["abcd", "efgh", "ij", "klm"]);
+        assert_eq!(wrap("", 4), vec![""]);
+    }
+
+    #[test]
+    fn test_show() {
+        let path = std::env::temp_dir().join(format!("kindle-fb-{}.pgm", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut fb = FileFramebuffer::new(path, 8, 6);
+
+        let canvas = render(b"P1 4 2 10100101", 8, 6, 1).unwrap();
+        fb.show(&canvas).unwrap();
+        let shown = Canvas::decode(&std::fs::read(path).unwrap()).unwrap();
+        let mut expected = Canvas::new(8, 6);
+        expected.draw(&checkers(), 2, 2);
+        assert_eq!(shown, expected);
+
+        let text = render(b"Title\nsome text", 200, 120, 1).unwrap();
+        let json = br#"{"title": "Title", "lines": ["some text"], "font_size": 1}"#;
+        assert_eq!(render(json, 200, 120, 3).unwrap(), text);
+        fb = FileFramebuffer::new(path, 200, 120);
+        fb.show(&text).unwrap();
+        let shown = Canvas::decode(&std::fs::read(path).unwrap()).unwrap();
+        assert_eq!(shown, text);
+        // Something in the title and the rule under it, nothing at the bottom
+        let dark = |y0: u32, y1: u32| (y0..y1).any(|y| (0..200).any(|x| shown.get(x, y) == BLACK));
+        assert!(dark(10, 50));
+        assert!(dark(50, 100));
+        assert!(!dark(100, 120));
+        std::fs::remove_file(path).unwrap();
+    }
+}
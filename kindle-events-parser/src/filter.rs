@@ -0,0 +1,580 @@
+//! Which events get published, as expressions compiled once:
+//!
+//! ```text
+//! com.lab126.powerd battLevelChanged where level < 20
+//! com.lab126.* * !battLevelChanged !/^scan/
+//! com.lab126.appmgrd appActivating where app ~ /reader/
+//! ```
+//!
+//! A source, by name, glob or `/regex/`, then the events to take, by name,
+//! glob or regex, each one after a `!` being left out, then conditions on their
+//! params. Params are named as in the catalog, or `$1`, `$2`... by position.
+//! Only the sources of the catalog can be reached by wildcards, LIPC needs a
+//! name to subscribe.
+
+use libopenlipc_sys::catalog::{self, acxreaderplugin, appmgrd, powerd, wifid};
+use libopenlipc_sys::{LipcEvent, LipcResult};
+use regex_lite::Regex;
+use serde::Deserialize;
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+
+#[derive(Debug, Clone)]
+enum Pattern {
+    Exact(String),
+    Glob(String),
+    Regex(Regex),
+}
+
+/// `*` for any run of characters, `?` for any one
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let t: Vec<char> = text.chars().collect();
+    let (mut pi, mut ti) = (0, 0);
+    // Where the last `*` was, and where in the text it matched up to
+    let mut star: Option<(usize, usize)> = None;
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == t[ti]) {
+            pi += 1;
+            ti += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, ti));
+            pi += 1;
+        } else if let Some((sp, st)) = star {
+            // The `*` takes one more character
+            pi = sp + 1;
+            ti = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+impl Pattern {
+    fn word(s: &str) -> Pattern {
+        if s.contains(&['*', '?'][..]) {
+            Pattern::Glob(s.to_string())
+        } else {
+            Pattern::Exact(s.to_string())
+        }
+    }
+
+    fn regex(s: &str) -> Result<Pattern, String> {
+        Regex::new(s)
+            .map(Pattern::Regex)
+            .map_err(|e| format!("Invalid regex /{}/: {}", s, e))
+    }
+
+    fn matches(&self, s: &str) -> bool {
+        match self {
+            Pattern::Exact(p) => p == s,
+            Pattern::Glob(p) => glob_matches(p, s),
+            Pattern::Regex(re) => re.is_match(s),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Cmp {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+    /// `~`, against a regex
+    Matches,
+}
+
+impl Cmp {
+    fn holds(self, ord: Ordering) -> bool {
+        match self {
+            Cmp::Lt => ord == Ordering::Less,
+            Cmp::Le => ord != Ordering::Greater,
+            Cmp::Gt => ord == Ordering::Greater,
+            Cmp::Ge => ord != Ordering::Less,
+            Cmp::Eq => ord == Ordering::Equal,
+            Cmp::Ne => ord != Ordering::Equal,
+            Cmp::Matches => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum ParamRef {
+    Name(String),
+    /// From 0
+    Index(usize),
+}
+
+#[derive(Debug, Clone)]
+struct Condition {
+    param: ParamRef,
+    cmp: Cmp,
+    value: String,
+    /// For `~`
+    regex: Option<Regex>,
+}
+
+impl Condition {
+    fn holds(&self, ev: &LipcEvent) -> bool {
+        let param = match &self.param {
+            ParamRef::Index(i) => ev.params.get(*i).cloned(),
+            ParamRef::Name(name) => {
+                let params = catalog::event(&ev.source, &ev.name).map(|e| e.params(ev));
+                params.and_then(|p| {
+                    p.int(name)
+                        .map(LipcResult::NUM)
+                        .or_else(|| p.str(name).map(|s| LipcResult::STR(s.to_string())))
+                })
+            }
+        };
+        let text = match &param {
+            Some(LipcResult::NUM(n)) => n.to_string(),
+            Some(LipcResult::STR(s)) => s.clone(),
+            None => return false,
+        };
+        if let Some(re) = &self.regex {
+            return re.is_match(&text);
+        }
+        match (&param, self.value.parse::<i64>()) {
+            (Some(LipcResult::NUM(n)), Ok(v)) => self.cmp.holds((*n as i64).cmp(&v)),
+            (Some(LipcResult::NUM(_)), Err(_)) => false,
+            _ => self.cmp.holds(text.as_str().cmp(&self.value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    source: Pattern,
+    /// Every event if empty
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+    conditions: Vec<Condition>,
+}
+
+impl Rule {
+    fn accepts(&self, ev: &LipcEvent) -> bool {
+        self.source.matches(&ev.source)
+            && (self.include.is_empty() || self.include.iter().any(|p| p.matches(&ev.name)))
+            && !self.exclude.iter().any(|p| p.matches(&ev.name))
+            && self.conditions.iter().all(|c| c.holds(ev))
+    }
+
+    /// The sources it can take events from; wildcards only reach the
+    /// services with events in the catalog
+    fn sources(&self) -> Vec<String> {
+        match &self.source {
+            Pattern::Exact(s) => vec![s.clone()],
+            pattern => catalog::event_services()
+                .into_iter()
+                .filter(|s| pattern.matches(s))
+                .map(|s| s.to_string())
+                .collect(),
+        }
+    }
+
+    /// The names of the events it takes, if it only takes some by name
+    fn names(&self) -> Option<Vec<String>> {
+        if self.include.is_empty() {
+            return None;
+        }
+        self.include
+            .iter()
+            .map(|p| match p {
+                Pattern::Exact(name) => Some(name.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Regex(String),
+    Op(&'static str),
+    Comma,
+    Not,
+}
+
+const OPS: &[&str] = &["<=", ">=", "==", "!=", "<", ">", "=", "~"];
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = s.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '"' || c == '/' {
+            let mut text = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => return Err(format!("Unclosed {}", c)),
+                    Some('\\') if chars.get(i + 1) == Some(&c) => {
+                        text.push(c);
+                        i += 2;
+                    }
+                    Some(&d) if d == c => {
+                        i += 1;
+                        break;
+                    }
+                    Some(&d) => {
+                        text.push(d);
+                        i += 1;
+                    }
+                }
+            }
+            tokens.push(if c == '"' {
+                Token::Quoted(text)
+            } else {
+                Token::Regex(text)
+            });
+        } else if c == ',' {
+            tokens.push(Token::Comma);
+            i += 1;
+        } else if let Some(op) = OPS
+            .iter()
+            .find(|op| chars[i..].iter().take(op.len()).copied().eq(op.chars()))
+        {
+            tokens.push(Token::Op(op));
+            i += op.len();
+        } else if c == '!' {
+            tokens.push(Token::Not);
+            i += 1;
+        } else {
+            let start = i;
+            while i < chars.len() && !chars[i].is_whitespace() && !"<>=!~,\"".contains(chars[i]) {
+                i += 1;
+            }
+            tokens.push(Token::Word(chars[start..i].iter().collect()));
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_conditions(tokens: &mut impl Iterator<Item = Token>) -> Result<Vec<Condition>, String> {
+    let mut conditions = vec![];
+    loop {
+        let param = match tokens.next() {
+            Some(Token::Word(w)) => match w.strip_prefix('$') {
+                Some(n) => match n.parse::<usize>() {
+                    Ok(n) if n > 0 => ParamRef::Index(n - 1),
+                    _ => return Err(format!("Params are numbered from $1, not {}", w)),
+                },
+                None => ParamRef::Name(w),
+            },
+            t => return Err(format!("Expected a param, got {:?}", t)),
+        };
+        let cmp = match tokens.next() {
+            Some(Token::Op(op)) => match op {
+                "<" => Cmp::Lt,
+                "<=" => Cmp::Le,
+                ">" => Cmp::Gt,
+                ">=" => Cmp::Ge,
+                "=" | "==" => Cmp::Eq,
+                "!=" => Cmp::Ne,
+                _ => Cmp::Matches,
+            },
+            t => return Err(format!("Expected a comparison, got {:?}", t)),
+        };
+        let value = match tokens.next() {
+            Some(Token::Word(v)) | Some(Token::Quoted(v)) | Some(Token::Regex(v)) => v,
+            t => return Err(format!("Expected a value, got {:?}", t)),
+        };
+        let regex = match cmp {
+            Cmp::Matches => {
+                Some(Regex::new(&value).map_err(|e| format!("Invalid regex /{}/: {}", value, e))?)
+            }
+            _ => None,
+        };
+        conditions.push(Condition {
+            param,
+            cmp,
+            value,
+            regex,
+        });
+        match tokens.next() {
+            None => return Ok(conditions),
+            Some(Token::Word(w)) if w == "and" => {}
+            t => return Err(format!("Expected and, got {:?}", t)),
+        }
+    }
+}
+
+fn parse_rule(expr: &str) -> Result<Rule, String> {
+    let mut tokens = tokenize(expr)?.into_iter();
+    let source = match tokens.next() {
+        Some(Token::Word(w)) => Pattern::word(&w),
+        Some(Token::Regex(r)) => Pattern::regex(&r)?,
+        t => return Err(format!("Expected a source, got {:?}", t)),
+    };
+    let mut rule = Rule {
+        source,
+        include: vec![],
+        exclude: vec![],
+        conditions: vec![],
+    };
+    while let Some(token) = tokens.next() {
+        match token {
+            Token::Word(w) if w == "where" => {
+                rule.conditions = parse_conditions(&mut tokens)?;
+                break;
+            }
+            Token::Comma => {}
+            Token::Not => match tokens.next() {
+                Some(Token::Word(w)) => rule.exclude.push(Pattern::word(&w)),
+                Some(Token::Regex(r)) => rule.exclude.push(Pattern::regex(&r)?),
+                t => return Err(format!("Expected an event after !, got {:?}", t)),
+            },
+            Token::Word(w) => rule.include.push(Pattern::word(&w)),
+            Token::Regex(r) => rule.include.push(Pattern::regex(&r)?),
+            t => return Err(format!("Unexpected {:?}", t)),
+        }
+    }
+    Ok(rule)
+}
+
+/// Events are taken if any of the expressions takes them
+#[derive(Deserialize, Debug, Clone)]
+#[serde(try_from = "Vec<String>")]
+pub struct Filter {
+    exprs: Vec<String>,
+    rules: Vec<Rule>,
+}
+
+impl TryFrom<Vec<String>> for Filter {
+    type Error = String;
+
+    fn try_from(exprs: Vec<String>) -> Result<Self, Self::Error> {
+        let rules = exprs
+            .iter()
+            .map(|e| parse_rule(e).map_err(|err| format!("{}: {}", e, err)))
+            .collect::<Result<_, _>>()?;
+        Ok(Filter { exprs, rules })
+    }
+}
+
+// The regexes can't be compared, the expressions they came from can
+impl PartialEq for Filter {
+    fn eq(&self, other: &Self) -> bool {
+        self.exprs == other.exprs
+    }
+}
+
+impl Default for Filter {
+    /// What the daemon publishes
+    fn default() -> Self {
+        Filter::parse(&[
+            powerd::SERVICE.to_string(),
+            appmgrd::SERVICE.to_string(),
+            format!(
+                "{} {}, {}",
+                wifid::SERVICE,
+                wifid::CM_CONNECTED.name,
+                wifid::CM_INTF_NOT_AVAILABLE.name
+            ),
+            format!(
+                "{} {}",
+                acxreaderplugin::SERVICE,
+                acxreaderplugin::ALL_READER_DATA_EVENT.name
+            ),
+        ])
+        .unwrap()
+    }
+}
+
+impl Filter {
+    pub fn parse<S: AsRef<str>>(exprs: &[S]) -> Result<Filter, String> {
+        Filter::try_from(
+            exprs
+                .iter()
+                .map(|e| e.as_ref().to_string())
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    pub fn accepts(&self, ev: &LipcEvent) -> bool {
+        self.rules.iter().any(|r| r.accepts(ev))
+    }
+
+    /// Takes what either `self` or `other` takes
+    pub fn union(&self, other: &Filter) -> Filter {
+        Filter {
+            exprs: self.exprs.iter().chain(&other.exprs).cloned().collect(),
+            rules: self.rules.iter().chain(&other.rules).cloned().collect(),
+        }
+    }
+
+    /// The fewest subscriptions getting every event that can be taken: a
+    /// source and an event name, or every event of the source
+    pub fn subscriptions(&self) -> Vec<(String, Option<String>)> {
+        // None for every event
+        let mut sources: Vec<(String, Option<Vec<String>>)> = vec![];
+        for rule in &self.rules {
+            let names = rule.names();
+            for source in rule.sources() {
+                let i = match sources.iter().position(|(s, _)| *s == source) {
+                    Some(i) => i,
+                    None => {
+                        sources.push((source, Some(vec![])));
+                        sources.len() - 1
+                    }
+                };
+                match (&mut sources[i].1, &names) {
+                    (Some(known), Some(names)) => {
+                        for name in names {
+                            if !known.contains(name) {
+                                known.push(name.clone());
+                            }
+                        }
+                    }
+                    (all, _) => *all = None,
+                }
+            }
+        }
+        let mut subs = vec![];
+        for (source, names) in sources {
+            match names {
+                None => subs.push((source, None)),
+                Some(names) => subs.extend(names.into_iter().map(|n| (source.clone(), Some(n)))),
+            }
+        }
+        subs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ev(source: &str, name: &str, params: Vec<LipcResult>) -> LipcEvent {
+        LipcEvent {
+            source: source.to_string(),
+            name: name.to_string(),
+            params,
+        }
+    }
+
+    #[test]
+    fn test_glob() {
+        assert!(glob_matches("com.lab126.*", "com.lab126.powerd"));
+        assert!(glob_matches("*Screen*", "goingToScreenSaver"));
+        assert!(glob_matches("cm?onnected", "cmConnected"));
+        assert!(!glob_matches("com.lab126.*", "com.example.other"));
+        assert!(!glob_matches("*Saver", "goingToScreenSaverNow"));
+    }
+
+    #[test]
+    fn test_accepts() {
+        let filter = Filter::parse(&[
+            "com.lab126.powerd battLevelChanged where level < 20",
+            "com.lab126.* * !battLevelChanged !/^scan/",
+            r#"com.lab126.appmgrd appActivating where $2 ~ /reader/ and status = 1"#,
+        ])
+        .unwrap();
+        let level = |n| {
+            ev(
+                powerd::SERVICE,
+                "battLevelChanged",
+                vec![LipcResult::NUM(n)],
+            )
+        };
+        assert!(filter.accepts(&level(15)));
+        assert!(!filter.accepts(&level(20)));
+        assert!(!filter.accepts(&ev(powerd::SERVICE, "battLevelChanged", vec![])));
+        assert!(filter.accepts(&ev(powerd::SERVICE, "goingToScreenSaver", vec![])));
+        assert!(!filter.accepts(&ev(wifid::SERVICE, "scanComplete", vec![])));
+        assert!(!filter.accepts(&ev("com.example.other", "anything", vec![])));
+
+        let activating = |status, app: &str| {
+            ev(
+                appmgrd::SERVICE,
+                "appActivating",
+                vec![LipcResult::NUM(status), LipcResult::STR(app.to_string())],
+            )
+        };
+        // Also taken by the second expression, without conditions
+        let strict = Filter::parse(&[
+            r#"com.lab126.appmgrd appActivating where $2 ~ /reader/ and status = 1"#,
+        ])
+        .unwrap();
+        assert!(strict.accepts(&activating(1, "com.lab126.booklet.reader")));
+        assert!(!strict.accepts(&activating(0, "com.lab126.booklet.reader")));
+        assert!(!strict.accepts(&activating(1, "com.lab126.booklet.home")));
+        assert!(filter.accepts(&activating(0, "com.lab126.booklet.home")));
+
+        let essid =
+            Filter::parse(&[r#"com.lab126.wifid cmConnected where essid != "guest""#]).unwrap();
+        let connected = |s: &str| {
+            ev(
+                wifid::SERVICE,
+                "cmConnected",
+                vec![LipcResult::STR(s.to_string())],
+            )
+        };
+        assert!(essid.accepts(&connected("home")));
+        assert!(!essid.accepts(&connected("guest")));
+    }
+
+    #[test]
+    fn test_subscriptions() {
+        let sub = |s: &str, e: Option<&str>| (s.to_string(), e.map(String::from));
+        let filter = Filter::parse(&[
+            "com.lab126.wifid cmConnected where essid = home",
+            "com.lab126.wifid cmConnected, scanComplete",
+            "com.lab126.powerd battLevelChanged",
+            "com.lab126.power* /Saver$/",
+            "com.example.other",
+        ])
+        .unwrap();
+        assert_eq!(
+            filter.subscriptions(),
+            vec![
+                sub(wifid::SERVICE, Some("cmConnected")),
+                sub(wifid::SERVICE, Some("scanComplete")),
+                sub(powerd::SERVICE, None),
+                sub("com.example.other", None),
+            ]
+        );
+        assert_eq!(
+            Filter::parse(&["com.lab126.* cmConnected"])
+                .unwrap()
+                .subscriptions()
+                .len(),
+            catalog::event_services().len()
+        );
+
+        let low = Filter::parse(&["com.lab126.powerd battLevelChanged where level < 20"]).unwrap();
+        let both = low.union(&Filter::parse(&["com.lab126.wifid cmConnected"]).unwrap());
+        assert_eq!(
+            both.subscriptions(),
+            vec![
+                sub(powerd::SERVICE, Some("battLevelChanged")),
+                sub(wifid::SERVICE, Some("cmConnected")),
+            ]
+        );
+        assert!(both.accepts(&ev(wifid::SERVICE, "cmConnected", vec![])));
+        // Every event of powerd, which the narrower rule can't take away
+        let all = low.union(&Filter::parse(&[powerd::SERVICE]).unwrap());
+        assert_eq!(all.subscriptions(), vec![sub(powerd::SERVICE, None)]);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(Filter::parse(&[""]).is_err());
+        assert!(Filter::parse(&["com.lab126.powerd /(/"]).is_err());
+        assert!(Filter::parse(&["com.lab126.powerd * where level"]).is_err());
+        assert!(Filter::parse(&["com.lab126.powerd * where level < 20 or"]).is_err());
+        assert!(Filter::parse(&["com.lab126.powerd * where $0 = 1"]).is_err());
+        let err = Filter::parse(&["com.lab126.powerd \"unclosed"]).unwrap_err();
+        assert!(err.starts_with("com.lab126.powerd"), "{}", err);
+    }
+}
@@ -0,0 +1,322 @@
+//! Where a canvas ends up: the e-ink screen, through `eips` or written to
+//! `/dev/fb0` directly, or a file standing in for the screen
+
+use crate::config::{DisplayConfig, DisplayOutput};
+use crate::display::{Canvas, Rect};
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::process::Command;
+
+pub trait Framebuffer: Send {
+    /// Width and height, in pixels
+    fn size(&self) -> (u32, u32);
+    /// Shows `canvas`, which has the size of the screen, with a full refresh
+    fn show(&mut self, canvas: &Canvas) -> Result<(), String>;
+    /// Shows the `region` of `canvas`, refreshing only that part of the
+    /// screen if the output can
+    fn show_region(&mut self, canvas: &Canvas, region: Rect) -> Result<(), String> {
+        let _ = region;
+        self.show(canvas)
+    }
+}
+
+/// The output in `config`
+pub fn open(config: &DisplayConfig) -> Result<Box<dyn Framebuffer>, String> {
+    Ok(match config.output {
+        DisplayOutput::Eips => Box::new(Eips {
+            width: config.width,
+            height: config.height,
+        }),
+        DisplayOutput::Framebuffer => Box::new(LinuxFramebuffer::open(&config.path)?),
+        DisplayOutput::File => Box::new(FileFramebuffer::new(
+            &config.path,
+            config.width,
+            config.height,
+        )),
+    })
+}
+
+/// Writes every canvas to a PGM file, for testing off the Kindle
+pub struct FileFramebuffer {
+    path: String,
+    width: u32,
+    height: u32,
+}
+
+impl FileFramebuffer {
+    pub fn new(path: &str, width: u32, height: u32) -> Self {
+        FileFramebuffer {
+            path: path.to_string(),
+            width,
+            height,
+        }
+    }
+}
+
+impl Framebuffer for FileFramebuffer {
+    fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    fn show(&mut self, canvas: &Canvas) -> Result<(), String> {
+        fs::write(&self.path, canvas.to_pgm())
+            .map_err(|e| format!("Failed to write {}: {}", self.path, e))
+    }
+}
+
+/// Hands a PNG to `eips`, which works on every model
+pub struct Eips {
+    width: u32,
+    height: u32,
+}
+
+const EIPS_IMAGE: &str = "/var/tmp/kindle-events-display.png";
+
+fn eips(canvas: &Canvas, args: &[&str]) -> Result<(), String> {
+    fs::write(EIPS_IMAGE, canvas.to_png()?)
+        .map_err(|e| format!("Failed to write {}: {}", EIPS_IMAGE, e))?;
+    let status = Command::new("eips")
+        .args(args)
+        .args(["-g", EIPS_IMAGE])
+        .status()
+        .map_err(|e| format!("Failed to run eips: {}", e))?;
+    if !status.success() {
+        return Err(format!("eips failed: {}", status));
+    }
+    Ok(())
+}
+
+impl Framebuffer for Eips {
+    fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    fn show(&mut self, canvas: &Canvas) -> Result<(), String> {
+        eips(canvas, &["-f"])
+    }
+
+    fn show_region(&mut self, canvas: &Canvas, region: Rect) -> Result<(), String> {
+        let (x, y) = (region.x.to_string(), region.y.to_string());
+        eips(&canvas.crop(region), &["-x", &x, "-y", &y])
+    }
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct FbBitfield {
+    offset: u32,
+    length: u32,
+    msb_right: u32,
+}
+
+/// `struct fb_var_screeninfo` of linux/fb.h
+#[repr(C)]
+#[derive(Default)]
+struct FbVarScreeninfo {
+    xres: u32,
+    yres: u32,
+    xres_virtual: u32,
+    yres_virtual: u32,
+    xoffset: u32,
+    yoffset: u32,
+    bits_per_pixel: u32,
+    grayscale: u32,
+    red: FbBitfield,
+    green: FbBitfield,
+    blue: FbBitfield,
+    transp: FbBitfield,
+    nonstd: u32,
+    activate: u32,
+    height: u32,
+    width: u32,
+    accel_flags: u32,
+    pixclock: u32,
+    left_margin: u32,
+    right_margin: u32,
+    upper_margin: u32,
+    lower_margin: u32,
+    hsync_len: u32,
+    vsync_len: u32,
+    sync: u32,
+    vmode: u32,
+    rotate: u32,
+    colorspace: u32,
+    reserved: [u32; 4],
+}
+
+/// `struct fb_fix_screeninfo` of linux/fb.h
+#[repr(C)]
+#[derive(Default)]
+struct FbFixScreeninfo {
+    id: [u8; 16],
+    smem_start: libc::c_ulong,
+    smem_len: u32,
+    kind: u32,
+    type_aux: u32,
+    visual: u32,
+    xpanstep: u16,
+    ypanstep: u16,
+    ywrapstep: u16,
+    line_length: u32,
+    mmio_start: libc::c_ulong,
+    mmio_len: u32,
+    accel: u32,
+    capabilities: u16,
+    reserved: [u16; 2],
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct MxcfbRect {
+    top: u32,
+    left: u32,
+    width: u32,
+    height: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct MxcfbAltBufferData {
+    phys_addr: u32,
+    width: u32,
+    height: u32,
+    alt_update_region: MxcfbRect,
+}
+
+/// `struct mxcfb_update_data` of the e-ink controller, as on the Paperwhites
+#[repr(C)]
+#[derive(Default)]
+struct MxcfbUpdateData {
+    update_region: MxcfbRect,
+    waveform_mode: u32,
+    update_mode: u32,
+    update_marker: u32,
+    hist_bw_waveform_mode: u32,
+    hist_gray_waveform_mode: u32,
+    temp: i32,
+    flags: u32,
+    alt_buffer_data: MxcfbAltBufferData,
+}
+
+const FBIOGET_VSCREENINFO: u32 = 0x4600;
+const FBIOGET_FSCREENINFO: u32 = 0x4602;
+/// `_IOW('F', 0x2E, struct mxcfb_update_data)`
+const MXCFB_SEND_UPDATE: u32 =
+    (1 << 30) | ((std::mem::size_of::<MxcfbUpdateData>() as u32) << 16) | (0x46 << 8) | 0x2e;
+const WAVEFORM_MODE_GC16: u32 = 2;
+const UPDATE_MODE_PARTIAL: u32 = 0;
+const UPDATE_MODE_FULL: u32 = 1;
+const TEMP_USE_AMBIENT: i32 = 0x1000;
+/// 0 is white instead of black
+const GRAYSCALE_8BIT_INVERTED: u32 = 2;
+
+/// Writes to the framebuffer device and asks the e-ink controller for a
+/// refresh. Only 8 bits per pixel framebuffers, like the Paperwhites', are
+/// supported.
+pub struct LinuxFramebuffer {
+    file: File,
+    var: FbVarScreeninfo,
+    line_length: u32,
+    marker: u32,
+}
+
+fn ioctl<T>(file: &File, request: u32, arg: &mut T, name: &str) -> Result<(), String> {
+    let res = unsafe { libc::ioctl(file.as_raw_fd(), request as _, arg as *mut T) };
+    if res < 0 {
+        return Err(format!(
+            "{} failed: {}",
+            name,
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+impl LinuxFramebuffer {
+    pub fn open(path: &str) -> Result<Self, String> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|e| format!("Failed to open {}: {}", path, e))?;
+        let mut var = FbVarScreeninfo::default();
+        ioctl(&file, FBIOGET_VSCREENINFO, &mut var, "FBIOGET_VSCREENINFO")?;
+        let mut fix = FbFixScreeninfo::default();
+        ioctl(&file, FBIOGET_FSCREENINFO, &mut fix, "FBIOGET_FSCREENINFO")?;
+        if var.bits_per_pixel != 8 {
+            return Err(format!(
+                "{} has {} bits per pixel, only 8 are supported, use eips",
+                path, var.bits_per_pixel
+            ));
+        }
+        Ok(LinuxFramebuffer {
+            file,
+            var,
+            line_length: fix.line_length,
+            marker: 0,
+        })
+    }
+}
+
+impl Framebuffer for LinuxFramebuffer {
+    fn size(&self) -> (u32, u32) {
+        (self.var.xres, self.var.yres)
+    }
+
+    fn show(&mut self, canvas: &Canvas) -> Result<(), String> {
+        let screen = Rect {
+            x: 0,
+            y: 0,
+            width: canvas.width,
+            height: canvas.height,
+        };
+        self.update(canvas, screen, UPDATE_MODE_FULL)
+    }
+
+    fn show_region(&mut self, canvas: &Canvas, region: Rect) -> Result<(), String> {
+        self.update(canvas, region, UPDATE_MODE_PARTIAL)
+    }
+}
+
+impl LinuxFramebuffer {
+    /// Writes `region` of `canvas` and refreshes it
+    fn update(&mut self, canvas: &Canvas, region: Rect, mode: u32) -> Result<(), String> {
+        let inverted = self.var.grayscale == GRAYSCALE_8BIT_INVERTED;
+        for y in region.y..region.y + region.height {
+            let start = (y * canvas.width + region.x) as usize;
+            let row = &canvas.pixels[start..start + region.width as usize];
+            let offset = (self.var.yoffset + y) as u64 * self.line_length as u64
+                + (self.var.xoffset + region.x) as u64;
+            let row: Vec<u8> = if inverted {
+                row.iter().map(|p| !p).collect()
+            } else {
+                row.to_vec()
+            };
+            self.file
+                .write_all_at(&row, offset)
+                .map_err(|e| format!("Failed to write to the framebuffer: {}", e))?;
+        }
+
+        self.marker = self.marker.wrapping_add(1);
+        let mut update = MxcfbUpdateData {
+            update_region: MxcfbRect {
+                top: region.y,
+                left: region.x,
+                width: region.width,
+                height: region.height,
+            },
+            waveform_mode: WAVEFORM_MODE_GC16,
+            update_mode: mode,
+            update_marker: self.marker,
+            temp: TEMP_USE_AMBIENT,
+            ..Default::default()
+        };
+        ioctl(
+            &self.file,
+            MXCFB_SEND_UPDATE,
+            &mut update,
+            "MXCFB_SEND_UPDATE",
+        )
+    }
+}
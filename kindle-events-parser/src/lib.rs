@@ -0,0 +1,681 @@
+pub mod awake;
+pub mod battery;
+pub mod cli;
+pub mod clock;
+pub mod config;
+pub mod daemon;
+pub mod dashboard;
+pub mod device;
+pub mod discovery;
+pub mod display;
+pub mod filter;
+pub mod framebuffer;
+pub mod limits;
+pub mod logging;
+pub mod metrics;
+pub mod publisher;
+pub mod reader;
+pub mod recording;
+pub mod scheduler;
+pub mod sessions;
+pub mod sinks;
+pub mod state;
+
+use awake::{KeepAwakeController, KEEP_AWAKE_TOPIC};
+use battery::BatteryMonitor;
+use clock::unix_now;
+use config::{Config, WakeConfig};
+use dashboard::Layout;
+use filter::Filter;
+use libopenlipc_sys::catalog::{acxreaderplugin, appmgrd, powerd, wifid};
+use libopenlipc_sys::{KindleEvent, LipcBackend, LipcEvent, LipcResult};
+use log::{debug, error, info, warn};
+use publisher::{Kind, Message, Publisher};
+use reader::{ReaderData, ReaderTracker, READER_DATA, READER_SERVICE};
+use scheduler::{Polled, Scheduler};
+use sessions::{SessionLog, SESSION_SUMMARY_TOPIC};
+use state::{DeviceState, SharedState, DEVICE_STATE_TOPIC};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Returns the topic, relative to the device's prefix, and message to publish
+/// for an event, if any
+pub fn run_and_match(event: &LipcEvent) -> Option<(&'static str, String)> {
+    debug!("[{}] {} || {:?}", event.source, event.name, event.params);
+
+    let ev = match KindleEvent::from_event(event) {
+        Ok(ev) => ev,
+        Err(e) => {
+            debug!("{}", e);
+            return None;
+        }
+    };
+
+    match ev {
+        KindleEvent::BatteryLevelChanged { level } => {
+            info!("Battery at {}%", level);
+            Some(("battery", level.to_string()))
+        }
+        KindleEvent::WifiDisconnected => {
+            info!("Wifi Disconnected");
+            Some(("connected", String::from("0")))
+        }
+        KindleEvent::WifiConnected { .. } => {
+            info!("Wifi Connected");
+            Some(("connected", String::from("1")))
+        }
+        KindleEvent::ScreenSaverExited => {
+            info!("Screen on");
+            Some(("screen", String::from("1")))
+        }
+        KindleEvent::ScreenSaverEntered => {
+            info!("Screen off");
+            Some(("screen", String::from("0")))
+        }
+        _ => {
+            debug!("Nothing to publish for {:?}", ev);
+            None
+        }
+    }
+}
+
+pub fn on_event(publisher: &Publisher, ev: &LipcEvent) {
+    if let Some((topic, m)) = run_and_match(ev) {
+        send_or_log(
+            publisher,
+            &Message {
+                topic,
+                source: &ev.source,
+                event: &ev.name,
+                value: m.as_str(),
+                kind: Kind::Number,
+            },
+        );
+    }
+}
+
+/// Publishes the Home Assistant discovery configs and marks the Kindle as
+/// available; everything is retained, so it survives Home Assistant restarts
+pub fn announce(publisher: &Publisher, device_id: &str) {
+    for (topic, m) in discovery::discovery_messages(publisher, device_id) {
+        if let Err(e) = publisher.publish_retained(&topic, &m) {
+            warn!("Failed to publish: {}", e);
+        }
+    }
+    if let Err(e) = publisher.send_retained(discovery::AVAILABILITY_TOPIC, "online") {
+        warn!("Failed to publish: {}", e);
+    }
+}
+
+/// The events the device state, the sessions, the link and the
+/// announcements follow, whatever the filters
+fn followed_events() -> Filter {
+    Filter::parse(&[
+        powerd::SERVICE.to_string(),
+        format!(
+            "{} {}, {}",
+            wifid::SERVICE,
+            wifid::CM_CONNECTED.name,
+            wifid::CM_INTF_NOT_AVAILABLE.name
+        ),
+        format!("{} {}", appmgrd::SERVICE, appmgrd::APP_ACTIVATING.name),
+        format!(
+            "{} {}",
+            acxreaderplugin::SERVICE,
+            acxreaderplugin::ALL_READER_DATA_EVENT.name
+        ),
+    ])
+    .unwrap()
+}
+
+/// Subscribes `handler` to the events `filter` takes, with the fewest
+/// subscriptions that get them all
+pub fn subscribe_all<B, F>(r: &B, filter: &Filter, handler: F) -> Result<(), String>
+where
+    B: LipcBackend,
+    F: FnMut(&LipcEvent) + Send + Clone + 'static,
+{
+    let filter = Arc::new(filter.clone());
+    for (source, name) in filter.subscriptions() {
+        let filter = filter.clone();
+        let mut handler = handler.clone();
+        r.subscribe_events(
+            &source,
+            name.as_deref(),
+            Box::new(move |ev| {
+                if filter.accepts(ev) {
+                    handler(ev)
+                }
+            }),
+        )?;
+    }
+    Ok(())
+}
+
+fn send_or_log(publisher: &Publisher, msg: &Message) {
+    if let Err(e) = publisher.send_message(msg) {
+        warn!("Failed to publish: {}", e);
+    }
+}
+
+/// Publishes the fields of `allReaderData` on their own topics, and the
+/// summary of the reading session it ended, if any
+fn publish_reader_data(
+    publisher: &Publisher,
+    tracker: &Mutex<ReaderTracker>,
+    sessions: &Mutex<SessionLog>,
+    json: &str,
+) {
+    match tracker.lock().unwrap().update(json) {
+        Ok(msgs) => {
+            for (topic, m) in msgs {
+                send_or_log(
+                    publisher,
+                    &Message {
+                        topic: &topic,
+                        source: READER_SERVICE,
+                        event: READER_DATA,
+                        value: &m,
+                        kind: reader::kind(&topic),
+                    },
+                );
+            }
+        }
+        Err(e) => warn!("{}", e),
+    }
+    if let Ok(data) = ReaderData::parse(json) {
+        if let Some(summary) = sessions.lock().unwrap().on_reader_data(&data, unix_now()) {
+            send_or_log(
+                publisher,
+                &Message {
+                    topic: SESSION_SUMMARY_TOPIC,
+                    source: READER_SERVICE,
+                    event: READER_DATA,
+                    value: &summary,
+                    kind: Kind::Json,
+                },
+            );
+        }
+    }
+}
+
+fn publish_polled(
+    publisher: &Publisher,
+    tracker: &Mutex<ReaderTracker>,
+    sessions: &Mutex<SessionLog>,
+    polled: &Polled,
+) {
+    send_or_log(
+        publisher,
+        &Message {
+            topic: &polled.topic,
+            source: &polled.service,
+            event: &polled.property,
+            value: &polled.value,
+            kind: if polled.service == READER_SERVICE && polled.property == READER_DATA {
+                Kind::Json
+            } else {
+                polled.kind.into()
+            },
+        },
+    );
+    if polled.service == READER_SERVICE && polled.property == READER_DATA {
+        publish_reader_data(publisher, tracker, sessions, &polled.value);
+    }
+}
+
+fn publish_state(publisher: &Publisher, state: &DeviceState) {
+    if let Err(e) = publisher.send_retained(DEVICE_STATE_TOPIC, &state.to_json()) {
+        warn!("Failed to publish: {}", e);
+    }
+}
+
+/// Follows the wifi, so the publisher holds messages back while it is down,
+/// and tells when suspend should be held off for the last ones to go out
+fn on_link_event(publisher: &Publisher, hold_suspend: &AtomicBool, ev: &KindleEvent) {
+    let online = match ev {
+        KindleEvent::WifiConnected { .. } => true,
+        // The radio goes down with the device, before wifid tells
+        KindleEvent::WifiDisconnected | KindleEvent::Suspending => false,
+        KindleEvent::ScreenSaverEntered | KindleEvent::ReadyToSuspend => {
+            hold_suspend.store(true, Ordering::Relaxed);
+            return;
+        }
+        _ => return,
+    };
+    if let Err(e) = publisher.set_online(online) {
+        warn!("Failed to publish: {}", e);
+    }
+}
+
+/// Holds off suspend for a bit and publishes what is left while the wifi is
+/// still up
+fn hold_suspend<B: LipcBackend>(r: &B, publisher: &Publisher, config: &WakeConfig) {
+    if config.defer_suspend_secs > 0 {
+        match r.set_int(powerd::DEFER_SUSPEND, config.defer_suspend_secs) {
+            Ok(()) => debug!("Suspend deferred by {}s", config.defer_suspend_secs),
+            Err(e) => warn!("Failed to defer suspend: {}", e),
+        }
+    }
+    if let Err(e) = publisher.flush_state() {
+        warn!("Failed to publish: {}", e);
+    }
+    if let Err(e) = publisher.flush_held(true) {
+        warn!("Failed to publish: {}", e);
+    }
+}
+
+fn publish_battery<B: LipcBackend>(r: &B, publisher: &Publisher, monitor: &mut BatteryMonitor) {
+    match battery::read(r) {
+        Ok(reading) => {
+            for (topic, m) in monitor.sample(reading, unix_now()) {
+                send_or_log(
+                    publisher,
+                    &Message {
+                        topic: &topic,
+                        source: powerd::SERVICE,
+                        event: powerd::BATT_LEVEL.name,
+                        value: &m,
+                        kind: battery::kind(&topic),
+                    },
+                );
+            }
+        }
+        Err(e) => warn!("Failed to read the battery state: {}", e),
+    }
+}
+
+/// Reads every configured property and the battery, and publishes them once,
+/// for running from cron or upstart instead of as a daemon
+pub fn once<B: LipcBackend>(r: &B, config: &Config) -> Result<(), String> {
+    let device_id = device::device_id(r, config.mqtt.device_id.as_deref());
+    let publisher = Publisher::new(config, &device_id)?;
+    announce(&publisher, &device_id);
+    publish_state(&publisher, &DeviceState::bootstrap(r));
+
+    let tracker = Mutex::new(ReaderTracker::new());
+    // A single reading can't end a session, so the statistics are left alone
+    let sessions = Mutex::new(SessionLog::new(&config.stats_path, Default::default()));
+    for polled in Scheduler::new(config.poll.clone(), Instant::now()).tick(r, Instant::now()) {
+        publish_polled(&publisher, &tracker, &sessions, &polled);
+    }
+    if config.battery.enabled {
+        publish_battery(
+            r,
+            &publisher,
+            &mut BatteryMonitor::new(config.battery.clone()),
+        );
+    }
+    let res = publisher.flush_state();
+    // Whatever the limits held back, there won't be a later
+    publisher.flush_held(true).map_err(|e| e.to_string())?;
+    res.map_err(|e| e.to_string())
+}
+
+/// Listens and publishes until SIGTERM or SIGINT. On SIGHUP, the
+/// configuration is read again with `load`; the device id, the stats path,
+/// the display, the keep awake topic and the event filters only change on
+/// restart. Fails on an invalid sink or if the events can't be subscribed to.
+pub fn run<B, L>(r: &B, mut config: Config, load: L) -> Result<(), String>
+where
+    B: LipcBackend,
+    L: Fn() -> Result<Config, String>,
+{
+    let device_id = device::device_id(r, config.mqtt.device_id.as_deref());
+    let publisher =
+        Publisher::new(&config, &device_id).map_err(|e| format!("Invalid sink: {}", e))?;
+    info!("Publishing to {}", publisher.prefix);
+    announce(&publisher, &device_id);
+    if config
+        .sink
+        .iter()
+        .any(|s| matches!(s, config::SinkConfig::Mqtt { .. }))
+    {
+        discovery::spawn_presence(
+            config.mqtt.broker.clone(),
+            format!("kindle-{}-presence", device_id),
+            publisher.topic(discovery::AVAILABILITY_TOPIC),
+        );
+    }
+    let state: SharedState = Arc::new(Mutex::new(DeviceState::bootstrap(r)));
+    if state.lock().unwrap().wifi_connected == Some(false) {
+        let _ = publisher.set_online(false);
+    }
+    publish_state(&publisher, &state.lock().unwrap());
+    let hold = Arc::new(AtomicBool::new(false));
+    if let (true, Some(path)) = (config.display.enabled, &config.display.layout) {
+        match Layout::load(path) {
+            Ok(layout) => dashboard::spawn(
+                config.display.clone(),
+                layout,
+                config.mqtt.broker.clone(),
+                format!("kindle-{}-display", device_id),
+            ),
+            Err(e) => error!("Invalid dashboard layout: {}", e),
+        }
+    } else if config.display.enabled {
+        display::spawn(
+            config.display.clone(),
+            config.mqtt.broker.clone(),
+            format!("kindle-{}-display", device_id),
+            publisher.topic(&config.display.topic),
+        );
+    }
+
+    let mut awake = KeepAwakeController::new(r);
+    if let (true, Some(topic)) = (config.keep_awake.enabled, &config.keep_awake.topic) {
+        awake::spawn(
+            config.mqtt.broker.clone(),
+            format!("kindle-{}-awake", device_id),
+            publisher.topic(topic),
+            awake.request(),
+        );
+    }
+
+    let sessions = SessionLog::open(&config.stats_path).unwrap_or_else(|e| {
+        warn!("Reading statistics start over: {}", e);
+        SessionLog::new(&config.stats_path, Default::default())
+    });
+    let sessions = Arc::new(Mutex::new(sessions));
+    // Nothing is polled while the screensaver is on or the device is suspended
+    let asleep = Arc::new(AtomicBool::new(false));
+    let tracker = Arc::new(Mutex::new(ReaderTracker::new()));
+    let t = tracker.clone();
+    let s = sessions.clone();
+    let m = publisher.clone();
+    let filter = config.events.filters.clone();
+    let followed = followed_events();
+    let st = state.clone();
+    let h = hold.clone();
+    let a = asleep.clone();
+    let id = device_id.clone();
+    // One set of subscriptions for both: the filters only decide what gets
+    // published
+    subscribe_all(r, &filter.union(&followed), move |ev| {
+        if filter.accepts(ev) {
+            if let (READER_SERVICE, READER_DATA, Some(LipcResult::STR(json))) =
+                (ev.source.as_str(), ev.name.as_str(), ev.params.first())
+            {
+                publish_reader_data(&m, &t, &s, json);
+            }
+            on_event(&m, ev);
+        }
+        if !followed.accepts(ev) {
+            return;
+        }
+        if ev.source == powerd::SERVICE {
+            if let Some(state) = scheduler::asleep_after(&ev.name) {
+                a.store(state, Ordering::Relaxed);
+            }
+        }
+        // The broker may have been restarted while we were offline
+        if wifid::CM_CONNECTED.matches(ev) {
+            announce(&m, &id);
+        }
+        match KindleEvent::from_event(ev) {
+            Ok(kev) => {
+                on_link_event(&m, &h, &kev);
+                let mut st = st.lock().unwrap();
+                if st.apply(&kev, unix_now()) {
+                    publish_state(&m, &st);
+                }
+            }
+            Err(e) => debug!("{}", e),
+        }
+        if let Some(summary) = s.lock().unwrap().on_event(ev, unix_now()) {
+            send_or_log(
+                &m,
+                &Message {
+                    topic: SESSION_SUMMARY_TOPIC,
+                    source: &ev.source,
+                    event: &ev.name,
+                    value: &summary,
+                    kind: Kind::Json,
+                },
+            );
+        }
+    })
+    .map_err(|e| format!("Failed to subscribe: {}", e))?;
+
+    let mut scheduler = Scheduler::new(config.poll.clone(), Instant::now());
+    let mut battery = BatteryMonitor::new(config.battery.clone());
+    let mut last_battery: Option<Instant> = None;
+    while !daemon::should_stop() {
+        for _ in 0..5 {
+            if daemon::should_stop() {
+                break;
+            }
+            if hold.swap(false, Ordering::Relaxed) {
+                hold_suspend(r, &publisher, &config.wake);
+            }
+            if let Err(e) = publisher.flush_held(false) {
+                warn!("Failed to publish: {}", e);
+            }
+            std::thread::sleep(std::time::Duration::from_secs(1));
+        }
+        if daemon::take_reload() {
+            match load().and_then(|c| publisher.reload(&c).map(|_| c)) {
+                Ok(c) => {
+                    if let Err(e) = logging::reload(&c.log) {
+                        error!("Keeping the previous log settings: {}", e);
+                    }
+                    scheduler = Scheduler::new(c.poll.clone(), Instant::now());
+                    battery = BatteryMonitor::new(c.battery.clone());
+                    last_battery = None;
+                    config = c;
+                    info!("Configuration reloaded");
+                }
+                Err(e) => error!("Keeping the previous configuration: {}", e),
+            }
+        }
+        scheduler.set_paused(asleep.load(Ordering::Relaxed));
+        for polled in scheduler.tick(r, Instant::now()) {
+            publish_polled(&publisher, &tracker, &sessions, &polled);
+        }
+        let battery_due =
+            last_battery.is_none_or(|t| t.elapsed().as_secs() >= config.battery.interval_secs);
+        if config.battery.enabled && battery_due {
+            last_battery = Some(Instant::now());
+            publish_battery(r, &publisher, &mut battery);
+        }
+        match awake.tick(&config.keep_awake, unix_now()) {
+            Ok(Some(on)) => {
+                info!("Keeping the screen on: {}", on);
+                let value = if on { "1" } else { "0" };
+                if let Err(e) = publisher.send_retained(KEEP_AWAKE_TOPIC, value) {
+                    warn!("Failed to publish: {}", e);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Failed to keep the screen on: {}", e),
+        }
+        // What the broker could not take right after the network came back
+        if let Err(e) = publisher.send_pending() {
+            warn!("Failed to publish: {}", e);
+        }
+        if let Err(e) = publisher.flush_state() {
+            warn!("Failed to publish: {}", e);
+        }
+    }
+
+    info!("Stopping");
+    if awake.release() {
+        if let Err(e) = publisher.send_retained(KEEP_AWAKE_TOPIC, "0") {
+            warn!("Failed to publish: {}", e);
+        }
+    }
+    if let Err(e) = publisher.flush_state() {
+        warn!("Failed to publish: {}", e);
+    }
+    if let Err(e) = publisher.send_retained(discovery::AVAILABILITY_TOPIC, "offline") {
+        warn!("Failed to publish: {}", e);
+    }
+    // Last, as the limits may hold back any of the above
+    if let Err(e) = publisher.flush_held(true) {
+        warn!("Failed to publish: {}", e);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(source: &str, name: &str, params: Vec<LipcResult>) -> LipcEvent {
+        LipcEvent {
+            source: source.to_string(),
+            name: name.to_string(),
+            params,
+        }
+    }
+
+    #[test]
+    fn test_match_battery() {
+        assert_eq!(
+            run_and_match(&event(
+                "com.lab126.powerd",
+                "battLevelChanged",
+                vec![LipcResult::NUM(67)]
+            )),
+            Some(("battery", String::from("67")))
+        );
+        // battLevelChanged always carries the level, without it there's nothing to publish
+        assert_eq!(
+            run_and_match(&event("com.lab126.powerd", "battLevelChanged", vec![])),
+            None
+        );
+    }
+
+    #[test]
+    fn test_match_screen_and_wifi() {
+        assert_eq!(
+            run_and_match(&event("com.lab126.powerd", "goingToScreenSaver", vec![])),
+            Some(("screen", String::from("0")))
+        );
+        assert_eq!(
+            run_and_match(&event(
+                "com.lab126.powerd",
+                "outOfScreenSaver",
+                vec![LipcResult::NUM(1)]
+            )),
+            Some(("screen", String::from("1")))
+        );
+        // The screen is on whatever the param says
+        assert_eq!(
+            run_and_match(&event(
+                "com.lab126.powerd",
+                "outOfScreenSaver",
+                vec![LipcResult::STR(String::from("1"))]
+            )),
+            Some(("screen", String::from("1")))
+        );
+        assert_eq!(
+            run_and_match(&event("com.lab126.wifid", "cmConnected", vec![])),
+            Some(("connected", String::from("1")))
+        );
+        assert_eq!(
+            run_and_match(&event("com.lab126.wifid", "cmIntfNotAvailable", vec![])),
+            Some(("connected", String::from("0")))
+        );
+        // Suspending says nothing about the wifi
+        assert_eq!(
+            run_and_match(&event("com.lab126.powerd", "suspending", vec![])),
+            None
+        );
+    }
+
+    #[test]
+    fn test_match_unknown() {
+        assert_eq!(
+            run_and_match(&event(
+                "com.lab126.appmgrd",
+                "appActivating",
+                vec![
+                    LipcResult::NUM(1),
+                    LipcResult::STR(String::from("com.lab126.booklet.reader"))
+                ]
+            )),
+            None
+        );
+    }
+
+    #[cfg(feature = "mock")]
+    #[test]
+    fn test_wake() {
+        use libopenlipc_sys::MockLipc;
+
+        let r = MockLipc::new();
+        let p = Publisher::with_sinks(Default::default(), "x", vec![]);
+        let hold = AtomicBool::new(false);
+        on_link_event(&p, &hold, &KindleEvent::ScreenSaverEntered);
+        assert!(hold.load(Ordering::Relaxed) && p.is_online());
+        hold_suspend(&r, &p, &WakeConfig::default());
+        assert_eq!(r.get_int(powerd::DEFER_SUSPEND), Ok(10));
+
+        on_link_event(&p, &hold, &KindleEvent::Suspending);
+        assert!(!p.is_online());
+        on_link_event(&p, &hold, &KindleEvent::Resuming);
+        assert!(!p.is_online());
+        on_link_event(&p, &hold, &KindleEvent::WifiConnected { essid: None });
+        assert!(p.is_online());
+    }
+
+    #[cfg(feature = "mock")]
+    #[test]
+    fn test_subscribe_all() {
+        use libopenlipc_sys::MockLipc;
+        use std::sync::{Arc, Mutex};
+
+        let r = MockLipc::new();
+        let published = Arc::new(Mutex::new(vec![]));
+        let p = published.clone();
+        subscribe_all(&r, &Filter::default(), move |ev| {
+            if let Some(msg) = run_and_match(ev) {
+                p.lock().unwrap().push(msg);
+            }
+        })
+        .unwrap();
+
+        assert_eq!(
+            r.subscriptions(),
+            vec![
+                (String::from("com.lab126.powerd"), None),
+                (String::from("com.lab126.appmgrd"), None),
+                (
+                    String::from("com.lab126.wifid"),
+                    Some(String::from("cmConnected"))
+                ),
+                (
+                    String::from("com.lab126.wifid"),
+                    Some(String::from("cmIntfNotAvailable"))
+                ),
+                (
+                    String::from("com.lab126.acxreaderplugin"),
+                    Some(String::from("allReaderData"))
+                ),
+            ]
+        );
+
+        r.inject_event(
+            "com.lab126.powerd",
+            "battLevelChanged",
+            Some(LipcResult::NUM(42)),
+        );
+        r.inject_event("com.lab126.wifid", "cmConnected", None);
+        r.inject_event("com.lab126.wifid", "cmIntfNotAvailable", None);
+        // Not subscribed to
+        r.inject_event("com.lab126.wifid", "scanComplete", None);
+        r.inject_event("com.lab126.powerd", "goingToScreenSaver", None);
+
+        assert_eq!(
+            *published.lock().unwrap(),
+            vec![
+                ("battery", String::from("42")),
+                ("connected", String::from("1")),
+                ("connected", String::from("0")),
+                ("screen", String::from("0")),
+            ]
+        );
+    }
+}
@@ -0,0 +1,215 @@
+//! Holding back messages on topics that change too often: battery levels
+//! while charging, a flapping wifi... Whatever is held back is published
+//! later, so the last value always gets out.
+
+use crate::config::LimitConfig;
+use crate::sinks::topic_matches;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// A message to publish now
+#[derive(Debug, Clone, PartialEq)]
+pub struct Release {
+    pub name: String,
+    pub payload: String,
+    pub retain: bool,
+}
+
+#[derive(Debug)]
+struct Held {
+    key: String,
+    payload: String,
+    retain: bool,
+    /// When the last value came
+    at: Instant,
+}
+
+#[derive(Debug, Default)]
+struct TopicState {
+    /// When the last value was published, and its key
+    sent: Option<(Instant, String)>,
+    held: Option<Held>,
+}
+
+/// What the rules let through, per topic
+#[derive(Debug, Default)]
+pub struct Limiter {
+    rules: Vec<LimitConfig>,
+    topics: HashMap<String, TopicState>,
+}
+
+impl Limiter {
+    pub fn new(rules: Vec<LimitConfig>) -> Self {
+        Limiter {
+            rules,
+            topics: HashMap::new(),
+        }
+    }
+
+    /// Applies to what comes next; what is held stays held
+    pub fn set_rules(&mut self, rules: Vec<LimitConfig>) {
+        self.rules = rules;
+    }
+
+    /// The first rule for `name`
+    fn rule(&self, name: &str) -> Option<&LimitConfig> {
+        self.rules
+            .iter()
+            .find(|r| r.topics.iter().any(|p| topic_matches(p, name)))
+    }
+
+    /// Offers `payload` for `name` at `now`; `key` is what tells values
+    /// apart, as the payload can hold a timestamp. Returns it if it is to be
+    /// published now, otherwise it is held or dropped as a duplicate.
+    pub fn offer(
+        &mut self,
+        name: &str,
+        key: &str,
+        payload: &str,
+        retain: bool,
+        now: Instant,
+    ) -> Option<Release> {
+        let rule = match self.rule(name) {
+            Some(rule) => rule.clone(),
+            None => {
+                return Some(Release {
+                    name: name.to_string(),
+                    payload: payload.to_string(),
+                    retain,
+                })
+            }
+        };
+        let state = self.topics.entry(name.to_string()).or_default();
+        if rule.dedupe && state.sent.as_ref().is_some_and(|(_, k)| k == key) {
+            // Back to what was published, nothing changed after all
+            state.held = None;
+            return None;
+        }
+        state.held = Some(Held {
+            key: key.to_string(),
+            payload: payload.to_string(),
+            retain,
+            at: now,
+        });
+        release(name, state, &rule, now)
+    }
+
+    /// The held messages whose time came
+    pub fn due(&mut self, now: Instant) -> Vec<Release> {
+        let mut released = vec![];
+        for (name, state) in self.topics.iter_mut() {
+            let rule = self
+                .rules
+                .iter()
+                .find(|r| r.topics.iter().any(|p| topic_matches(p, name)));
+            // Without a rule anymore, there is no reason to wait
+            let rule = rule.cloned().unwrap_or_default();
+            released.extend(release(name, state, &rule, now));
+        }
+        released
+    }
+
+    /// Every held message, before suspending or stopping
+    pub fn drain(&mut self, now: Instant) -> Vec<Release> {
+        let mut released = vec![];
+        for (name, state) in self.topics.iter_mut() {
+            released.extend(release(name, state, &LimitConfig::default(), now));
+        }
+        released
+    }
+}
+
+/// The held message of `state`, if `rule` lets it out at `now`
+fn release(
+    name: &str,
+    state: &mut TopicState,
+    rule: &LimitConfig,
+    now: Instant,
+) -> Option<Release> {
+    let held = state.held.as_ref()?;
+    let quiet = now >= held.at + Duration::from_secs(rule.debounce_secs);
+    let allowed = state
+        .sent
+        .as_ref()
+        .is_none_or(|(at, _)| now >= *at + Duration::from_secs(rule.throttle_secs));
+    if !quiet || !allowed {
+        return None;
+    }
+    let held = state.held.take()?;
+    state.sent = Some((now, held.key));
+    Some(Release {
+        name: name.to_string(),
+        payload: held.payload,
+        retain: held.retain,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payloads(released: Vec<Release>) -> Vec<String> {
+        released.into_iter().map(|r| r.payload).collect()
+    }
+
+    #[test]
+    fn test_throttle_and_dedupe() {
+        let mut limiter = Limiter::new(vec![LimitConfig {
+            topics: vec![String::from("battery/#")],
+            throttle_secs: 60,
+            dedupe: true,
+            ..Default::default()
+        }]);
+        let t0 = Instant::now();
+        let at = |secs| t0 + Duration::from_secs(secs);
+
+        // No rule, no limit
+        assert!(limiter.offer("screen", "1", "1", false, t0).is_some());
+        assert!(limiter.offer("screen", "1", "1", false, t0).is_some());
+
+        let sent = limiter.offer("battery", "67", "67", false, t0).unwrap();
+        assert_eq!(sent.name, "battery");
+        assert_eq!(limiter.offer("battery", "68", "68", false, at(10)), None);
+        assert_eq!(limiter.offer("battery", "69", "69", false, at(20)), None);
+        assert_eq!(payloads(limiter.due(at(59))), Vec::<String>::new());
+        // The last value, once the minute is over
+        assert_eq!(payloads(limiter.due(at(60))), vec!["69"]);
+        assert_eq!(payloads(limiter.due(at(200))), Vec::<String>::new());
+
+        assert_eq!(limiter.offer("battery", "69", "69", false, at(200)), None);
+        assert!(limiter
+            .offer("battery", "70", "70", false, at(200))
+            .is_some());
+        // Back to the published value before the held one got out
+        assert_eq!(limiter.offer("battery", "71", "71", false, at(210)), None);
+        assert_eq!(limiter.offer("battery", "70", "70", false, at(220)), None);
+        assert_eq!(payloads(limiter.due(at(300))), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_debounce() {
+        let mut limiter = Limiter::new(vec![LimitConfig {
+            topics: vec![String::from("connected")],
+            debounce_secs: 5,
+            ..Default::default()
+        }]);
+        let t0 = Instant::now();
+        let at = |secs| t0 + Duration::from_secs(secs);
+
+        assert_eq!(limiter.offer("connected", "0", "0", false, t0), None);
+        assert_eq!(limiter.offer("connected", "1", "1", false, at(3)), None);
+        assert_eq!(limiter.offer("connected", "0", "0", true, at(6)), None);
+        assert_eq!(payloads(limiter.due(at(10))), Vec::<String>::new());
+        assert_eq!(
+            limiter.due(at(11)),
+            vec![Release {
+                name: String::from("connected"),
+                payload: String::from("0"),
+                retain: true,
+            }]
+        );
+
+        assert_eq!(limiter.offer("connected", "1", "1", false, at(20)), None);
+        assert_eq!(payloads(limiter.drain(at(20))), vec!["1"]);
+    }
+}
@@ -0,0 +1,205 @@
+//! Where log records go: stderr, syslog or a file rotated by size. The level
+//! and destination can be changed while running, on configuration reload.
+
+use crate::clock::{iso8601, unix_now};
+use crate::config::{LogConfig, LogLevel, LogTarget};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// Shows up in front of every syslog line; openlog keeps the pointer
+static IDENT: &[u8] = b"kindle-events\0";
+
+/// Appends to `path`, moving it to `<path>.1` before it grows past
+/// `max_bytes`
+struct RotatingFile {
+    path: String,
+    max_bytes: u64,
+    file: File,
+    size: u64,
+}
+
+fn open_append(path: &str) -> Result<File, String> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("Failed to open {}: {}", path, e))
+}
+
+impl RotatingFile {
+    fn open(path: &str, max_bytes: u64) -> Result<RotatingFile, String> {
+        let file = open_append(path)?;
+        let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+        Ok(RotatingFile {
+            path: path.to_string(),
+            max_bytes,
+            file,
+            size,
+        })
+    }
+
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_bytes {
+            fs::rename(&self.path, format!("{}.1", self.path))?;
+            self.file = open_append(&self.path).map_err(io::Error::other)?;
+            self.size = 0;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+enum Output {
+    Stderr,
+    Syslog,
+    File(RotatingFile),
+}
+
+struct Logger {
+    output: Mutex<Output>,
+    /// Whether the process runs as a daemon, which changes the default target
+    background: AtomicBool,
+}
+
+static LOGGER: Logger = Logger {
+    output: Mutex::new(Output::Stderr),
+    background: AtomicBool::new(false),
+};
+
+fn syslog_priority(level: Level) -> libc::c_int {
+    match level {
+        Level::Error => libc::LOG_ERR,
+        Level::Warn => libc::LOG_WARNING,
+        Level::Info => libc::LOG_INFO,
+        Level::Debug | Level::Trace => libc::LOG_DEBUG,
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        // Failing to log has nowhere to be reported
+        match &mut *self.output.lock().unwrap() {
+            Output::Stderr => {
+                let _ = io::stderr().write_all(line(record).as_bytes());
+            }
+            Output::Syslog => {
+                let msg = format!("{}: {}", record.target(), record.args()).replace('\0', "");
+                let msg = CString::new(msg).unwrap();
+                unsafe {
+                    libc::syslog(
+                        syslog_priority(record.level()),
+                        b"%s\0".as_ptr() as *const libc::c_char,
+                        msg.as_ptr(),
+                    );
+                }
+            }
+            Output::File(f) => {
+                let _ = f.write(&line(record));
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Output::File(f) = &mut *self.output.lock().unwrap() {
+            let _ = f.file.flush();
+        }
+    }
+}
+
+/// `2024-03-04T00:00:00Z INFO  kindle_events_screen: Wifi Connected`
+fn line(record: &Record) -> String {
+    format!(
+        "{} {:<5} {}: {}\n",
+        iso8601(unix_now()),
+        record.level(),
+        record.target(),
+        record.args()
+    )
+}
+
+fn level_filter(level: LogLevel) -> LevelFilter {
+    match level {
+        LogLevel::Off => LevelFilter::Off,
+        LogLevel::Error => LevelFilter::Error,
+        LogLevel::Warn => LevelFilter::Warn,
+        LogLevel::Info => LevelFilter::Info,
+        LogLevel::Debug => LevelFilter::Debug,
+        LogLevel::Trace => LevelFilter::Trace,
+    }
+}
+
+/// Installs the logger; `background` is whether the process detached from
+/// its terminal
+pub fn init(config: &LogConfig, background: bool) -> Result<(), String> {
+    LOGGER.background.store(background, Ordering::SeqCst);
+    log::set_logger(&LOGGER).map_err(|e| e.to_string())?;
+    reload(config)
+}
+
+/// Applies a new level and destination; on error, the previous ones are kept
+pub fn reload(config: &LogConfig) -> Result<(), String> {
+    let target = config
+        .target
+        .unwrap_or(if LOGGER.background.load(Ordering::SeqCst) {
+            LogTarget::Syslog
+        } else {
+            LogTarget::Stderr
+        });
+    let output = match target {
+        LogTarget::Stderr => Output::Stderr,
+        LogTarget::Syslog => {
+            unsafe {
+                libc::openlog(
+                    IDENT.as_ptr() as *const libc::c_char,
+                    libc::LOG_PID,
+                    libc::LOG_DAEMON,
+                )
+            };
+            Output::Syslog
+        }
+        LogTarget::File => Output::File(RotatingFile::open(&config.path, config.max_bytes)?),
+    };
+    *LOGGER.output.lock().unwrap() = output;
+    log::set_max_level(level_filter(config.level));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotation() {
+        let path = std::env::temp_dir().join(format!("kindle-events-{}.log", std::process::id()));
+        let path = path.to_str().unwrap();
+        let rotated = format!("{}.1", path);
+
+        let mut f = RotatingFile::open(path, 12).unwrap();
+        f.write("12345\n").unwrap();
+        f.write("6789\n").unwrap();
+        assert_eq!(fs::read_to_string(path).unwrap(), "12345\n6789\n");
+        f.write("abc\n").unwrap();
+        assert_eq!(fs::read_to_string(&rotated).unwrap(), "12345\n6789\n");
+        assert_eq!(fs::read_to_string(path).unwrap(), "abc\n");
+
+        // Picks up where it left off
+        let mut f = RotatingFile::open(path, 12).unwrap();
+        f.write("defghijkl\n").unwrap();
+        assert_eq!(fs::read_to_string(&rotated).unwrap(), "abc\n");
+
+        fs::remove_file(path).unwrap();
+        fs::remove_file(rotated).unwrap();
+    }
+}
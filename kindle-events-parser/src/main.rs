@@ -1,156 +1,116 @@
-use libopenlipc_sys::{rLIPC, LipcResult};
-use mqtt_simple::publish_once;
-use std::io::{self, Write};
+use kindle_events_screen::cli::{self, Args, Command};
+use kindle_events_screen::config::Config;
+use kindle_events_screen::{daemon, logging};
+use log::{error, info};
+use std::process::exit;
 
-#[derive(Clone, Debug)]
-enum Events {
-    WifiDisconnected,
-    WifiConnected,
-    ScreenOff,
-    ScreenOn,
-    BatteryChanged,
-    Unknown(String),
+#[cfg(feature = "native")]
+fn with_bus(args: &Args, config: Config) -> Result<(), String> {
+    on_bus(libopenlipc_sys::rLIPC::new()?, args, config)
 }
 
-#[derive(Debug)]
-struct EventFilter<'a> {
-    source: &'a str,
-    events: Vec<&'a str>,
+#[cfg(all(feature = "dbus", not(feature = "native")))]
+fn with_bus(args: &Args, config: Config) -> Result<(), String> {
+    on_bus(libopenlipc_sys::DbusLipc::new()?, args, config)
 }
 
-const KINDLE_TOPIC: &str = "KINDLE/BOOK";
+#[cfg(any(feature = "native", feature = "dbus"))]
+fn on_bus<B: libopenlipc_sys::LipcBackend>(
+    r: B,
+    args: &Args,
+    config: Config,
+) -> Result<(), String> {
+    use kindle_events_screen::recording::DEFAULT_SERVICES;
+    use libopenlipc_sys::LipcEvent;
 
-impl Events {
-    fn from_str(s: &str) -> Events {
-        match s {
-            "cmConnected" => Events::WifiConnected,
-            "goingToScreenSaver" => Events::ScreenOff,
-            "outOfScreenSaver" => Events::ScreenOn,
-            "battLevelChanged" => Events::BatteryChanged,
-            "suspending" => Events::WifiDisconnected,
-            "readyToSuspend" => Events::WifiDisconnected,
-            _ => Events::Unknown(s.to_string()),
+    match &args.command {
+        Command::Run { .. } => {
+            let _pid_file = match config.daemon.pid_file.as_deref() {
+                Some(path) => Some(daemon::PidFile::create(path)?),
+                None => None,
+            };
+            daemon::install_signal_handlers()?;
+            // Returning drops the PID file, which exiting here would not
+            kindle_events_screen::run(&r, config, || args.load_config())?;
         }
-    }
-    fn to_topic(&self) -> Option<&'static str> {
-        match self {
-            Events::WifiDisconnected => Some("KINDLE/CONNECTED"),
-            Events::WifiConnected => Some("KINDLE/CONNECTED"),
-            Events::ScreenOff => Some("KINDLE/SCREEN_STATE"),
-            Events::ScreenOn => Some("KINDLE/SCREEN_STATE"),
-            Events::BatteryChanged => Some("KINDLE/BATTERY_STATE"),
-            Events::Unknown(_) => None,
-        }
-    }
-}
-
-fn run_and_match(source: &str, in_event: &str, res: Option<LipcResult>) {
-    println!("[{}] {} || {:?}", source, in_event, res);
-
-    let ev = Events::from_str(in_event);
-    let topic = ev.to_topic();
-
-    let msg = match (ev, res) {
-        (Events::BatteryChanged, Some(LipcResult::NUM(batt))) => {
-            println!("Battery at {}%", batt);
-            Some(batt.to_string())
-        }
-        (Events::WifiDisconnected, _) => {
-            println!("Wifi Disconnected");
-            Some(String::from("0"))
-        }
-        (Events::WifiConnected, _) => {
-            println!("Wifi Connected");
-            Some(String::from("1"))
-        }
-        (Events::ScreenOn, _) => {
-            println!("Screen on");
-            Some(String::from("1"))
-        }
-        (Events::ScreenOff, _) => {
-            println!("Screen off");
-            Some(String::from("0"))
-        }
-        _ => {
-            println!("No idea what i got..");
-            None
+        Command::Once => kindle_events_screen::once(&r, &config)?,
+        Command::Dump => {
+            daemon::install_signal_handlers()?;
+            for service in DEFAULT_SERVICES {
+                r.subscribe_events(
+                    service,
+                    None,
+                    Box::new(|ev: &LipcEvent| {
+                        println!("[{}] {} || {:?}", ev.source, ev.name, ev.params)
+                    }),
+                )?;
+            }
+            while !daemon::should_stop() {
+                std::thread::sleep(std::time::Duration::from_secs(1));
+            }
         }
-    };
-
-    if let Some(m) = msg {
-        let topic = topic.unwrap();
-        match send(topic, m.as_str()) {
-            Err(e) => println!("Failed to publish! {:?}", e),
-            Ok(()) => (),
+        Command::Get { service, property } => {
+            println!("{}", cli::get(&r, service, property)?);
         }
+        Command::Set {
+            service,
+            property,
+            value,
+        } => cli::set(&r, service, property, value)?,
+        Command::CheckConfig | Command::Help => unreachable!(),
     }
+    // Closing the connection drops every subscription
+    drop(r);
+    Ok(())
 }
 
-fn send(topic: &str, value: &str) -> Result<(), Box<dyn std::error::Error>> {
-    println!("Publishing {} to {}", value, topic);
-    publish_once(
-        String::from("KINDLE"),
-        String::from("192.168.20.125"),
-        topic,
-        value,
-        false,
-    )
+#[cfg(not(any(feature = "native", feature = "dbus")))]
+fn with_bus(_args: &Args, _config: Config) -> Result<(), String> {
+    Err(String::from(
+        "Built without the `native` or `dbus` feature, there is no LIPC bus",
+    ))
 }
 
 fn main() {
-    println!("Started!");
-
-    let r = rLIPC::new().unwrap();
-
-    for filter in vec![
-        EventFilter {
-            source: "com.lab126.powerd",
-            //events: vec!["goingToScreenSaver", "battLevelChanged"],
-            events: vec![],
-        },
-        EventFilter {
-            source: "com.lab126.appmgrd",
-            events: vec![],
-        },
-        EventFilter {
-            source: "com.lab126.wifid",
-            events: vec!["cmConnected"],
-        },
-        EventFilter {
-            source: "com.lab126.acxreaderplugin",
-            events: vec!["allReaderData"],
-        },
-    ] {
-        if filter.events.is_empty() {
-            r.subscribe(filter.source, None, |source, ev, res| {
-                run_and_match(source, ev, res)
-            })
-            .unwrap();
+    let args = cli::parse(std::env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{}\n\n{}", e, cli::USAGE);
+        exit(1);
+    });
+    if args.command == Command::Help {
+        println!("{}", cli::USAGE);
+        return;
+    }
+    let config = args.load_config().unwrap_or_else(|e| {
+        eprintln!("Invalid configuration: {}", e);
+        exit(1);
+    });
+    if args.command == Command::CheckConfig {
+        println!("{:#?}", config);
+        if std::path::Path::new(&args.config_path).exists() {
+            println!("{}: OK", args.config_path);
         } else {
-            for e in filter.events {
-                r.subscribe(filter.source, Some(e), |source, ev, res| {
-                    run_and_match(source, ev, res)
-                })
-                .unwrap();
-            }
+            println!("{}: not found, using the defaults", args.config_path);
         }
+        return;
     }
 
-    let mut counter = 0;
-    loop {
-        std::thread::sleep(std::time::Duration::from_secs(5));
-        print!(".");
-        if counter == 60 {
-            // once every 5 minutes
-            if let Ok(data) = r.get_str_prop("com.lab126.acxreaderplugin", "allReaderData") {
-                match send(KINDLE_TOPIC, data.as_str()) {
-                    Err(e) => println!("Failed to publish! {:?}", e),
-                    Ok(()) => (),
-                }
-            }
-            counter = 0;
+    let background = args.command == (Command::Run { daemon: true });
+    // Before anything starts a thread, which would not survive the fork
+    if background {
+        if let Err(e) = daemon::daemonize() {
+            eprintln!("{}", e);
+            exit(1);
         }
-        counter += 1;
-        io::stdout().flush().unwrap();
+    }
+    // From here on, stderr may be /dev/null
+    if let Err(e) = logging::init(&config.log, background) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+    info!("Started");
+
+    if let Err(e) = with_bus(&args, config) {
+        error!("{}", e);
+        exit(1);
     }
 }
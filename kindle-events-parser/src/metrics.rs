@@ -0,0 +1,296 @@
+//! Numeric values for time series databases: InfluxDB line protocol, pushed
+//! over UDP or HTTP, and a Prometheus `/metrics` endpoint
+
+use crate::clock::unix_now;
+use crate::sinks::{http_post, parse_http_url, Sink, HTTP_TIMEOUT};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// A numeric value published by a device
+#[derive(Debug, PartialEq)]
+struct Sample {
+    device: String,
+    /// The topic relative to the device prefix, as a metric name:
+    /// `battery/rate` becomes `battery_rate`
+    name: String,
+    value: f64,
+}
+
+/// The sample in a message, if its payload is a number, either raw or as the
+/// `value` of a JSON payload
+fn sample(topic: &str, payload: &str) -> Option<Sample> {
+    let value = match serde_json::from_str(payload).ok()? {
+        Value::Number(n) => n.as_f64()?,
+        Value::Object(o) => o.get("value")?.as_f64()?,
+        _ => return None,
+    };
+    // kindle/<device>/<name...>
+    let mut levels = topic.splitn(3, '/');
+    let (_, device, name) = (levels.next()?, levels.next()?, levels.next()?);
+    let name = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    Some(Sample {
+        device: device.to_string(),
+        name,
+        value,
+    })
+}
+
+enum Transport {
+    Udp(SocketAddr),
+    /// `host:port` and path of the write endpoint
+    Http(String, String),
+}
+
+/// Writes every numeric value as a line of InfluxDB line protocol, to
+/// `udp://host:port` or to an `http://` write endpoint
+pub struct InfluxSink {
+    transport: Transport,
+}
+
+impl InfluxSink {
+    pub fn new(url: &str) -> Result<InfluxSink, String> {
+        let transport = match url.strip_prefix("udp://") {
+            Some(addr) => Transport::Udp(
+                addr.parse()
+                    .map_err(|e| format!("Invalid address {}: {}", addr, e))?,
+            ),
+            None => {
+                let (host, path) = parse_http_url(url)?;
+                Transport::Http(host, path)
+            }
+        };
+        Ok(InfluxSink { transport })
+    }
+}
+
+/// `battery,device=g000ab12 value=67 1709510400000000000`
+fn line(sample: &Sample, ts: u64) -> String {
+    format!(
+        "{},device={} value={} {}000000000",
+        sample.name, sample.device, sample.value, ts
+    )
+}
+
+impl Sink for InfluxSink {
+    fn send(&self, topic: &str, payload: &str, _retain: bool) -> Result<(), Box<dyn Error>> {
+        let sample = match sample(topic, payload) {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+        let line = line(&sample, unix_now());
+        match &self.transport {
+            Transport::Udp(addr) => {
+                UdpSocket::bind("0.0.0.0:0")?.send_to(line.as_bytes(), addr)?;
+                Ok(())
+            }
+            Transport::Http(host, path) => http_post(host, path, "text/plain", &line),
+        }
+    }
+
+    fn remote(&self) -> bool {
+        true
+    }
+}
+
+type Gauges = Arc<Mutex<BTreeMap<(String, String), f64>>>;
+
+/// Keeps the last numeric value of every topic, served in the Prometheus text
+/// format on `/metrics`
+pub struct PrometheusSink {
+    gauges: Gauges,
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+}
+
+impl PrometheusSink {
+    /// Starts serving on `listen`, e.g. `0.0.0.0:9100`
+    pub fn new(listen: &str) -> Result<PrometheusSink, String> {
+        let listener = TcpListener::bind(listen)
+            .map_err(|e| format!("Failed to listen on {}: {}", listen, e))?;
+        let addr = listener.local_addr().map_err(|e| e.to_string())?;
+        let gauges = Gauges::default();
+        let stop = Arc::new(AtomicBool::new(false));
+        let (g, s) = (gauges.clone(), stop.clone());
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                if s.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Err(e) = serve(stream, &g) {
+                    log::warn!("Failed to serve metrics: {}", e);
+                }
+            }
+        });
+        Ok(PrometheusSink { gauges, addr, stop })
+    }
+
+    /// Where metrics are served
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+fn exposition(gauges: &BTreeMap<(String, String), f64>) -> String {
+    let mut out = String::new();
+    let mut last = None;
+    for ((name, device), value) in gauges {
+        if last != Some(name) {
+            out.push_str(&format!("# TYPE kindle_{} gauge\n", name));
+            last = Some(name);
+        }
+        out.push_str(&format!(
+            "kindle_{}{{device=\"{}\"}} {}\n",
+            name, device, value
+        ));
+    }
+    out
+}
+
+fn serve(stream: TcpStream, gauges: &Gauges) -> Result<(), Box<dyn Error>> {
+    // Connections are served one at a time, a silent one must not hold up
+    // the next scrape
+    stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+    stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+    let mut reader = BufReader::new(stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // The headers are not needed, but must be read before answering
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line == "\r\n" || line == "\n" {
+            break;
+        }
+    }
+
+    let (status, body) = match request.split_whitespace().nth(1) {
+        Some("/metrics") => ("200 OK", exposition(&gauges.lock().unwrap())),
+        _ => ("404 Not Found", String::new()),
+    };
+    write!(
+        reader.get_mut(),
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    Ok(())
+}
+
+impl Drop for PrometheusSink {
+    /// Stops listening, so the address can be used again
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // Wake up the thread blocked in accept()
+        let _ = TcpStream::connect(self.addr);
+    }
+}
+
+impl Sink for PrometheusSink {
+    fn send(&self, topic: &str, payload: &str, _retain: bool) -> Result<(), Box<dyn Error>> {
+        if let Some(s) = sample(topic, payload) {
+            self.gauges
+                .lock()
+                .unwrap()
+                .insert((s.name, s.device), s.value);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sinks::tests::serve_once;
+    use std::io::Read;
+
+    #[test]
+    fn test_sample() {
+        assert_eq!(
+            sample("kindle/g000ab12/battery/rate", "-4.5"),
+            Some(Sample {
+                device: String::from("g000ab12"),
+                name: String::from("battery_rate"),
+                value: -4.5,
+            })
+        );
+        assert_eq!(
+            sample(
+                "kindle/x/battery",
+                r#"{"event":"battLevelChanged","value":67}"#
+            )
+            .map(|s| s.value),
+            Some(67.0)
+        );
+        assert_eq!(sample("kindle/x/book/title", "Dune"), None);
+        assert_eq!(sample("kindle/x/book", r#"{"title":"Dune"}"#), None);
+        assert_eq!(
+            line(&sample("kindle/x/battery", "67").unwrap(), 1),
+            "battery,device=x value=67 1000000000"
+        );
+    }
+
+    #[test]
+    fn test_influx_udp() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let url = format!("udp://{}", socket.local_addr().unwrap());
+        let sink = InfluxSink::new(&url).unwrap();
+        sink.send("kindle/x/book/title", "Dune", false).unwrap();
+        sink.send("kindle/x/battery", "67", false).unwrap();
+
+        let mut buf = [0; 256];
+        let n = socket.recv(&mut buf).unwrap();
+        let received = String::from_utf8_lossy(&buf[..n]);
+        assert!(received.starts_with("battery,device=x value=67 "));
+    }
+
+    #[test]
+    fn test_influx_http() {
+        let (url, server) = serve_once("204 No Content");
+        let sink = InfluxSink::new(&format!("{}?db=kindle", url)).unwrap();
+        sink.send("kindle/x/battery/temperature", "80", false)
+            .unwrap();
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /hook?db=kindle HTTP/1.1\r\n"));
+        assert!(request.contains("\r\n\r\nbattery_temperature,device=x value=80 "));
+    }
+
+    #[test]
+    fn test_prometheus() {
+        let sink = PrometheusSink::new("127.0.0.1:0").unwrap();
+        sink.send("kindle/x/battery", "67", false).unwrap();
+        sink.send("kindle/x/battery", "66", false).unwrap();
+        sink.send("kindle/x/book/position", "1500", false).unwrap();
+        sink.send("kindle/x/book/title", "Dune", false).unwrap();
+
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(sink.local_addr()).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: kindle\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        // Connected but silent, given up on after a while
+        let _idle = TcpStream::connect(sink.local_addr()).unwrap();
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(
+            "\r\n\r\n# TYPE kindle_battery gauge\nkindle_battery{device=\"x\"} 66\n\
+             # TYPE kindle_book_position gauge\nkindle_book_position{device=\"x\"} 1500\n"
+        ));
+        assert!(get("/").starts_with("HTTP/1.1 404"));
+
+        let addr = sink.local_addr();
+        drop(sink);
+        // Give the listener thread a moment to go away
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        PrometheusSink::new(&addr.to_string()).unwrap();
+    }
+}
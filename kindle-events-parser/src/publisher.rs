@@ -0,0 +1,818 @@
+//! Publishing under the topic prefix of this device, to every sink whose
+//! routes match
+
+use crate::clock::{iso8601, unix_now};
+use crate::config::{
+    Config, LimitConfig, PayloadConfig, PayloadFormat, PropType, SinkConfig, WakeConfig,
+};
+use crate::limits::Limiter;
+use crate::metrics::{InfluxSink, PrometheusSink};
+use crate::sinks::{topic_matches, FileSink, HttpSink, MqttSink, Sink, StdoutSink};
+use serde_json::{json, Map, Value};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+
+/// Topic with the last value of every other topic, as a JSON object
+pub const STATE_TOPIC: &str = "state";
+
+/// What a value is known to be, as all of them travel as strings
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Text,
+    Number,
+    /// A JSON document
+    Json,
+}
+
+impl From<PropType> for Kind {
+    fn from(prop: PropType) -> Self {
+        match prop {
+            PropType::Int => Kind::Number,
+            PropType::Str => Kind::Text,
+        }
+    }
+}
+
+/// A value to publish, along with the event it came from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Message<'a> {
+    /// Relative to the prefix
+    pub topic: &'a str,
+    pub source: &'a str,
+    pub event: &'a str,
+    pub value: &'a str,
+    pub kind: Kind,
+}
+
+/// `value` as a number or a JSON document if it is known to be one, so a
+/// book titled `1984` stays a string
+pub(crate) fn typed(value: &str, kind: Kind) -> Value {
+    let parsed = match kind {
+        Kind::Text => None,
+        Kind::Number => serde_json::from_str(value).ok().filter(Value::is_number),
+        Kind::Json => serde_json::from_str(value).ok(),
+    };
+    parsed.unwrap_or_else(|| Value::String(value.to_string()))
+}
+
+impl Message<'_> {
+    pub fn payload(&self, format: PayloadFormat, ts: u64) -> String {
+        match format {
+            PayloadFormat::Raw => self.value.to_string(),
+            PayloadFormat::Json => json!({
+                "event": self.event,
+                "source": self.source,
+                "value": typed(self.value, self.kind),
+                "ts": iso8601(ts),
+            })
+            .to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    values: Map<String, Value>,
+    /// Changed since it was last published
+    dirty: bool,
+}
+
+struct Route {
+    /// What the sink was created from, if it came from the configuration
+    config: Option<SinkConfig>,
+    sink: Box<dyn Sink>,
+    /// Patterns of the topics the sink gets, everything if empty
+    topics: Vec<String>,
+}
+
+impl Route {
+    fn wants(&self, topic: &str) -> bool {
+        self.topics.is_empty() || self.topics.iter().any(|p| topic_matches(p, topic))
+    }
+}
+
+/// What can change when the configuration is reloaded
+struct Outputs {
+    payload: PayloadConfig,
+    routes: Vec<Route>,
+    max_pending: usize,
+}
+
+/// A message for the remote sinks, kept until the network is back
+#[derive(Clone, PartialEq)]
+struct Pending {
+    name: String,
+    topic: String,
+    value: String,
+    retain: bool,
+    retaining_only: bool,
+}
+
+#[derive(Default)]
+struct Link {
+    offline: bool,
+    pending: VecDeque<Pending>,
+    /// While `send_pending` goes through `pending`
+    draining: bool,
+}
+
+#[derive(Clone)]
+pub struct Publisher {
+    /// Prepended to every topic, `kindle/<device id>`
+    pub prefix: String,
+    client_id: String,
+    outputs: Arc<RwLock<Outputs>>,
+    state: Arc<Mutex<State>>,
+    link: Arc<Mutex<Link>>,
+    limiter: Arc<Mutex<Limiter>>,
+}
+
+fn create_sink(
+    sink: &SinkConfig,
+    config: &Config,
+    client_id: &str,
+) -> Result<Box<dyn Sink>, String> {
+    Ok(match sink {
+        SinkConfig::Mqtt { .. } => Box::new(MqttSink::new(&config.mqtt.broker, client_id)),
+        SinkConfig::Http { url, .. } => Box::new(HttpSink::new(url)?),
+        SinkConfig::File { path, .. } => Box::new(FileSink::new(path)?),
+        SinkConfig::Stdout { .. } => Box::new(StdoutSink),
+        SinkConfig::Influx { url, .. } => Box::new(InfluxSink::new(url)?),
+        SinkConfig::Prometheus { listen, .. } => Box::new(PrometheusSink::new(listen)?),
+    })
+}
+
+/// The routes for `config`, taking over the sinks in `old` that did not
+/// change. On errors, `old` is left untouched.
+fn build_routes(
+    config: &Config,
+    client_id: &str,
+    old: &mut Vec<Route>,
+) -> Result<Vec<Route>, String> {
+    // Either a new sink or the index of one to take over
+    let mut plan = vec![];
+    let mut claimed = vec![false; old.len()];
+    for sink in &config.sink {
+        let wanted = sink.without_topics();
+        // MQTT sinks are cheap, and the broker is not part of their config
+        let reuse = match sink {
+            SinkConfig::Mqtt { .. } => None,
+            _ => (0..old.len()).find(|&i| {
+                !claimed[i]
+                    && old[i]
+                        .config
+                        .as_ref()
+                        .is_some_and(|c| c.without_topics() == wanted)
+            }),
+        };
+        match reuse {
+            Some(i) => {
+                claimed[i] = true;
+                plan.push(Err(i));
+            }
+            None => plan.push(Ok(create_sink(sink, config, client_id)?)),
+        }
+    }
+
+    let mut old: Vec<Option<Route>> = old.drain(..).map(Some).collect();
+    Ok(plan
+        .into_iter()
+        .zip(&config.sink)
+        .map(|(p, config)| Route {
+            config: Some(config.clone()),
+            sink: match p {
+                Ok(sink) => sink,
+                Err(i) => old[i].take().unwrap().sink,
+            },
+            topics: config.topics().to_vec(),
+        })
+        .collect())
+}
+
+impl Publisher {
+    /// Publishes to the sinks in `config`
+    pub fn new(config: &Config, device_id: &str) -> Result<Self, String> {
+        let mut publisher = Self::with_sinks(config.mqtt.payload.clone(), device_id, vec![]);
+        let routes = build_routes(config, &publisher.client_id, &mut vec![])?;
+        publisher.outputs = Arc::new(RwLock::new(Outputs {
+            payload: config.mqtt.payload.clone(),
+            routes,
+            max_pending: config.wake.max_pending,
+        }));
+        publisher.set_limits(config.limit.clone());
+        Ok(publisher)
+    }
+
+    /// Switches every clone of this publisher to the sinks and payload
+    /// formats of `config`; sinks that did not change are kept as they are.
+    /// The device id, and so the prefix, stays the same.
+    pub fn reload(&self, config: &Config) -> Result<(), String> {
+        let mut outputs = self.outputs.write().unwrap();
+        let routes = build_routes(config, &self.client_id, &mut outputs.routes)?;
+        outputs.routes = routes;
+        outputs.payload = config.mqtt.payload.clone();
+        outputs.max_pending = config.wake.max_pending;
+        self.set_limits(config.limit.clone());
+        Ok(())
+    }
+
+    /// Holds back messages on the topics of `rules`, for every clone of
+    /// this publisher
+    pub fn set_limits(&self, rules: Vec<LimitConfig>) {
+        self.limiter.lock().unwrap().set_rules(rules);
+    }
+
+    /// Publishes to `sinks`, each only getting the topics matching its
+    /// patterns
+    pub fn with_sinks(
+        payload: PayloadConfig,
+        device_id: &str,
+        sinks: Vec<(Box<dyn Sink>, Vec<String>)>,
+    ) -> Self {
+        Publisher {
+            prefix: format!("kindle/{}", device_id),
+            client_id: format!("kindle-{}", device_id),
+            outputs: Arc::new(RwLock::new(Outputs {
+                payload,
+                routes: sinks
+                    .into_iter()
+                    .map(|(sink, topics)| Route {
+                        config: None,
+                        sink,
+                        topics,
+                    })
+                    .collect(),
+                max_pending: WakeConfig::default().max_pending,
+            })),
+            state: Default::default(),
+            link: Default::default(),
+            limiter: Default::default(),
+        }
+    }
+
+    /// The full topic for `name`, e.g. `battery` becomes `kindle/<id>/battery`
+    pub fn topic(&self, name: &str) -> String {
+        format!("{}/{}", self.prefix, name)
+    }
+
+    /// Publishes `msg` in the format configured for its topic, and keeps its
+    /// value for the state topic
+    pub fn send_message(&self, msg: &Message) -> Result<(), Box<dyn std::error::Error>> {
+        {
+            let mut state = self.state.lock().unwrap();
+            state
+                .values
+                .insert(msg.topic.to_string(), typed(msg.value, msg.kind));
+            state.dirty = true;
+        }
+        let format = self.outputs.read().unwrap().payload.format_for(msg.topic);
+        let payload = msg.payload(format, unix_now());
+        self.limited(msg.topic, msg.value, &payload, false)
+    }
+
+    /// Publishes the state topic, retained, if anything changed since the
+    /// last time
+    pub fn flush_state(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let snapshot = {
+            let mut state = self.state.lock().unwrap();
+            if !state.dirty {
+                return Ok(());
+            }
+            state.dirty = false;
+            let mut snapshot = state.values.clone();
+            snapshot.insert(String::from("ts"), Value::String(iso8601(unix_now())));
+            Value::Object(snapshot).to_string()
+        };
+        let res = self.send_retained(STATE_TOPIC, &snapshot);
+        if res.is_err() {
+            self.state.lock().unwrap().dirty = true;
+        }
+        res
+    }
+
+    pub fn send(&self, name: &str, value: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.limited(name, value, value, false)
+    }
+
+    /// Same as `send`, but the broker keeps the message for new subscribers
+    pub fn send_retained(&self, name: &str, value: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.limited(name, value, value, true)
+    }
+
+    /// Publishes `payload` now, unless a limit holds it back; `key` is the
+    /// value it holds, to tell duplicates
+    fn limited(
+        &self,
+        name: &str,
+        key: &str,
+        payload: &str,
+        retain: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let release =
+            self.limiter
+                .lock()
+                .unwrap()
+                .offer(name, key, payload, retain, Instant::now());
+        match release {
+            Some(r) => self.route(&r.name, &self.topic(&r.name), &r.payload, r.retain, false),
+            None => Ok(()),
+        }
+    }
+
+    /// Publishes the messages held back by the limits whose time came, or
+    /// all of them, before suspending or stopping
+    pub fn flush_held(&self, all: bool) -> Result<(), Box<dyn std::error::Error>> {
+        let released = {
+            let mut limiter = self.limiter.lock().unwrap();
+            if all {
+                limiter.drain(Instant::now())
+            } else {
+                limiter.due(Instant::now())
+            }
+        };
+        let mut errors = vec![];
+        for r in released {
+            if let Err(e) = self.route(&r.name, &self.topic(&r.name), &r.payload, r.retain, false) {
+                errors.push(e.to_string());
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join(", ").into())
+        }
+    }
+
+    /// Publishes to `topic` as is, without the prefix, and only to the sinks
+    /// that keep retained messages
+    pub fn publish_retained(
+        &self,
+        topic: &str,
+        value: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.route(topic, topic, value, true, true)
+    }
+
+    /// Tells whether the network is up. While it is not, what the remote
+    /// sinks should get is kept, and delivered once it is back, oldest first.
+    pub fn set_online(&self, online: bool) -> Result<(), Box<dyn std::error::Error>> {
+        {
+            let mut link = self.link.lock().unwrap();
+            link.offline = !online;
+            if !online {
+                return Ok(());
+            }
+        }
+        self.send_pending()
+    }
+
+    /// Delivers what was kept while offline, up to the first message that
+    /// fails. That one and the rest are kept for the next try, and until
+    /// then newer messages queue up behind them.
+    pub fn send_pending(&self) -> Result<(), Box<dyn std::error::Error>> {
+        {
+            let mut link = self.link.lock().unwrap();
+            // Whoever is already draining also sends what queues up meanwhile
+            if link.offline || link.draining {
+                return Ok(());
+            }
+            if !link.pending.is_empty() {
+                log::info!("Online, sending {} pending messages", link.pending.len());
+            }
+            link.draining = true;
+        }
+        let res = self.drain_pending();
+        self.link.lock().unwrap().draining = false;
+        res
+    }
+
+    /// Sends the pending messages in place, each one leaving the queue only
+    /// once delivered, so newer ones keep queuing up behind it
+    fn drain_pending(&self) -> Result<(), Box<dyn std::error::Error>> {
+        loop {
+            let p = {
+                let link = self.link.lock().unwrap();
+                match link.pending.front() {
+                    Some(p) if !link.offline => p.clone(),
+                    _ => return Ok(()),
+                }
+            };
+            self.deliver(
+                &p.name,
+                &p.topic,
+                &p.value,
+                p.retain,
+                p.retaining_only,
+                Some(true),
+            )?;
+            let mut link = self.link.lock().unwrap();
+            // Unless it was dropped meanwhile to make room
+            if link.pending.front() == Some(&p) {
+                link.pending.pop_front();
+            }
+        }
+    }
+
+    pub fn is_online(&self) -> bool {
+        !self.link.lock().unwrap().offline
+    }
+
+    /// Delivers `value` to every sink wanting `name`, or only to the local
+    /// ones while offline
+    fn route(
+        &self,
+        name: &str,
+        topic: &str,
+        value: &str,
+        retain: bool,
+        retaining_only: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let queued = {
+            let mut link = self.link.lock().unwrap();
+            // Behind what is still pending, so retained values don't go back
+            // in time
+            let queue = link.offline || !link.pending.is_empty();
+            if queue {
+                let max_pending = self.outputs.read().unwrap().max_pending;
+                if link.pending.len() >= max_pending && link.pending.pop_front().is_some() {
+                    log::warn!("Offline for too long, dropping the oldest pending message");
+                }
+                if max_pending > 0 {
+                    link.pending.push_back(Pending {
+                        name: name.to_string(),
+                        topic: topic.to_string(),
+                        value: value.to_string(),
+                        retain,
+                        retaining_only,
+                    });
+                }
+            }
+            queue
+        };
+        let remote = if queued { Some(false) } else { None };
+        self.deliver(name, topic, value, retain, retaining_only, remote)
+    }
+
+    /// Delivers `value` to every sink wanting `name`, or only to the remote
+    /// or local ones if `remote` is set; all of them are tried, even if one
+    /// fails
+    fn deliver(
+        &self,
+        name: &str,
+        topic: &str,
+        value: &str,
+        retain: bool,
+        retaining_only: bool,
+        remote: Option<bool>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        log::debug!("Publishing {} to {}", value, topic);
+        let mut errors = vec![];
+        for route in self.outputs.read().unwrap().routes.iter() {
+            if !route.wants(name)
+                || (retaining_only && !route.sink.retains())
+                || remote.is_some_and(|r| r != route.sink.remote())
+            {
+                continue;
+            }
+            if let Err(e) = route.sink.send(topic, value, retain) {
+                errors.push(e.to_string());
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join(", ").into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc;
+
+    type Sent = Arc<Mutex<Vec<(String, String, bool)>>>;
+
+    /// Keeps what it gets in memory
+    struct MemorySink(Sent, bool, bool);
+
+    impl Sink for MemorySink {
+        fn send(
+            &self,
+            topic: &str,
+            payload: &str,
+            retain: bool,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            self.0
+                .lock()
+                .unwrap()
+                .push((topic.to_string(), payload.to_string(), retain));
+            Ok(())
+        }
+
+        fn retains(&self) -> bool {
+            self.1
+        }
+
+        fn remote(&self) -> bool {
+            self.2
+        }
+    }
+
+    fn memory_sink(retains: bool) -> (Box<dyn Sink>, Sent) {
+        let sent = Sent::default();
+        (Box::new(MemorySink(sent.clone(), retains, false)), sent)
+    }
+
+    #[test]
+    fn test_topics() {
+        let p = Publisher::new(&Config::default(), "g000ab12").unwrap();
+        assert_eq!(p.topic("battery"), "kindle/g000ab12/battery");
+        assert_eq!(p.topic("book/title"), "kindle/g000ab12/book/title");
+    }
+
+    #[test]
+    fn test_reload() {
+        let path = std::env::temp_dir().join(format!("kindle-reload-{}.jsonl", std::process::id()));
+        let path = path.to_str().unwrap();
+        let lines = || std::fs::read_to_string(path).unwrap().lines().count();
+        let file = |topics: Vec<String>| Config {
+            sink: vec![SinkConfig::File {
+                path: path.to_string(),
+                topics,
+            }],
+            ..Default::default()
+        };
+
+        let p = Publisher::new(&file(vec![]), "x").unwrap();
+        p.send("battery", "1").unwrap();
+        assert_eq!(lines(), 1);
+
+        let broken = Config {
+            sink: vec![SinkConfig::Http {
+                url: String::from("https://example.com"),
+                topics: vec![],
+            }],
+            ..Default::default()
+        };
+        assert!(p.reload(&broken).is_err());
+        p.send("battery", "2").unwrap();
+        assert_eq!(lines(), 2);
+
+        p.reload(&file(vec![String::from("screen")])).unwrap();
+        p.send("battery", "3").unwrap();
+        p.clone().send("screen", "1").unwrap();
+        assert_eq!(lines(), 3);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_routing() {
+        let (broker, to_broker) = memory_sink(true);
+        let (webhook, to_webhook) = memory_sink(false);
+        let p = Publisher::with_sinks(
+            PayloadConfig::default(),
+            "x",
+            vec![(broker, vec![]), (webhook, vec![String::from("battery/#")])],
+        );
+        p.send("battery", "67").unwrap();
+        p.send("screen", "1").unwrap();
+        p.send_retained("battery/alert", "{}").unwrap();
+        p.publish_retained("homeassistant/sensor/x/config", "{}")
+            .unwrap();
+
+        let topics = |sent: &Sent| -> Vec<String> {
+            sent.lock()
+                .unwrap()
+                .iter()
+                .map(|(t, _, _)| t.clone())
+                .collect()
+        };
+        assert_eq!(
+            topics(&to_broker),
+            vec![
+                "kindle/x/battery",
+                "kindle/x/screen",
+                "kindle/x/battery/alert",
+                "homeassistant/sensor/x/config",
+            ]
+        );
+        assert_eq!(
+            topics(&to_webhook),
+            vec!["kindle/x/battery", "kindle/x/battery/alert"]
+        );
+        assert!(to_webhook.lock().unwrap()[1].2);
+    }
+
+    #[test]
+    fn test_offline() {
+        let broker = Sent::default();
+        let (file, to_file) = memory_sink(false);
+        let p = Publisher::with_sinks(
+            PayloadConfig::default(),
+            "x",
+            vec![
+                (Box::new(MemorySink(broker.clone(), true, true)), vec![]),
+                (file, vec![]),
+            ],
+        );
+        p.set_online(false).unwrap();
+        assert!(!p.is_online());
+        p.send("screen", "0").unwrap();
+        p.send_retained("state", "{}").unwrap();
+        assert!(broker.lock().unwrap().is_empty());
+        assert_eq!(to_file.lock().unwrap().len(), 2);
+
+        p.set_online(true).unwrap();
+        p.send("screen", "1").unwrap();
+        assert_eq!(
+            *broker.lock().unwrap(),
+            vec![
+                (String::from("kindle/x/screen"), String::from("0"), false),
+                (String::from("kindle/x/state"), String::from("{}"), true),
+                (String::from("kindle/x/screen"), String::from("1"), false),
+            ]
+        );
+        assert_eq!(to_file.lock().unwrap().len(), 3);
+    }
+
+    /// Fails its first send, as a broker not reachable yet
+    struct FlakySink(Sent, AtomicBool);
+
+    impl Sink for FlakySink {
+        fn send(
+            &self,
+            topic: &str,
+            payload: &str,
+            retain: bool,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            if self.1.swap(false, Ordering::SeqCst) {
+                return Err("Connection refused".into());
+            }
+            MemorySink(self.0.clone(), true, true).send(topic, payload, retain)
+        }
+
+        fn retains(&self) -> bool {
+            true
+        }
+
+        fn remote(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn test_pending_kept_on_failure() {
+        let broker = Sent::default();
+        let sink = FlakySink(broker.clone(), AtomicBool::new(true));
+        let p = Publisher::with_sinks(
+            PayloadConfig::default(),
+            "x",
+            vec![(Box::new(sink), vec![])],
+        );
+        p.set_online(false).unwrap();
+        p.send_retained("state", "1").unwrap();
+        p.send_retained("state", "2").unwrap();
+
+        assert!(p.set_online(true).is_err());
+        assert!(broker.lock().unwrap().is_empty());
+        // Queued behind the pending ones
+        p.send_retained("state", "3").unwrap();
+        assert!(broker.lock().unwrap().is_empty());
+
+        p.send_pending().unwrap();
+        let values: Vec<String> = broker.lock().unwrap().iter().map(|m| m.1.clone()).collect();
+        assert_eq!(values, vec!["1", "2", "3"]);
+        p.send_retained("state", "4").unwrap();
+        assert_eq!(broker.lock().unwrap().len(), 4);
+    }
+
+    /// Holds its first message until told to go on
+    struct GatedSink {
+        sent: Sent,
+        started: Mutex<Option<mpsc::Sender<()>>>,
+        go: Mutex<Option<mpsc::Receiver<()>>>,
+    }
+
+    impl Sink for GatedSink {
+        fn send(
+            &self,
+            topic: &str,
+            payload: &str,
+            retain: bool,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            if let Some(started) = self.started.lock().unwrap().take() {
+                let go = self.go.lock().unwrap().take().unwrap();
+                started.send(()).unwrap();
+                go.recv().unwrap();
+            }
+            MemorySink(self.sent.clone(), true, true).send(topic, payload, retain)
+        }
+
+        fn retains(&self) -> bool {
+            true
+        }
+
+        fn remote(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn test_send_while_draining() {
+        let broker = Sent::default();
+        let (started_tx, started) = mpsc::channel();
+        let (go, go_rx) = mpsc::channel();
+        let sink = GatedSink {
+            sent: broker.clone(),
+            started: Mutex::new(Some(started_tx)),
+            go: Mutex::new(Some(go_rx)),
+        };
+        let p = Publisher::with_sinks(
+            PayloadConfig::default(),
+            "x",
+            vec![(Box::new(sink), vec![])],
+        );
+        p.set_online(false).unwrap();
+        p.send_retained("state", "1").unwrap();
+        p.send_retained("state", "2").unwrap();
+        // Back online, without draining yet
+        p.link.lock().unwrap().offline = false;
+
+        let draining = p.clone();
+        let drain = std::thread::spawn(move || draining.send_pending().is_ok());
+        started.recv().unwrap();
+        // Sent while "1" is on its way, it goes behind "2"
+        p.send_retained("state", "3").unwrap();
+        go.send(()).unwrap();
+        assert!(drain.join().unwrap());
+
+        let values: Vec<String> = broker.lock().unwrap().iter().map(|m| m.1.clone()).collect();
+        assert_eq!(values, vec!["1", "2", "3"]);
+    }
+
+    #[test]
+    fn test_limits() {
+        let (sink, sent) = memory_sink(false);
+        let p = Publisher::with_sinks(PayloadConfig::default(), "x", vec![(sink, vec![])]);
+        p.set_limits(vec![LimitConfig {
+            topics: vec![String::from("battery")],
+            throttle_secs: 3600,
+            dedupe: true,
+            ..Default::default()
+        }]);
+        let msg = Message {
+            topic: "battery",
+            source: "com.lab126.powerd",
+            event: "battLevelChanged",
+            value: "67",
+            kind: Kind::Number,
+        };
+        p.send_message(&msg).unwrap();
+        p.send_message(&msg).unwrap();
+        p.send_message(&Message { value: "68", ..msg }).unwrap();
+        p.send("screen", "1").unwrap();
+        p.flush_held(false).unwrap();
+        assert_eq!(sent.lock().unwrap().len(), 2);
+
+        p.flush_held(true).unwrap();
+        assert_eq!(
+            sent.lock().unwrap()[2],
+            (String::from("kindle/x/battery"), String::from("68"), false)
+        );
+    }
+
+    #[test]
+    fn test_payload() {
+        let msg = Message {
+            topic: "battery",
+            source: "com.lab126.powerd",
+            event: "battLevelChanged",
+            value: "67",
+            kind: Kind::Number,
+        };
+        assert_eq!(msg.payload(PayloadFormat::Raw, 0), "67");
+        let json: Value = serde_json::from_str(&msg.payload(PayloadFormat::Json, 0)).unwrap();
+        assert_eq!(
+            json,
+            json!({
+                "event": "battLevelChanged",
+                "source": "com.lab126.powerd",
+                "value": 67,
+                "ts": "1970-01-01T00:00:00Z",
+            })
+        );
+
+        let title = Message {
+            value: "1984",
+            kind: Kind::Text,
+            ..msg
+        };
+        let json: Value = serde_json::from_str(&title.payload(PayloadFormat::Json, 0)).unwrap();
+        assert_eq!(json["value"], "1984");
+        assert_eq!(typed("{\"a\": 1}", Kind::Json), json!({"a": 1}));
+        assert_eq!(typed("n/a", Kind::Number), json!("n/a"));
+    }
+}
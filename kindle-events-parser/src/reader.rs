@@ -0,0 +1,194 @@
+//! Typed view of `com.lab126.acxreaderplugin allReaderData`, which is a JSON
+//! document describing what is being read
+
+use crate::publisher::Kind;
+use libopenlipc_sys::catalog::acxreaderplugin;
+use serde::{Deserialize, Deserializer};
+use serde_json::json;
+
+pub const READER_SERVICE: &str = acxreaderplugin::SERVICE;
+pub const READER_DATA: &str = acxreaderplugin::ALL_READER_DATA.name;
+
+const BOOK_TOPIC: &str = "book";
+const SESSION_TOPIC: &str = "book/session";
+
+/// What the values published on `topic` by `ReaderTracker::update` are
+pub fn kind(topic: &str) -> Kind {
+    match topic {
+        SESSION_TOPIC => Kind::Json,
+        "book/position" | "book/percent" => Kind::Number,
+        _ => Kind::Text,
+    }
+}
+
+/// Numbers sometimes come as strings, accept both
+fn lenient_num<'de, D, T>(d: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: std::str::FromStr + serde::de::DeserializeOwned,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum NumOrStr<T> {
+        Num(T),
+        Str(String),
+    }
+    Ok(match Option::<NumOrStr<T>>::deserialize(d)? {
+        Some(NumOrStr::Num(n)) => Some(n),
+        Some(NumOrStr::Str(s)) => s.trim().parse().ok(),
+        None => None,
+    })
+}
+
+/// The parts of `allReaderData` we know about, every other field is ignored
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct ReaderData {
+    #[serde(alias = "bookTitle")]
+    pub title: Option<String>,
+    #[serde(alias = "bookAuthor")]
+    pub author: Option<String>,
+    #[serde(alias = "ASIN", alias = "bookAsin")]
+    pub asin: Option<String>,
+    #[serde(alias = "currentPosition", deserialize_with = "lenient_num")]
+    pub position: Option<i64>,
+    #[serde(alias = "maxPosition", deserialize_with = "lenient_num")]
+    pub max_position: Option<i64>,
+    #[serde(alias = "percentRead", deserialize_with = "lenient_num")]
+    pub percent_read: Option<f64>,
+}
+
+impl ReaderData {
+    pub fn parse(json: &str) -> Result<ReaderData, String> {
+        serde_json::from_str(json).map_err(|e| format!("Invalid reader data: {}", e))
+    }
+
+    /// `percent_read`, or derived from the position if it's missing
+    pub fn percent(&self) -> Option<f64> {
+        self.percent_read
+            .or(match (self.position, self.max_position) {
+                (Some(pos), Some(max)) if max > 0 => Some(pos as f64 * 100.0 / max as f64),
+                _ => None,
+            })
+    }
+
+    /// What identifies the book, the ASIN or else the title
+    pub fn book(&self) -> Option<&str> {
+        self.asin.as_deref().or(self.title.as_deref())
+    }
+}
+
+/// Keeps the last reader data around, to notice book and position changes
+#[derive(Default)]
+pub struct ReaderTracker {
+    last: Option<ReaderData>,
+}
+
+impl ReaderTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parses `json` and returns the (topic, message) pairs to publish: every
+    /// known field on its own sub-topic and, if the book or the position
+    /// changed, a reading session event.
+    pub fn update(&mut self, json: &str) -> Result<Vec<(String, String)>, String> {
+        let data = ReaderData::parse(json)?;
+        let sub = |name: &str| format!("{}/{}", BOOK_TOPIC, name);
+
+        let mut out = vec![];
+        if let Some(title) = &data.title {
+            out.push((sub("title"), title.clone()));
+        }
+        if let Some(author) = &data.author {
+            out.push((sub("author"), author.clone()));
+        }
+        if let Some(asin) = &data.asin {
+            out.push((sub("asin"), asin.clone()));
+        }
+        if let Some(position) = data.position {
+            out.push((sub("position"), position.to_string()));
+        }
+        if let Some(percent) = data.percent() {
+            out.push((sub("percent"), format!("{:.1}", percent)));
+        }
+
+        let (last_book, last_position) = match &self.last {
+            Some(last) => (last.book().map(String::from), last.position),
+            None => (None, None),
+        };
+        let book_changed = data.book().is_some() && data.book() != last_book.as_deref();
+        let position_changed = data.position.is_some() && data.position != last_position;
+        if book_changed || position_changed {
+            let event = json!({
+                "title": data.title,
+                "asin": data.asin,
+                "book_changed": book_changed,
+                "from": if book_changed { None } else { last_position },
+                "to": data.position,
+                "percent": data.percent(),
+            });
+            out.push((String::from(SESSION_TOPIC), event.to_string()));
+        }
+
+        self.last = Some(data);
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let data = ReaderData::parse(
+            r#"{"bookTitle": "Dune", "asin": "B00B7NPRY8", "currentPosition": "1500",
+                "maxPosition": 6000, "somethingElse": {"x": 1}}"#,
+        )
+        .unwrap();
+        assert_eq!(data.title.as_deref(), Some("Dune"));
+        assert_eq!(data.position, Some(1500));
+        assert_eq!(data.max_position, Some(6000));
+        assert_eq!(data.percent(), Some(25.0));
+        assert_eq!(data.book(), Some("B00B7NPRY8"));
+
+        assert_eq!(ReaderData::parse("{}").unwrap(), ReaderData::default());
+        assert!(ReaderData::parse("not json").is_err());
+    }
+
+    #[test]
+    fn test_tracker() {
+        let mut t = ReaderTracker::new();
+        let out = t
+            .update(r#"{"title": "Dune", "asin": "A1", "position": 10, "percentRead": 1.5}"#)
+            .unwrap();
+        assert_eq!(
+            out[..4],
+            [
+                (String::from("book/title"), String::from("Dune")),
+                (String::from("book/asin"), String::from("A1")),
+                (String::from("book/position"), String::from("10")),
+                (String::from("book/percent"), String::from("1.5")),
+            ]
+        );
+        assert_eq!(out[4].0, "book/session");
+        let session: serde_json::Value = serde_json::from_str(&out[4].1).unwrap();
+        assert_eq!(session["book_changed"], true);
+        assert_eq!(session["to"], 10);
+
+        // Same book and position, no session event
+        let out = t
+            .update(r#"{"title": "Dune", "asin": "A1", "position": 10}"#)
+            .unwrap();
+        assert!(out.iter().all(|(topic, _)| topic != "book/session"));
+
+        let out = t
+            .update(r#"{"title": "Dune", "asin": "A1", "position": 42}"#)
+            .unwrap();
+        let session: serde_json::Value = serde_json::from_str(&out.last().unwrap().1).unwrap();
+        assert_eq!(session["book_changed"], false);
+        assert_eq!(session["from"], 10);
+        assert_eq!(session["to"], 42);
+    }
+}
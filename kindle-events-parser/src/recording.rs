@@ -0,0 +1,204 @@
+//! Recording of LIPC events to JSON-lines files, and replaying them through
+//! an in-memory bus to reproduce on a laptop what happened on a Kindle.
+
+#[cfg(feature = "mock")]
+use libopenlipc_sys::MockLipc;
+use libopenlipc_sys::{LipcBackend, LipcEvent, LipcResult};
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Services recorded when none are given
+pub const DEFAULT_SERVICES: &[&str] = libopenlipc_sys::catalog::SERVICES;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Param {
+    Int(i32),
+    Str(String),
+}
+
+impl From<&LipcResult> for Param {
+    fn from(r: &LipcResult) -> Param {
+        match r {
+            LipcResult::NUM(v) => Param::Int(*v),
+            LipcResult::STR(v) => Param::Str(v.clone()),
+        }
+    }
+}
+
+impl From<&Param> for LipcResult {
+    fn from(p: &Param) -> LipcResult {
+        match p {
+            Param::Int(v) => LipcResult::NUM(*v),
+            Param::Str(v) => LipcResult::STR(v.clone()),
+        }
+    }
+}
+
+/// A line of a recording
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RecordedEvent {
+    pub source: String,
+    pub name: String,
+    pub params: Vec<Param>,
+    /// Milliseconds since the recording started, from a monotonic clock
+    pub mono_ms: u64,
+    /// Wall clock time, in milliseconds since the unix epoch
+    pub wall_ms: u64,
+}
+
+impl RecordedEvent {
+    pub fn to_event(&self) -> LipcEvent {
+        LipcEvent {
+            source: self.source.clone(),
+            name: self.name.clone(),
+            params: self.params.iter().map(LipcResult::from).collect(),
+        }
+    }
+}
+
+fn write_line<W: Write>(out: &mut W, rec: &RecordedEvent) -> io::Result<()> {
+    serde_json::to_writer(&mut *out, rec)?;
+    writeln!(out)?;
+    out.flush()
+}
+
+/// Subscribes to every event of `services`, writing each of them to `out` as
+/// a line of JSON
+pub fn record<B, W>(r: &B, services: &[&str], out: Arc<Mutex<W>>) -> Result<(), String>
+where
+    B: LipcBackend,
+    W: Write + Send + 'static,
+{
+    let start = Instant::now();
+    for service in services {
+        let out = out.clone();
+        r.subscribe_events(
+            service,
+            None,
+            Box::new(move |ev| {
+                let rec = RecordedEvent {
+                    source: ev.source.clone(),
+                    name: ev.name.clone(),
+                    params: ev.params.iter().map(Param::from).collect(),
+                    mono_ms: start.elapsed().as_millis() as u64,
+                    wall_ms: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map(|d| d.as_millis() as u64)
+                        .unwrap_or(0),
+                };
+                if let Err(e) = write_line(&mut *out.lock().unwrap(), &rec) {
+                    log::warn!("Failed to record {}: {}", rec.name, e);
+                }
+            }),
+        )?;
+    }
+    Ok(())
+}
+
+/// Reads a recording made by `record`
+pub fn read_recording<R: BufRead>(input: R) -> Result<Vec<RecordedEvent>, String> {
+    let mut events = vec![];
+    for (i, line) in input.lines().enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let ev = serde_json::from_str(&line).map_err(|e| format!("line {}: {}", i + 1, e))?;
+        events.push(ev);
+    }
+    Ok(events)
+}
+
+/// Injects `events` into `bus` in order, waiting between them as long as they
+/// were apart when recorded, divided by `speed`. A `speed` of 0 replays them
+/// without waiting.
+#[cfg(feature = "mock")]
+pub fn replay(events: &[RecordedEvent], bus: &MockLipc, speed: f64) {
+    let mut last_ms = events.first().map_or(0, |e| e.mono_ms);
+    for ev in events {
+        if speed > 0.0 {
+            let wait = ev.mono_ms.saturating_sub(last_ms) as f64 / speed;
+            std::thread::sleep(std::time::Duration::from_millis(wait as u64));
+        }
+        last_ms = ev.mono_ms;
+        bus.inject(&ev.to_event());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_recording() {
+        let input = r#"{"source":"com.lab126.powerd","name":"battLevelChanged","params":[67],"mono_ms":10,"wall_ms":1660000000000}
+
+{"source":"com.lab126.appmgrd","name":"appActivating","params":[1,"com.lab126.booklet.reader"],"mono_ms":25,"wall_ms":1660000000015}
+"#;
+        let events = read_recording(input.as_bytes()).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(
+            events[1].to_event(),
+            LipcEvent {
+                source: String::from("com.lab126.appmgrd"),
+                name: String::from("appActivating"),
+                params: vec![
+                    LipcResult::NUM(1),
+                    LipcResult::STR(String::from("com.lab126.booklet.reader"))
+                ],
+            }
+        );
+
+        let err = read_recording("{}\n".as_bytes()).unwrap_err();
+        assert!(err.starts_with("line 1:"), "{}", err);
+    }
+
+    #[cfg(feature = "mock")]
+    #[test]
+    fn test_record_and_replay() {
+        use crate::filter::Filter;
+        use crate::{run_and_match, subscribe_all};
+
+        let device = MockLipc::new();
+        let out = Arc::new(Mutex::new(vec![]));
+        record(&device, DEFAULT_SERVICES, out.clone()).unwrap();
+        device.inject_event(
+            "com.lab126.powerd",
+            "battLevelChanged",
+            Some(LipcResult::NUM(67)),
+        );
+        device.inject_event("com.lab126.powerd", "goingToScreenSaver", None);
+        device.inject_event("com.lab126.wifid", "cmConnected", None);
+        // Not one of the recorded services
+        device.inject_event("com.example.other", "cmConnected", None);
+
+        let recording = out.lock().unwrap().clone();
+        let events = read_recording(recording.as_slice()).unwrap();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0].params, vec![Param::Int(67)]);
+        assert!(events.windows(2).all(|w| w[0].mono_ms <= w[1].mono_ms));
+
+        let laptop = MockLipc::new();
+        let published = Arc::new(Mutex::new(vec![]));
+        let p = published.clone();
+        subscribe_all(&laptop, &Filter::default(), move |ev| {
+            if let Some(msg) = run_and_match(ev) {
+                p.lock().unwrap().push(msg);
+            }
+        })
+        .unwrap();
+        replay(&events, &laptop, 0.0);
+
+        assert_eq!(
+            *published.lock().unwrap(),
+            vec![
+                ("battery", String::from("67")),
+                ("screen", String::from("0")),
+                ("connected", String::from("1")),
+            ]
+        );
+    }
+}
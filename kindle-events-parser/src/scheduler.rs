@@ -0,0 +1,260 @@
+//! Periodic polling of LIPC properties, publishing only what changed
+
+use crate::config::{PollConfig, PropType};
+use libopenlipc_sys::catalog::{powerd, Event};
+use libopenlipc_sys::LipcBackend;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+struct Poll {
+    config: PollConfig,
+    next_due: Instant,
+    /// Last published value, and when it was published
+    last: Option<(String, Instant)>,
+}
+
+/// A value that has to be published
+#[derive(Debug, Clone, PartialEq)]
+pub struct Polled {
+    pub service: String,
+    pub property: String,
+    pub topic: String,
+    pub value: String,
+    pub kind: PropType,
+}
+
+pub struct Scheduler {
+    polls: Vec<Poll>,
+    paused: bool,
+    /// xorshift state, for the jitter
+    rng: u64,
+}
+
+/// Whether the device is asleep (screensaver or suspended) after `event`, if
+/// the event changes that
+pub fn asleep_after(event: &str) -> Option<bool> {
+    const ASLEEP: [Event; 3] = [
+        powerd::GOING_TO_SCREEN_SAVER,
+        powerd::SUSPENDING,
+        powerd::READY_TO_SUSPEND,
+    ];
+    const AWAKE: [Event; 3] = [
+        powerd::OUT_OF_SCREEN_SAVER,
+        powerd::RESUMING,
+        powerd::WAKEUP_FROM_SUSPEND,
+    ];
+    if ASLEEP.iter().any(|e| e.name == event) {
+        Some(true)
+    } else if AWAKE.iter().any(|e| e.name == event) {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+fn read<B: LipcBackend>(r: &B, config: &PollConfig) -> Result<String, String> {
+    match config.kind {
+        PropType::Int => r
+            .get_int_prop(&config.service, &config.property)
+            .map(|v| v.to_string()),
+        PropType::Str => r.get_str_prop(&config.service, &config.property),
+    }
+}
+
+impl Scheduler {
+    /// Every property is due right away
+    pub fn new(polls: Vec<PollConfig>, now: Instant) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        Scheduler {
+            polls: polls
+                .into_iter()
+                .map(|config| Poll {
+                    config,
+                    next_due: now,
+                    last: None,
+                })
+                .collect(),
+            paused: false,
+            rng: seed | 1,
+        }
+    }
+
+    /// While paused, nothing is polled. Whatever became due in the meantime is
+    /// polled on the first `tick` after resuming.
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    fn jitter(&mut self, max_secs: u64) -> Duration {
+        if max_secs == 0 {
+            return Duration::from_secs(0);
+        }
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        Duration::from_millis(self.rng % (max_secs * 1000))
+    }
+
+    /// Polls every property that is due, returning the values that changed or
+    /// reached their max age
+    pub fn tick<B: LipcBackend>(&mut self, r: &B, now: Instant) -> Vec<Polled> {
+        let mut out = vec![];
+        if self.paused {
+            return out;
+        }
+
+        for i in 0..self.polls.len() {
+            if self.polls[i].next_due > now {
+                continue;
+            }
+            let jitter = self.jitter(self.polls[i].config.jitter_secs);
+            let poll = &mut self.polls[i];
+            poll.next_due = now + Duration::from_secs(poll.config.interval_secs) + jitter;
+
+            let value = match read(r, &poll.config) {
+                Ok(v) => v,
+                Err(e) => {
+                    log::warn!(
+                        "Failed to poll {} {}: {}",
+                        poll.config.service,
+                        poll.config.property,
+                        e
+                    );
+                    continue;
+                }
+            };
+            let max_age = poll.config.max_age_secs.map(Duration::from_secs);
+            let publish = match &poll.last {
+                None => true,
+                Some((last, at)) => {
+                    *last != value || max_age.is_some_and(|m| now.duration_since(*at) >= m)
+                }
+            };
+            if publish {
+                out.push(Polled {
+                    service: poll.config.service.clone(),
+                    property: poll.config.property.clone(),
+                    topic: poll.config.topic.clone(),
+                    value: value.clone(),
+                    kind: poll.config.kind,
+                });
+                poll.last = Some((value, now));
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "mock")]
+    fn poll(kind: PropType, property: &str, topic: &str) -> PollConfig {
+        PollConfig {
+            service: String::from("com.lab126.powerd"),
+            property: String::from(property),
+            kind,
+            topic: String::from(topic),
+            interval_secs: 60,
+            max_age_secs: None,
+            jitter_secs: 0,
+        }
+    }
+
+    #[cfg(feature = "mock")]
+    fn topics(polled: Vec<Polled>) -> Vec<(String, String)> {
+        polled.into_iter().map(|p| (p.topic, p.value)).collect()
+    }
+
+    #[test]
+    fn test_asleep_after() {
+        assert_eq!(asleep_after("goingToScreenSaver"), Some(true));
+        assert_eq!(asleep_after("outOfScreenSaver"), Some(false));
+        assert_eq!(asleep_after("battLevelChanged"), None);
+    }
+
+    #[test]
+    fn test_jitter() {
+        let now = Instant::now();
+        let mut s = Scheduler::new(vec![], now);
+        for _ in 0..100 {
+            assert!(s.jitter(5) < Duration::from_secs(5));
+        }
+        assert_eq!(s.jitter(0), Duration::from_secs(0));
+    }
+
+    #[cfg(feature = "mock")]
+    #[test]
+    fn test_publish_on_change() {
+        use libopenlipc_sys::{LipcResult, MockLipc};
+
+        let r = MockLipc::new();
+        r.set_prop("com.lab126.powerd", "battLevel", LipcResult::NUM(50));
+        r.set_prop(
+            "com.lab126.powerd",
+            "status",
+            LipcResult::STR(String::from("charging")),
+        );
+        let now = Instant::now();
+        let mut status = poll(PropType::Str, "status", "status");
+        status.interval_secs = 10;
+        let mut s = Scheduler::new(
+            vec![poll(PropType::Int, "battLevel", "battery"), status],
+            now,
+        );
+
+        assert_eq!(
+            topics(s.tick(&r, now)),
+            vec![
+                (String::from("battery"), String::from("50")),
+                (String::from("status"), String::from("charging")),
+            ]
+        );
+        // Nothing due
+        assert_eq!(s.tick(&r, now + Duration::from_secs(5)), vec![]);
+        // Due, but unchanged
+        assert_eq!(s.tick(&r, now + Duration::from_secs(10)), vec![]);
+
+        r.set_prop("com.lab126.powerd", "battLevel", LipcResult::NUM(49));
+        let polled = s.tick(&r, now + Duration::from_secs(60));
+        assert_eq!(
+            polled,
+            vec![Polled {
+                service: String::from("com.lab126.powerd"),
+                property: String::from("battLevel"),
+                topic: String::from("battery"),
+                value: String::from("49"),
+                kind: PropType::Int,
+            }]
+        );
+    }
+
+    #[cfg(feature = "mock")]
+    #[test]
+    fn test_max_age_and_pause() {
+        use libopenlipc_sys::{LipcResult, MockLipc};
+
+        let r = MockLipc::new();
+        r.set_prop("com.lab126.powerd", "battLevel", LipcResult::NUM(50));
+        let now = Instant::now();
+        let mut batt = poll(PropType::Int, "battLevel", "battery");
+        batt.max_age_secs = Some(120);
+        let mut s = Scheduler::new(vec![batt], now);
+
+        assert_eq!(s.tick(&r, now).len(), 1);
+        assert_eq!(s.tick(&r, now + Duration::from_secs(60)), vec![]);
+        assert_eq!(s.tick(&r, now + Duration::from_secs(120)).len(), 1);
+
+        s.set_paused(true);
+        assert_eq!(s.tick(&r, now + Duration::from_secs(400)), vec![]);
+        s.set_paused(false);
+        assert_eq!(s.tick(&r, now + Duration::from_secs(401)).len(), 1);
+
+        // Properties that fail to read are skipped
+        let mut missing = Scheduler::new(vec![poll(PropType::Int, "nope", "X")], now);
+        assert_eq!(missing.tick(&r, now), vec![]);
+    }
+}
@@ -0,0 +1,408 @@
+//! Reading sessions, derived from the reader app being in the foreground, the
+//! screen being on and the position in the book, with daily and weekly totals
+//! kept in a local JSON file
+
+use crate::clock::date;
+use crate::reader::ReaderData;
+use crate::scheduler::asleep_after;
+use libopenlipc_sys::catalog::{appmgrd, powerd};
+use libopenlipc_sys::{LipcEvent, LipcResult};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+
+pub const SESSION_SUMMARY_TOPIC: &str = "reading/session";
+
+const READER_APP: &str = "com.lab126.booklet.reader";
+/// Shorter sessions are most likely the device being picked up, not read
+const MIN_SESSION_SECS: u64 = 30;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Session {
+    pub book: String,
+    pub title: Option<String>,
+    /// Unix timestamps
+    pub start: u64,
+    pub end: u64,
+    pub start_position: Option<i64>,
+    pub end_position: Option<i64>,
+}
+
+impl Session {
+    pub fn duration_secs(&self) -> u64 {
+        self.end.saturating_sub(self.start)
+    }
+
+    /// Positions read forward; going back in the book does not count
+    pub fn positions_advanced(&self) -> i64 {
+        match (self.start_position, self.end_position) {
+            (Some(start), Some(end)) if end > start => end - start,
+            _ => 0,
+        }
+    }
+}
+
+/// Follows the device state and tells when a reading session ended. A session
+/// lasts while the reader app is in the foreground with the screen on, and
+/// ends early when the book changes.
+#[derive(Debug)]
+pub struct SessionTracker {
+    reader_active: bool,
+    screen_on: bool,
+    book: Option<String>,
+    title: Option<String>,
+    position: Option<i64>,
+    current: Option<Session>,
+}
+
+impl Default for SessionTracker {
+    fn default() -> Self {
+        SessionTracker {
+            reader_active: false,
+            screen_on: true,
+            book: None,
+            title: None,
+            position: None,
+            current: None,
+        }
+    }
+}
+
+impl SessionTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handles app activations from appmgrd and screen changes from powerd,
+    /// returning the session that ended, if any
+    pub fn on_event(&mut self, event: &LipcEvent, now: u64) -> Option<Session> {
+        match (event.source.as_str(), event.name.as_str()) {
+            _ if appmgrd::APP_ACTIVATING.matches(event) => {
+                // Not decoded, the status is not always sent
+                let app = event.params.iter().find_map(|p| match p {
+                    LipcResult::STR(s) => Some(s),
+                    LipcResult::NUM(_) => None,
+                })?;
+                self.reader_active = app.starts_with(READER_APP);
+            }
+            (powerd::SERVICE, name) => self.screen_on = !asleep_after(name)?,
+            _ => return None,
+        }
+        self.step(now)
+    }
+
+    /// Handles a new `allReaderData`, returning the session that ended, if any
+    pub fn on_reader_data(&mut self, data: &ReaderData, now: u64) -> Option<Session> {
+        let previous = (self.book.clone(), self.position);
+        if let Some(book) = data.book() {
+            self.book = Some(book.to_string());
+            self.title = data.title.clone();
+        }
+        if data.position.is_some() {
+            // Turning pages means the reader is open, even if its activation
+            // happened before we were started
+            if self.position.is_some() && data.position != self.position {
+                self.reader_active = true;
+            }
+            self.position = data.position;
+        }
+        let started = self.current.is_none();
+        let ended = self.step(now);
+        if let Some(current) = &mut self.current {
+            // A page turn that starts the session started on the page before
+            if started && previous.0.as_ref() == Some(&current.book) && previous.1.is_some() {
+                current.start_position = previous.1;
+            }
+            if data.position.is_some() {
+                current.end_position = data.position;
+            }
+        }
+        ended
+    }
+
+    /// The session in progress
+    pub fn current(&self) -> Option<&Session> {
+        self.current.as_ref()
+    }
+
+    fn step(&mut self, now: u64) -> Option<Session> {
+        let reading = self.reader_active && self.screen_on;
+        let ended = match &self.current {
+            Some(c) if !reading || Some(&c.book) != self.book.as_ref() => {
+                self.current.take().map(|mut c| {
+                    c.end = now;
+                    c
+                })
+            }
+            _ => None,
+        };
+        if reading && self.current.is_none() {
+            if let Some(book) = &self.book {
+                self.current = Some(Session {
+                    book: book.clone(),
+                    title: self.title.clone(),
+                    start: now,
+                    end: now,
+                    start_position: self.position,
+                    end_position: self.position,
+                });
+            }
+        }
+        ended.filter(|s| s.duration_secs() >= MIN_SESSION_SECS)
+    }
+}
+
+/// Totals over a period of time
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct Aggregate {
+    pub sessions: u32,
+    pub seconds: u64,
+    pub positions: i64,
+}
+
+impl Aggregate {
+    fn add(&mut self, session: &Session) {
+        self.sessions += 1;
+        self.seconds += session.duration_secs();
+        self.positions += session.positions_advanced();
+    }
+}
+
+/// Reading totals per day, and per week keyed by its Monday, in UTC
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Stats {
+    pub daily: BTreeMap<String, Aggregate>,
+    pub weekly: BTreeMap<String, Aggregate>,
+}
+
+fn day_key(ts: u64) -> String {
+    date(ts as i64 / 86400)
+}
+
+fn week_key(ts: u64) -> String {
+    let days = ts as i64 / 86400;
+    // The epoch was a Thursday
+    date(days - (days + 3).rem_euclid(7))
+}
+
+impl Stats {
+    /// Reads the stats at `path`, or empty stats if there is no file
+    pub fn load(path: &str) -> Result<Stats, String> {
+        match fs::read_to_string(path) {
+            Ok(s) => serde_json::from_str(&s).map_err(|e| format!("{}: {}", path, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Stats::default()),
+            Err(e) => Err(format!("Failed to read {}: {}", path, e)),
+        }
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let s = serde_json::to_string(self).map_err(|e| e.to_string())?;
+        // Write then rename, so a crash can't leave a truncated file behind
+        let tmp = format!("{}.tmp", path);
+        fs::write(&tmp, s)
+            .and_then(|_| fs::rename(&tmp, path))
+            .map_err(|e| format!("Failed to write {}: {}", path, e))
+    }
+
+    /// Counts `session` on the day and week it started
+    pub fn add(&mut self, session: &Session) {
+        self.daily
+            .entry(day_key(session.start))
+            .or_default()
+            .add(session);
+        self.weekly
+            .entry(week_key(session.start))
+            .or_default()
+            .add(session);
+    }
+
+    pub fn day(&self, ts: u64) -> Aggregate {
+        self.daily.get(&day_key(ts)).copied().unwrap_or_default()
+    }
+
+    pub fn week(&self, ts: u64) -> Aggregate {
+        self.weekly.get(&week_key(ts)).copied().unwrap_or_default()
+    }
+
+    /// The message published when `session` ends
+    pub fn summary(&self, session: &Session) -> String {
+        json!({
+            "book": session.book,
+            "title": session.title,
+            "start": session.start,
+            "end": session.end,
+            "duration_secs": session.duration_secs(),
+            "positions": session.positions_advanced(),
+            "today": self.day(session.start),
+            "week": self.week(session.start),
+        })
+        .to_string()
+    }
+}
+
+/// A `SessionTracker` whose sessions are added to the stats stored at `path`
+pub struct SessionLog {
+    tracker: SessionTracker,
+    stats: Stats,
+    path: String,
+}
+
+impl SessionLog {
+    pub fn new(path: &str, stats: Stats) -> SessionLog {
+        SessionLog {
+            tracker: SessionTracker::new(),
+            stats,
+            path: path.to_string(),
+        }
+    }
+
+    /// Continues from the stats already stored at `path`
+    pub fn open(path: &str) -> Result<SessionLog, String> {
+        Ok(SessionLog::new(path, Stats::load(path)?))
+    }
+
+    /// Same as `SessionTracker::on_event`, returning the summary to publish
+    pub fn on_event(&mut self, event: &LipcEvent, now: u64) -> Option<String> {
+        let ended = self.tracker.on_event(event, now)?;
+        Some(self.record(&ended))
+    }
+
+    /// Same as `SessionTracker::on_reader_data`, returning the summary to publish
+    pub fn on_reader_data(&mut self, data: &ReaderData, now: u64) -> Option<String> {
+        let ended = self.tracker.on_reader_data(data, now)?;
+        Some(self.record(&ended))
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    fn record(&mut self, session: &Session) -> String {
+        self.stats.add(session);
+        if let Err(e) = self.stats.save(&self.path) {
+            log::warn!("{}", e);
+        }
+        self.stats.summary(session)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(source: &str, name: &str, params: Vec<LipcResult>) -> LipcEvent {
+        LipcEvent {
+            source: source.to_string(),
+            name: name.to_string(),
+            params,
+        }
+    }
+
+    fn activate(app: &str) -> LipcEvent {
+        event(
+            appmgrd::SERVICE,
+            "appActivating",
+            vec![LipcResult::STR(app.into())],
+        )
+    }
+
+    fn reading(asin: &str, position: i64) -> ReaderData {
+        ReaderData {
+            asin: Some(asin.to_string()),
+            position: Some(position),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_keys() {
+        assert_eq!(day_key(1_709_251_199), "2024-02-29");
+        // Sunday 2024-03-03 belongs to the week of Monday 2024-02-26
+        assert_eq!(week_key(1_709_424_000), "2024-02-26");
+        assert_eq!(week_key(1_709_510_400), "2024-03-04");
+    }
+
+    #[test]
+    fn test_session() {
+        let mut t = SessionTracker::new();
+        assert_eq!(t.on_reader_data(&reading("A1", 100), 0), None);
+        assert_eq!(t.on_event(&activate("com.lab126.booklet.reader"), 10), None);
+        assert_eq!(t.current().unwrap().start, 10);
+        assert_eq!(t.on_reader_data(&reading("A1", 150), 200), None);
+
+        let ended = t
+            .on_event(&event(powerd::SERVICE, "goingToScreenSaver", vec![]), 310)
+            .unwrap();
+        assert_eq!(ended.book, "A1");
+        assert_eq!(ended.duration_secs(), 300);
+        assert_eq!(ended.positions_advanced(), 50);
+        assert_eq!(t.current(), None);
+
+        // Back on the same page, too short to count
+        t.on_event(&event(powerd::SERVICE, "outOfScreenSaver", vec![]), 400);
+        assert!(t.current().is_some());
+        assert_eq!(t.on_event(&activate("com.lab126.booklet.home"), 410), None);
+        assert_eq!(t.current(), None);
+    }
+
+    #[test]
+    fn test_book_change() {
+        let mut t = SessionTracker::new();
+        t.on_event(&activate("com.lab126.booklet.reader"), 0);
+        // Nothing is known about the book yet
+        assert_eq!(t.current(), None);
+        t.on_reader_data(&reading("A1", 10), 0);
+        t.on_reader_data(&reading("A1", 20), 50);
+
+        let ended = t.on_reader_data(&reading("B2", 500), 100).unwrap();
+        assert_eq!(ended.book, "A1");
+        assert_eq!(ended.end_position, Some(20));
+        let current = t.current().unwrap();
+        assert_eq!(current.book, "B2");
+        assert_eq!(current.start_position, Some(500));
+    }
+
+    #[test]
+    fn test_page_turn_starts_session() {
+        let mut t = SessionTracker::new();
+        t.on_reader_data(&reading("A1", 10), 0);
+        assert_eq!(t.current(), None);
+        t.on_reader_data(&reading("A1", 11), 5);
+        assert_eq!(t.current().unwrap().start_position, Some(10));
+    }
+
+    #[test]
+    fn test_stats() {
+        let path = std::env::temp_dir().join(format!("kindle-stats-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let mut log = SessionLog::open(path).unwrap();
+        let monday = 1_709_510_400;
+        log.on_event(&activate("com.lab126.booklet.reader"), monday);
+        log.on_reader_data(&reading("A1", 10), monday);
+        log.on_reader_data(&reading("A1", 40), monday + 60);
+        let summary = log
+            .on_event(&activate("com.lab126.booklet.home"), monday + 120)
+            .unwrap();
+        let summary: serde_json::Value = serde_json::from_str(&summary).unwrap();
+        assert_eq!(summary["duration_secs"], 120);
+        assert_eq!(summary["positions"], 30);
+        assert_eq!(summary["today"]["sessions"], 1);
+        assert_eq!(summary["week"]["seconds"], 120);
+
+        let stats = Stats::load(path).unwrap();
+        fs::remove_file(path).unwrap();
+        assert_eq!(&stats, log.stats());
+        assert_eq!(
+            stats.week(monday + 86400),
+            Aggregate {
+                sessions: 1,
+                seconds: 120,
+                positions: 30,
+            }
+        );
+        assert_eq!(stats.day(monday + 86400), Aggregate::default());
+    }
+}
@@ -0,0 +1,310 @@
+//! Where published messages end up: an MQTT broker, an HTTP webhook, a JSONL
+//! file or stdout
+
+use crate::clock::{iso8601, unix_now};
+use mqtt_simple::publish_once;
+use serde_json::{json, Value};
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::Duration;
+
+pub(crate) const HTTP_TIMEOUT: Duration = Duration::from_secs(3);
+
+pub trait Sink: Send + Sync {
+    /// Deliver `payload`, published on the full `topic`
+    fn send(&self, topic: &str, payload: &str, retain: bool) -> Result<(), Box<dyn Error>>;
+    /// Whether the sink keeps retained messages for later consumers, which is
+    /// what Home Assistant discovery relies on
+    fn retains(&self) -> bool {
+        false
+    }
+    /// Whether the sink needs the network, which goes away when the Kindle
+    /// suspends
+    fn remote(&self) -> bool {
+        false
+    }
+}
+
+/// How a message is represented outside of MQTT. Which values are numbers is
+/// not known here, only JSON payloads are kept as documents.
+fn record(topic: &str, payload: &str) -> Value {
+    let document = serde_json::from_str(payload)
+        .ok()
+        .filter(|v: &Value| v.is_object() || v.is_array());
+    json!({
+        "topic": topic,
+        "payload": document.unwrap_or_else(|| Value::String(payload.to_string())),
+        "ts": iso8601(unix_now()),
+    })
+}
+
+pub struct MqttSink {
+    broker: String,
+    client_id: String,
+    /// One connection at a time: the broker drops the older one when a
+    /// second connects with the same client id
+    sending: Mutex<()>,
+}
+
+impl MqttSink {
+    pub fn new(broker: &str, client_id: &str) -> MqttSink {
+        MqttSink {
+            broker: broker.to_string(),
+            client_id: client_id.to_string(),
+            sending: Mutex::new(()),
+        }
+    }
+}
+
+impl Sink for MqttSink {
+    fn send(&self, topic: &str, payload: &str, retain: bool) -> Result<(), Box<dyn Error>> {
+        let _sending = self.sending.lock().unwrap();
+        publish_once(
+            self.client_id.clone(),
+            self.broker.clone(),
+            topic,
+            payload,
+            retain,
+        )
+    }
+
+    fn retains(&self) -> bool {
+        true
+    }
+
+    fn remote(&self) -> bool {
+        true
+    }
+}
+
+/// POSTs every message as JSON to a plain `http://` URL
+pub struct HttpSink {
+    /// `host:port`
+    host: String,
+    path: String,
+}
+
+/// Splits a plain `http://` URL into `host:port` and path
+pub(crate) fn parse_http_url(url: &str) -> Result<(String, String), String> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| format!("Only http:// URLs are supported: {}", url))?;
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    if host.is_empty() {
+        return Err(format!("No host in {}", url));
+    }
+    let host = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:80", host)
+    };
+    Ok((host, path.to_string()))
+}
+
+/// A minimal HTTP/1.1 POST, failing unless the response is a 2xx
+pub(crate) fn http_post(
+    host: &str,
+    path: &str,
+    content_type: &str,
+    body: &str,
+) -> Result<(), Box<dyn Error>> {
+    let addr = host
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| format!("Could not resolve {}", host))?;
+    let mut stream = TcpStream::connect_timeout(&addr, HTTP_TIMEOUT)?;
+    stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        host,
+        content_type,
+        body.len(),
+        body
+    )?;
+
+    let mut status = String::new();
+    BufReader::new(stream).read_line(&mut status)?;
+    // HTTP/1.1 200 OK
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(format!("POST to {}{} failed: {}", host, path, status.trim()).into()),
+    }
+}
+
+impl HttpSink {
+    pub fn new(url: &str) -> Result<HttpSink, String> {
+        let (host, path) = parse_http_url(url)?;
+        Ok(HttpSink { host, path })
+    }
+}
+
+impl Sink for HttpSink {
+    fn send(&self, topic: &str, payload: &str, _retain: bool) -> Result<(), Box<dyn Error>> {
+        let body = record(topic, payload).to_string();
+        http_post(&self.host, &self.path, "application/json", &body)
+    }
+
+    fn remote(&self) -> bool {
+        true
+    }
+}
+
+/// Appends every message to a file, one JSON object per line
+pub struct FileSink {
+    file: Mutex<File>,
+}
+
+impl FileSink {
+    pub fn new(path: &str) -> Result<FileSink, String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("Failed to open {}: {}", path, e))?;
+        Ok(FileSink {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl Sink for FileSink {
+    fn send(&self, topic: &str, payload: &str, _retain: bool) -> Result<(), Box<dyn Error>> {
+        let line = record(topic, payload).to_string();
+        writeln!(self.file.lock().unwrap(), "{}", line)?;
+        Ok(())
+    }
+}
+
+pub struct StdoutSink;
+
+impl Sink for StdoutSink {
+    fn send(&self, topic: &str, payload: &str, _retain: bool) -> Result<(), Box<dyn Error>> {
+        println!("{} {}", topic, payload);
+        Ok(())
+    }
+}
+
+/// Whether `topic` matches `pattern`, which can use the MQTT wildcards: `+`
+/// for one level and a trailing `#` for any amount of levels
+pub fn topic_matches(pattern: &str, topic: &str) -> bool {
+    let mut levels = topic.split('/');
+    for p in pattern.split('/') {
+        if p == "#" {
+            return true;
+        }
+        match levels.next() {
+            Some(level) if p == "+" || p == level => continue,
+            _ => return false,
+        }
+    }
+    levels.next().is_none()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_topic_matches() {
+        assert!(topic_matches("battery", "battery"));
+        assert!(!topic_matches("battery", "battery/rate"));
+        assert!(topic_matches("battery/#", "battery"));
+        assert!(topic_matches("battery/#", "battery/rate"));
+        assert!(topic_matches("+/session", "book/session"));
+        assert!(!topic_matches("+/session", "book"));
+        assert!(topic_matches("#", "anything/at/all"));
+    }
+
+    #[test]
+    fn test_http_url() {
+        let s = HttpSink::new("http://example.com:8080/hook").unwrap();
+        assert_eq!(
+            (s.host.as_str(), s.path.as_str()),
+            ("example.com:8080", "/hook")
+        );
+        let s = HttpSink::new("http://example.com").unwrap();
+        assert_eq!((s.host.as_str(), s.path.as_str()), ("example.com:80", "/"));
+        assert!(HttpSink::new("https://example.com").is_err());
+        assert!(HttpSink::new("http:///hook").is_err());
+    }
+
+    /// Answers a single request with `status`, returning what was received
+    pub(crate) fn serve_once(status: &'static str) -> (String, std::thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let handle = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request = String::new();
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if let Some(l) = line.strip_prefix("Content-Length: ") {
+                    length = l.trim().parse().unwrap();
+                }
+                request.push_str(&line);
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            request.push_str(&String::from_utf8(body).unwrap());
+            write!(reader.get_mut(), "HTTP/1.1 {}\r\n\r\n", status).unwrap();
+            request
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn test_http_sink() {
+        let (url, server) = serve_once("204 No Content");
+        HttpSink::new(&url)
+            .unwrap()
+            .send("kindle/x/battery", "67", false)
+            .unwrap();
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /hook HTTP/1.1\r\n"));
+        let body: Value = serde_json::from_str(request.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        assert_eq!(body["topic"], "kindle/x/battery");
+        // Raw payloads could be anything, a title like 1984 included
+        assert_eq!(body["payload"], "67");
+
+        let (url, server) = serve_once("500 Internal Server Error");
+        assert!(HttpSink::new(&url)
+            .unwrap()
+            .send("kindle/x/battery", "67", false)
+            .is_err());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_file_sink() {
+        let path = std::env::temp_dir().join(format!("kindle-sink-{}.jsonl", std::process::id()));
+        let path = path.to_str().unwrap();
+        let sink = FileSink::new(path).unwrap();
+        sink.send("kindle/x/battery", "67", false).unwrap();
+        sink.send("kindle/x/book/title", "Dune", false).unwrap();
+
+        let contents = std::fs::read_to_string(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let lines: Vec<Value> = contents
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["topic"], "kindle/x/book/title");
+        assert_eq!(lines[1]["payload"], "Dune");
+    }
+}
@@ -0,0 +1,161 @@
+//! What the Kindle is doing right now, kept up to date from the events, for
+//! consumers that want a snapshot rather than a stream of changes
+
+use crate::reader::ReaderData;
+use libopenlipc_sys::catalog::{acxreaderplugin, appmgrd, powerd, wifid};
+use libopenlipc_sys::{KindleEvent, LipcBackend};
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+
+/// Retained, the snapshot as a JSON object. Unlike `publisher::STATE_TOPIC`,
+/// which holds whatever was published, it has fixed fields and follows every
+/// event, including those the filters keep from being published.
+pub const DEVICE_STATE_TOPIC: &str = "device";
+
+/// Every field is `None` until it is read or an event tells
+#[derive(Serialize, Debug, Clone, PartialEq, Default)]
+pub struct DeviceState {
+    /// Percent
+    pub battery: Option<i32>,
+    pub charging: Option<bool>,
+    pub screen_on: Option<bool>,
+    pub wifi_connected: Option<bool>,
+    /// Only comes with `cmConnected`, no property holds it
+    pub essid: Option<String>,
+    /// Id of the app in the foreground
+    pub app: Option<String>,
+    /// Title of the open book, or its ASIN
+    pub book: Option<String>,
+    /// Unix timestamp
+    pub last_event: Option<u64>,
+}
+
+/// The state shared between the event handlers and whoever reads it
+pub type SharedState = Arc<Mutex<DeviceState>>;
+
+fn book(json: &str) -> Option<String> {
+    let data = ReaderData::parse(json).ok()?;
+    data.title.clone().or_else(|| data.book().map(String::from))
+}
+
+impl DeviceState {
+    /// Reads what can be read from the services; what fails is left unknown
+    pub fn bootstrap<B: LipcBackend>(r: &B) -> DeviceState {
+        DeviceState {
+            battery: r.get_int(powerd::BATT_LEVEL).ok(),
+            charging: r.get_int(powerd::IS_CHARGING).ok().map(|c| c != 0),
+            screen_on: r.get_str(powerd::STATE).ok().map(|s| s == "active"),
+            wifi_connected: r.get_str(wifid::CM_STATE).ok().map(|s| s == "CONNECTED"),
+            app: r.get_str(appmgrd::ACTIVE_APP).ok(),
+            book: r
+                .get_str(acxreaderplugin::ALL_READER_DATA)
+                .ok()
+                .and_then(|json| book(&json)),
+            ..Default::default()
+        }
+    }
+
+    /// Updates the state with `event`, received at `now`. Returns whether
+    /// anything but the time of the last event changed.
+    pub fn apply(&mut self, event: &KindleEvent, now: u64) -> bool {
+        let before = self.clone();
+        match event {
+            KindleEvent::BatteryLevelChanged { level } => self.battery = Some(*level),
+            KindleEvent::ChargerConnected => self.charging = Some(true),
+            KindleEvent::ChargerDisconnected => self.charging = Some(false),
+            KindleEvent::ScreenSaverEntered
+            | KindleEvent::ReadyToSuspend
+            | KindleEvent::Suspending => self.screen_on = Some(false),
+            KindleEvent::ScreenSaverExited => self.screen_on = Some(true),
+            KindleEvent::WifiConnected { essid } => {
+                self.wifi_connected = Some(true);
+                self.essid = essid.clone();
+            }
+            KindleEvent::WifiDisconnected => {
+                self.wifi_connected = Some(false);
+                self.essid = None;
+            }
+            KindleEvent::AppActivating { app } => self.app = Some(app.clone()),
+            KindleEvent::ReaderData(json) => {
+                if let Some(book) = book(json) {
+                    self.book = Some(book);
+                }
+            }
+            _ => {}
+        }
+        let changed = *self != before;
+        self.last_event = Some(now);
+        changed
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let mut state = DeviceState::default();
+        assert!(state.apply(&KindleEvent::BatteryLevelChanged { level: 67 }, 10));
+        assert!(!state.apply(&KindleEvent::BatteryLevelChanged { level: 67 }, 20));
+        assert_eq!(state.last_event, Some(20));
+        assert!(state.apply(
+            &KindleEvent::WifiConnected {
+                essid: Some(String::from("home"))
+            },
+            30
+        ));
+        assert!(state.apply(&KindleEvent::ScreenSaverEntered, 40));
+        assert!(state.apply(
+            &KindleEvent::ReaderData(String::from(r#"{"bookTitle":"Dune"}"#)),
+            50
+        ));
+        assert!(!state.apply(&KindleEvent::Resuming, 60));
+        assert!(state.apply(&KindleEvent::WifiDisconnected, 70));
+
+        assert_eq!(
+            state,
+            DeviceState {
+                battery: Some(67),
+                screen_on: Some(false),
+                wifi_connected: Some(false),
+                book: Some(String::from("Dune")),
+                last_event: Some(70),
+                ..Default::default()
+            }
+        );
+        let json: serde_json::Value = serde_json::from_str(&state.to_json()).unwrap();
+        assert_eq!(json["battery"], 67);
+        assert_eq!(json["charging"], serde_json::Value::Null);
+    }
+
+    #[cfg(feature = "mock")]
+    #[test]
+    fn test_bootstrap() {
+        use libopenlipc_sys::{LipcResult, MockLipc};
+
+        let r = MockLipc::new();
+        let s = |v: &str| LipcResult::STR(v.to_string());
+        r.set_prop(powerd::SERVICE, "battLevel", LipcResult::NUM(80));
+        r.set_prop(powerd::SERVICE, "isCharging", LipcResult::NUM(1));
+        r.set_prop(powerd::SERVICE, "state", s("screenSaver"));
+        r.set_prop(wifid::SERVICE, "cmState", s("CONNECTED"));
+        r.set_prop(appmgrd::SERVICE, "activeApp", s("com.lab126.booklet.home"));
+
+        assert_eq!(
+            DeviceState::bootstrap(&r),
+            DeviceState {
+                battery: Some(80),
+                charging: Some(true),
+                screen_on: Some(false),
+                wifi_connected: Some(true),
+                app: Some(String::from("com.lab126.booklet.home")),
+                ..Default::default()
+            }
+        );
+    }
+}
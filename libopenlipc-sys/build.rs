@@ -1,5 +1,8 @@
 use std::env;
 fn main() {
+    if env::var_os("CARGO_FEATURE_NATIVE").is_none() {
+        return;
+    }
     env::set_var("SYSROOT_LIB_DIR", "../Amazon-Kindle-Cross-Toolchain/arm-kindle-linux-gnueabi/arm-kindle-linux-gnueabi/sysroot/lib/");
 
     println!("cargo:rustc-link-search=so");
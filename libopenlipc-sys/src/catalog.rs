@@ -0,0 +1,406 @@
+//! The well-known services, with their properties and events, as typed
+//! constants:
+//!
+//! ```no_run
+//! use libopenlipc_sys::catalog::powerd;
+//! # fn f<B: libopenlipc_sys::LipcBackend>(r: &B) -> Result<(), String> {
+//! let level: i32 = r.get_int(powerd::BATT_LEVEL)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Reading a string property as an int is a type error instead of a failure
+//! at runtime, and event params can be decoded by name.
+
+use crate::{Hasharray, LipcEvent, LipcResult};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::marker::PhantomData;
+
+/// A property of `service`, holding values of type `T`
+pub struct Prop<T> {
+    pub service: &'static str,
+    pub name: &'static str,
+    value: PhantomData<fn() -> T>,
+}
+
+impl<T> Prop<T> {
+    pub const fn new(service: &'static str, name: &'static str) -> Self {
+        Prop {
+            service,
+            name,
+            value: PhantomData,
+        }
+    }
+}
+
+// Derives would require T to be Clone, Copy, etc. too
+impl<T> Clone for Prop<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Prop<T> {}
+
+impl<T> fmt::Debug for Prop<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.service, self.name)
+    }
+}
+
+impl<T> PartialEq for Prop<T> {
+    fn eq(&self, other: &Self) -> bool {
+        (self.service, self.name) == (other.service, other.name)
+    }
+}
+
+pub type IntProp = Prop<i32>;
+pub type StrProp = Prop<String>;
+pub type HasharrayProp = Prop<Hasharray>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParamKind {
+    Int,
+    Str,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Param {
+    pub name: &'static str,
+    pub kind: ParamKind,
+}
+
+const fn int(name: &'static str) -> Param {
+    Param {
+        name,
+        kind: ParamKind::Int,
+    }
+}
+
+const fn string(name: &'static str) -> Param {
+    Param {
+        name,
+        kind: ParamKind::Str,
+    }
+}
+
+/// An event broadcasted by `service`, with the params it carries, in order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+    pub service: &'static str,
+    pub name: &'static str,
+    pub params: &'static [Param],
+}
+
+/// The params of an event, by name
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Params(BTreeMap<&'static str, LipcResult>);
+
+impl Params {
+    pub fn int(&self, name: &str) -> Option<i32> {
+        match self.0.get(name)? {
+            LipcResult::NUM(n) => Some(*n),
+            LipcResult::STR(_) => None,
+        }
+    }
+
+    pub fn str(&self, name: &str) -> Option<&str> {
+        match self.0.get(name)? {
+            LipcResult::STR(s) => Some(s),
+            LipcResult::NUM(_) => None,
+        }
+    }
+}
+
+impl Event {
+    pub fn matches(&self, ev: &LipcEvent) -> bool {
+        ev.source == self.service && ev.name == self.name
+    }
+
+    /// Names the params of `ev`. Params the event was sent without are left
+    /// out, as are extra ones; a param of the wrong type is an error.
+    pub fn decode(&self, ev: &LipcEvent) -> Result<Params, String> {
+        if !self.matches(ev) {
+            return Err(format!(
+                "Expected {} {}, got {} {}",
+                self.service, self.name, ev.source, ev.name
+            ));
+        }
+        let mut params = BTreeMap::new();
+        for (param, value) in self.params.iter().zip(&ev.params) {
+            match (param.kind, value) {
+                (ParamKind::Int, LipcResult::NUM(_)) | (ParamKind::Str, LipcResult::STR(_)) => {
+                    params.insert(param.name, value.clone());
+                }
+                _ => {
+                    return Err(format!(
+                        "{} of {} should be {:?}, got {:?}",
+                        param.name, self.name, param.kind, value
+                    ))
+                }
+            }
+        }
+        Ok(Params(params))
+    }
+
+    /// Names the params of `ev` that have the type the catalog gives them:
+    /// missing, extra and wrongly typed params are all left out, for callers
+    /// that only care about some of them.
+    pub fn params(&self, ev: &LipcEvent) -> Params {
+        let params = self
+            .params
+            .iter()
+            .zip(&ev.params)
+            .filter(|(param, value)| {
+                matches!(
+                    (param.kind, value),
+                    (ParamKind::Int, LipcResult::NUM(_)) | (ParamKind::Str, LipcResult::STR(_))
+                )
+            })
+            .map(|(param, value)| (param.name, value.clone()))
+            .collect();
+        Params(params)
+    }
+}
+
+macro_rules! event {
+    ($name:literal $(, $param:expr)*) => {
+        Event {
+            service: SERVICE,
+            name: $name,
+            params: &[$($param),*],
+        }
+    };
+}
+
+/// Power management: battery, screensaver and suspend
+pub mod powerd {
+    use super::*;
+
+    pub const SERVICE: &str = "com.lab126.powerd";
+
+    /// Percent
+    pub const BATT_LEVEL: IntProp = Prop::new(SERVICE, "battLevel");
+    /// Fahrenheit; not on every model
+    pub const BATT_TEMPERATURE: IntProp = Prop::new(SERVICE, "battTemperature");
+    pub const IS_CHARGING: IntProp = Prop::new(SERVICE, "isCharging");
+    /// `active`, `screenSaver`, `readyToSuspend`...
+    pub const STATE: StrProp = Prop::new(SERVICE, "state");
+    /// 1 keeps the screensaver from kicking in
+    pub const PREVENT_SCREEN_SAVER: IntProp = Prop::new(SERVICE, "preventScreenSaver");
+    /// Seconds to hold off suspending for
+    pub const DEFER_SUSPEND: IntProp = Prop::new(SERVICE, "deferSuspend");
+    /// 1 leaves the screensaver
+    pub const WAKE_UP: IntProp = Prop::new(SERVICE, "wakeUp");
+    /// 1 starts the countdown to the screensaver over
+    pub const TOUCH_SCREEN_SAVER_TIMEOUT: IntProp = Prop::new(SERVICE, "touchScreenSaverTimeout");
+    /// Seconds after which the device wakes up from suspend
+    pub const RTC_WAKEUP: IntProp = Prop::new(SERVICE, "rtcWakeup");
+    /// 1 does what pressing the power button does
+    pub const POWER_BUTTON: IntProp = Prop::new(SERVICE, "powerButton");
+
+    pub const BATT_LEVEL_CHANGED: Event = event!("battLevelChanged", int("level"));
+    pub const GOING_TO_SCREEN_SAVER: Event = event!("goingToScreenSaver", int("reason"));
+    pub const OUT_OF_SCREEN_SAVER: Event = event!("outOfScreenSaver", int("source"));
+    pub const READY_TO_SUSPEND: Event = event!("readyToSuspend", int("delay"));
+    pub const SUSPENDING: Event = event!("suspending");
+    pub const RESUMING: Event = event!("resuming");
+    pub const WAKEUP_FROM_SUSPEND: Event = event!("wakeupFromSuspend", int("seconds"));
+    pub const CHARGING: Event = event!("charging");
+    pub const NOT_CHARGING: Event = event!("notCharging");
+}
+
+/// Wifi connection manager
+pub mod wifid {
+    use super::*;
+
+    pub const SERVICE: &str = "com.lab126.wifid";
+
+    /// `CONNECTED`, `NA`...
+    pub const CM_STATE: StrProp = Prop::new(SERVICE, "cmState");
+    /// One hash per known network
+    pub const PROFILE_DATA: HasharrayProp = Prop::new(SERVICE, "profileData");
+    /// One hash per network seen in the last scan
+    pub const SCAN_LIST: HasharrayProp = Prop::new(SERVICE, "scanList");
+
+    /// The network is not sent by every firmware
+    pub const CM_CONNECTED: Event = event!("cmConnected", string("essid"));
+    pub const CM_INTF_NOT_AVAILABLE: Event = event!("cmIntfNotAvailable");
+    pub const SCAN_COMPLETE: Event = event!("scanComplete");
+}
+
+/// Application manager
+pub mod appmgrd {
+    use super::*;
+
+    pub const SERVICE: &str = "com.lab126.appmgrd";
+
+    /// Setting it to an app url, like `app://com.lab126.booklet.home`, starts
+    /// the app
+    pub const START: StrProp = Prop::new(SERVICE, "start");
+    pub const ACTIVE_APP: StrProp = Prop::new(SERVICE, "activeApp");
+
+    pub const APP_ACTIVATING: Event = event!("appActivating", int("status"), string("app"));
+}
+
+/// The reader, and what is being read
+pub mod acxreaderplugin {
+    use super::*;
+
+    pub const SERVICE: &str = "com.lab126.acxreaderplugin";
+
+    /// A JSON document describing the open book
+    pub const ALL_READER_DATA: StrProp = Prop::new(SERVICE, "allReaderData");
+
+    /// Same JSON document as the property
+    pub const ALL_READER_DATA_EVENT: Event = event!("allReaderData", string("data"));
+}
+
+/// Window manager
+pub mod winmgr {
+    use super::*;
+
+    pub const SERVICE: &str = "com.lab126.winmgr";
+
+    /// `U`, `R`, `D` or `L`
+    pub const ORIENTATION_LOCK: StrProp = Prop::new(SERVICE, "orientationLock");
+}
+
+/// Connectivity
+pub mod cmd {
+    use super::*;
+
+    pub const SERVICE: &str = "com.lab126.cmd";
+
+    pub const WIRELESS_ENABLE: IntProp = Prop::new(SERVICE, "wirelessEnable");
+}
+
+/// The device itself
+pub mod system {
+    use super::*;
+
+    pub const SERVICE: &str = "com.lab126.system";
+
+    /// The serial number
+    pub const USID: StrProp = Prop::new(SERVICE, "usid");
+}
+
+/// The services of the catalog, including those that only have properties
+pub const SERVICES: &[&str] = &[
+    powerd::SERVICE,
+    wifid::SERVICE,
+    appmgrd::SERVICE,
+    acxreaderplugin::SERVICE,
+    winmgr::SERVICE,
+    cmd::SERVICE,
+    system::SERVICE,
+];
+
+pub const EVENTS: &[Event] = &[
+    powerd::BATT_LEVEL_CHANGED,
+    powerd::GOING_TO_SCREEN_SAVER,
+    powerd::OUT_OF_SCREEN_SAVER,
+    powerd::READY_TO_SUSPEND,
+    powerd::SUSPENDING,
+    powerd::RESUMING,
+    powerd::WAKEUP_FROM_SUSPEND,
+    powerd::CHARGING,
+    powerd::NOT_CHARGING,
+    wifid::CM_CONNECTED,
+    wifid::CM_INTF_NOT_AVAILABLE,
+    wifid::SCAN_COMPLETE,
+    appmgrd::APP_ACTIVATING,
+    acxreaderplugin::ALL_READER_DATA_EVENT,
+];
+
+/// The services broadcasting the events of the catalog, in its order
+pub fn event_services() -> Vec<&'static str> {
+    let mut services = vec![];
+    for e in EVENTS {
+        if !services.contains(&e.service) {
+            services.push(e.service);
+        }
+    }
+    services
+}
+
+/// The catalog entry for `name` broadcasted by `source`, if known
+pub fn event(source: &str, name: &str) -> Option<&'static Event> {
+    EVENTS
+        .iter()
+        .find(|e| e.service == source && e.name == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ev(source: &str, name: &str, params: Vec<LipcResult>) -> LipcEvent {
+        LipcEvent {
+            source: source.to_string(),
+            name: name.to_string(),
+            params,
+        }
+    }
+
+    #[test]
+    fn test_decode() {
+        let activating = ev(
+            appmgrd::SERVICE,
+            "appActivating",
+            vec![
+                LipcResult::NUM(1),
+                LipcResult::STR(String::from("com.lab126.booklet.reader")),
+            ],
+        );
+        let spec = event(&activating.source, &activating.name).unwrap();
+        assert_eq!(*spec, appmgrd::APP_ACTIVATING);
+        let params = spec.decode(&activating).unwrap();
+        assert_eq!(params.int("status"), Some(1));
+        assert_eq!(params.str("app"), Some("com.lab126.booklet.reader"));
+        assert_eq!(params.str("status"), None);
+
+        // Missing params are left out
+        let params = powerd::GOING_TO_SCREEN_SAVER
+            .decode(&ev(powerd::SERVICE, "goingToScreenSaver", vec![]))
+            .unwrap();
+        assert_eq!(params, Params::default());
+
+        let wrong = ev(
+            powerd::SERVICE,
+            "battLevelChanged",
+            vec![LipcResult::STR(String::from("full"))],
+        );
+        assert!(powerd::BATT_LEVEL_CHANGED.decode(&wrong).is_err());
+        assert!(powerd::GOING_TO_SCREEN_SAVER.decode(&wrong).is_err());
+        // Unless only the well typed ones are asked for
+        assert_eq!(powerd::BATT_LEVEL_CHANGED.params(&wrong), Params::default());
+        assert_eq!(
+            appmgrd::APP_ACTIVATING.params(&activating).str("app"),
+            Some("com.lab126.booklet.reader")
+        );
+        assert_eq!(event(powerd::SERVICE, "nothing"), None);
+    }
+
+    #[test]
+    fn test_catalog() {
+        for e in EVENTS {
+            assert!(SERVICES.contains(&e.service), "{:?}", e);
+            assert_eq!(event(e.service, e.name), Some(e));
+        }
+        assert_eq!(
+            event_services(),
+            vec![
+                powerd::SERVICE,
+                wifid::SERVICE,
+                appmgrd::SERVICE,
+                acxreaderplugin::SERVICE
+            ]
+        );
+        assert_eq!(
+            format!("{:?}", powerd::BATT_LEVEL),
+            "com.lab126.powerd battLevel"
+        );
+    }
+}
@@ -0,0 +1,403 @@
+use crate::{EventHandler, LipcBackend, LipcCallback, LipcEvent, LipcResult};
+use log::error;
+use zbus::blocking::{Connection, MessageIterator};
+use zbus::message::Type;
+use zbus::zvariant::{OwnedValue, Structure, StructureBuilder, Value};
+use zbus::MatchRule;
+
+/// Object path every LIPC service exposes its properties and events on
+const LIPC_PATH: &str = "/default";
+/// Method returning the value of a property, takes (property name) and returns a variant
+const LIPC_GET: &str = "get";
+/// Method setting the value of a property, takes (property name, variant)
+const LIPC_SET: &str = "set";
+
+/// LIPC client speaking D-Bus directly, without `liblipc.so`.
+///
+/// Every service `com.lab126.<name>` owns the bus name of the same name and
+/// exposes, on the object `/default` and under an interface also named after
+/// the service:
+///  * properties through the `get` and `set` methods, with the value wrapped
+///    in a variant that holds either an `i32` or a string
+///  * events as signals named after the event, with the int and string
+///    params as the signal arguments, in order
+pub struct DbusLipc {
+    conn: Connection,
+    name: Option<String>,
+}
+
+impl DbusLipc {
+    /// Returns a new LIPC client connected to the system bus, where the Kindle
+    /// services live.
+    pub fn new() -> Result<Self, String> {
+        let conn = Connection::system().map_err(|e| format!("Failed to connect: {}", e))?;
+        Ok(Self { conn, name: None })
+    }
+
+    /// Returns a new LIPC client connected to the system bus and registered as
+    /// `service`, which is required to send events.
+    pub fn with_name(service: &str) -> Result<Self, String> {
+        let conn = zbus::blocking::connection::Builder::system()
+            .and_then(|b| b.name(service.to_string()))
+            .and_then(|b| b.build())
+            .map_err(|e| format!("Failed to connect: {}", e))?;
+        Ok(Self {
+            conn,
+            name: Some(service.to_string()),
+        })
+    }
+
+    /// Returns a new LIPC client connected to the bus at `address`, optionally
+    /// registered as `service`. Mostly useful to talk to a private `dbus-daemon`.
+    pub fn with_address(address: &str, service: Option<&str>) -> Result<Self, String> {
+        let mut builder = zbus::blocking::connection::Builder::address(address)
+            .map_err(|e| format!("Failed to connect: {}", e))?;
+        if let Some(service) = service {
+            builder = builder
+                .name(service.to_string())
+                .map_err(|e| format!("Invalid service name: {}", e))?;
+        }
+        let conn = builder
+            .build()
+            .map_err(|e| format!("Failed to connect: {}", e))?;
+        Ok(Self {
+            conn,
+            name: service.map(String::from),
+        })
+    }
+
+    fn get_prop(&self, service: &str, prop: &str) -> Result<OwnedValue, String> {
+        let reply = self
+            .conn
+            .call_method(Some(service), LIPC_PATH, Some(service), LIPC_GET, &(prop,))
+            .map_err(|e| format!("Failed to get {} {}: {}", service, prop, e))?;
+        reply
+            .body()
+            .deserialize::<OwnedValue>()
+            .map_err(|e| format!("Unexpected reply for {} {}: {}", service, prop, e))
+    }
+
+    fn set_prop(&self, service: &str, prop: &str, value: Value) -> Result<(), String> {
+        self.conn
+            .call_method(
+                Some(service),
+                LIPC_PATH,
+                Some(service),
+                LIPC_SET,
+                &(prop, value),
+            )
+            .map_err(|e| format!("Failed to set {} {}: {}", service, prop, e))?;
+        Ok(())
+    }
+}
+
+/// LIPC params are ints or strings, anything else in the signal is ignored
+fn params(body: &Structure) -> Vec<LipcResult> {
+    body.fields()
+        .iter()
+        .filter_map(|f| match f {
+            Value::I32(v) => Some(LipcResult::NUM(*v)),
+            Value::Str(s) => Some(LipcResult::STR(s.to_string())),
+            _ => None,
+        })
+        .collect()
+}
+
+impl LipcBackend for DbusLipc {
+    fn subscribe(
+        &self,
+        service: &str,
+        name: Option<&str>,
+        mut callback: LipcCallback,
+    ) -> Result<(), String> {
+        self.subscribe_events(
+            service,
+            name,
+            Box::new(move |ev| callback(&ev.source, &ev.name, ev.param())),
+        )
+    }
+
+    fn subscribe_events(
+        &self,
+        service: &str,
+        name: Option<&str>,
+        mut callback: EventHandler,
+    ) -> Result<(), String> {
+        let mut rule = MatchRule::builder()
+            .msg_type(Type::Signal)
+            .path(LIPC_PATH)
+            .and_then(|r| r.interface(service.to_string()))
+            .map_err(|e| format!("Failed to subscribe: {}", e))?;
+        if let Some(name) = name {
+            rule = rule
+                .member(name.to_string())
+                .map_err(|e| format!("Failed to subscribe: {}", e))?;
+        }
+        let iter = MessageIterator::for_match_rule(rule.build(), &self.conn, None)
+            .map_err(|e| format!("Failed to subscribe: {}", e))?;
+
+        let service = service.to_string();
+        std::thread::spawn(move || {
+            for msg in iter {
+                let msg = match msg {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        error!("Error receiving event: {}", e);
+                        continue;
+                    }
+                };
+                let header = msg.header();
+                let name = match header.member() {
+                    Some(name) => name.to_string(),
+                    None => continue,
+                };
+                let body = msg.body();
+                let params = if body.is_empty() {
+                    vec![]
+                } else {
+                    match body.deserialize::<Structure>() {
+                        Ok(s) => params(&s),
+                        Err(e) => {
+                            error!("Error decoding params of {}: {}", name, e);
+                            vec![]
+                        }
+                    }
+                };
+                callback(&LipcEvent {
+                    source: service.clone(),
+                    name,
+                    params,
+                });
+            }
+        });
+        Ok(())
+    }
+
+    fn get_int_prop(&self, service: &str, prop: &str) -> Result<i32, String> {
+        match &*self.get_prop(service, prop)? {
+            Value::I32(v) => Ok(*v),
+            v => Err(format!(
+                "{} {} is not an int property: {:?}",
+                service, prop, v
+            )),
+        }
+    }
+
+    fn get_str_prop(&self, service: &str, prop: &str) -> Result<String, String> {
+        match &*self.get_prop(service, prop)? {
+            Value::Str(v) => Ok(v.to_string()),
+            v => Err(format!(
+                "{} {} is not a string property: {:?}",
+                service, prop, v
+            )),
+        }
+    }
+
+    fn set_int_prop(&self, service: &str, prop: &str, value: i32) -> Result<(), String> {
+        self.set_prop(service, prop, Value::I32(value))
+    }
+
+    fn set_str_prop(&self, service: &str, prop: &str, value: &str) -> Result<(), String> {
+        self.set_prop(service, prop, Value::from(value))
+    }
+
+    fn send_event(&self, name: &str, params: &[LipcResult]) -> Result<(), String> {
+        let service = self
+            .name
+            .as_deref()
+            .ok_or("Failed to create event, is the connection named?")?;
+
+        let result = if params.is_empty() {
+            self.conn
+                .emit_signal(None::<&str>, LIPC_PATH, service, name, &())
+        } else {
+            let body = params
+                .iter()
+                .fold(StructureBuilder::new(), |b, p| match p {
+                    LipcResult::NUM(v) => b.add_field(*v),
+                    LipcResult::STR(v) => b.add_field(v.as_str()),
+                })
+                .build()
+                .map_err(|e| format!("Failed to create event: {}", e))?;
+            self.conn
+                .emit_signal(None::<&str>, LIPC_PATH, service, name, &body)
+        };
+        result.map_err(|e| format!("Failed to send event: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::convert::TryInto;
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    /// A private session bus, killed on drop
+    struct TestBus {
+        daemon: Child,
+        address: String,
+    }
+
+    impl TestBus {
+        fn start() -> Option<TestBus> {
+            let mut daemon = Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--nopidfile", "--print-address"])
+                .stdout(Stdio::piped())
+                .spawn()
+                .ok()?;
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.as_mut().unwrap())
+                .read_line(&mut address)
+                .ok()?;
+            Some(TestBus {
+                daemon,
+                address: address.trim().to_string(),
+            })
+        }
+    }
+
+    impl Drop for TestBus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    /// Serves `props` as `service` the way the Kindle services do
+    fn fake_service(address: &str, service: &str, mut props: HashMap<String, OwnedValue>) {
+        let conn = zbus::blocking::connection::Builder::address(address)
+            .unwrap()
+            .name(service.to_string())
+            .unwrap()
+            .build()
+            .unwrap();
+        std::thread::spawn(move || {
+            for msg in MessageIterator::from(&conn) {
+                let msg = msg.unwrap();
+                let header = msg.header();
+                if header.message_type() != Type::MethodCall {
+                    continue;
+                }
+                let member = header.member().map(|m| m.to_string());
+                match member.as_deref() {
+                    Some(LIPC_GET) => {
+                        let (prop,): (String,) = msg.body().deserialize().unwrap();
+                        match props.get(&prop) {
+                            Some(v) => conn.reply(&header, v).unwrap(),
+                            None => conn
+                                .reply_error(&header, "com.lab126.NoSuchProperty", &(prop,))
+                                .unwrap(),
+                        }
+                    }
+                    Some(LIPC_SET) => {
+                        let (prop, value): (String, OwnedValue) = msg.body().deserialize().unwrap();
+                        props.insert(prop, value);
+                        conn.reply(&header, &()).unwrap();
+                    }
+                    _ => (),
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn test_props() {
+        let bus = match TestBus::start() {
+            Some(bus) => bus,
+            None => {
+                println!("No dbus-daemon available, skipping");
+                return;
+            }
+        };
+        let mut props = HashMap::new();
+        props.insert(String::from("battLevel"), OwnedValue::from(67));
+        props.insert(
+            String::from("status"),
+            Value::from("charging").try_into().unwrap(),
+        );
+        fake_service(&bus.address, "com.lab126.powerd", props);
+
+        let r = DbusLipc::with_address(&bus.address, None).unwrap();
+        assert_eq!(r.get_int_prop("com.lab126.powerd", "battLevel"), Ok(67));
+        assert_eq!(
+            r.get_str_prop("com.lab126.powerd", "status"),
+            Ok(String::from("charging"))
+        );
+        assert!(r.get_str_prop("com.lab126.powerd", "battLevel").is_err());
+        assert!(r.get_int_prop("com.lab126.powerd", "nothing").is_err());
+
+        r.set_int_prop("com.lab126.powerd", "battLevel", 12)
+            .unwrap();
+        assert_eq!(r.get_int_prop("com.lab126.powerd", "battLevel"), Ok(12));
+        r.set_str_prop("com.lab126.powerd", "status", "full")
+            .unwrap();
+        assert_eq!(
+            r.get_str_prop("com.lab126.powerd", "status"),
+            Ok(String::from("full"))
+        );
+    }
+
+    #[test]
+    fn test_events() {
+        let bus = match TestBus::start() {
+            Some(bus) => bus,
+            None => {
+                println!("No dbus-daemon available, skipping");
+                return;
+            }
+        };
+        let powerd = DbusLipc::with_address(&bus.address, Some("com.lab126.powerd")).unwrap();
+        let r = DbusLipc::with_address(&bus.address, None).unwrap();
+
+        let (tx, rx) = channel();
+        r.subscribe(
+            "com.lab126.powerd",
+            Some("battLevelChanged"),
+            Box::new(move |source, name, res| {
+                tx.send((source.to_string(), name.to_string(), res))
+                    .unwrap()
+            }),
+        )
+        .unwrap();
+
+        powerd.send_event("goingToScreenSaver", &[]).unwrap();
+        powerd
+            .send_event(
+                "battLevelChanged",
+                &[LipcResult::STR(String::from("x")), LipcResult::NUM(12)],
+            )
+            .unwrap();
+
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+            (
+                String::from("com.lab126.powerd"),
+                String::from("battLevelChanged"),
+                Some(LipcResult::NUM(12))
+            )
+        );
+        assert!(r.send_event("unnamed", &[]).is_err());
+
+        let (tx, rx) = channel();
+        r.subscribe_events(
+            "com.lab126.powerd",
+            Some("battLevelChanged"),
+            Box::new(move |ev| tx.send(ev.params.clone()).unwrap()),
+        )
+        .unwrap();
+        powerd
+            .send_event(
+                "battLevelChanged",
+                &[LipcResult::STR(String::from("x")), LipcResult::NUM(13)],
+            )
+            .unwrap();
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+            vec![LipcResult::STR(String::from("x")), LipcResult::NUM(13)]
+        );
+    }
+}
@@ -0,0 +1,155 @@
+//! Events of the `catalog`, decoded into what they mean for the device
+
+use crate::catalog::{self, acxreaderplugin, appmgrd, powerd, wifid, Params};
+use crate::{LipcEvent, LipcResult};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum KindleEvent {
+    /// Percent
+    BatteryLevelChanged {
+        level: i32,
+    },
+    ChargerConnected,
+    ChargerDisconnected,
+    ScreenSaverEntered,
+    ScreenSaverExited,
+    /// The device will suspend unless something defers it
+    ReadyToSuspend,
+    Suspending,
+    Resuming,
+    WokeUp,
+    WifiConnected {
+        essid: Option<String>,
+    },
+    WifiDisconnected,
+    WifiScanComplete,
+    AppActivating {
+        app: String,
+    },
+    /// The `allReaderData` JSON document
+    ReaderData(String),
+    /// Not in the catalog, kept as received
+    Unknown(LipcEvent),
+}
+
+fn required_int(params: &Params, event: &str, name: &str) -> Result<i32, String> {
+    params
+        .int(name)
+        .ok_or_else(|| format!("{} without {}", event, name))
+}
+
+fn required_str(params: &Params, event: &str, name: &str) -> Result<String, String> {
+    params
+        .str(name)
+        .map(String::from)
+        .ok_or_else(|| format!("{} without {}", event, name))
+}
+
+impl KindleEvent {
+    /// Decodes the event `name` broadcasted by `source`. Events the catalog
+    /// does not know are `Unknown`; known ones are only an error when a param
+    /// they need is missing or of the wrong type, the others are not looked at.
+    pub fn decode(source: &str, name: &str, params: &[LipcResult]) -> Result<KindleEvent, String> {
+        let raw = LipcEvent {
+            source: source.to_string(),
+            name: name.to_string(),
+            params: params.to_vec(),
+        };
+        let spec = match catalog::event(source, name) {
+            Some(spec) => spec,
+            None => return Ok(KindleEvent::Unknown(raw)),
+        };
+        let p = spec.params(&raw);
+        let event = match *spec {
+            powerd::BATT_LEVEL_CHANGED => KindleEvent::BatteryLevelChanged {
+                level: required_int(&p, name, "level")?,
+            },
+            powerd::CHARGING => KindleEvent::ChargerConnected,
+            powerd::NOT_CHARGING => KindleEvent::ChargerDisconnected,
+            powerd::GOING_TO_SCREEN_SAVER => KindleEvent::ScreenSaverEntered,
+            powerd::OUT_OF_SCREEN_SAVER => KindleEvent::ScreenSaverExited,
+            powerd::READY_TO_SUSPEND => KindleEvent::ReadyToSuspend,
+            powerd::SUSPENDING => KindleEvent::Suspending,
+            powerd::RESUMING => KindleEvent::Resuming,
+            powerd::WAKEUP_FROM_SUSPEND => KindleEvent::WokeUp,
+            wifid::CM_CONNECTED => KindleEvent::WifiConnected {
+                essid: p.str("essid").map(String::from),
+            },
+            wifid::CM_INTF_NOT_AVAILABLE => KindleEvent::WifiDisconnected,
+            wifid::SCAN_COMPLETE => KindleEvent::WifiScanComplete,
+            appmgrd::APP_ACTIVATING => KindleEvent::AppActivating {
+                app: required_str(&p, name, "app")?,
+            },
+            acxreaderplugin::ALL_READER_DATA_EVENT => {
+                KindleEvent::ReaderData(required_str(&p, name, "data")?)
+            }
+            _ => KindleEvent::Unknown(raw),
+        };
+        Ok(event)
+    }
+
+    pub fn from_event(ev: &LipcEvent) -> Result<KindleEvent, String> {
+        Self::decode(&ev.source, &ev.name, &ev.params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let num = LipcResult::NUM;
+        let s = |v: &str| LipcResult::STR(v.to_string());
+
+        assert_eq!(
+            KindleEvent::decode(powerd::SERVICE, "battLevelChanged", &[num(67)]),
+            Ok(KindleEvent::BatteryLevelChanged { level: 67 })
+        );
+        assert!(KindleEvent::decode(powerd::SERVICE, "battLevelChanged", &[]).is_err());
+        assert!(KindleEvent::decode(powerd::SERVICE, "battLevelChanged", &[s("67")]).is_err());
+        assert_eq!(
+            KindleEvent::decode(powerd::SERVICE, "readyToSuspend", &[num(5)]),
+            Ok(KindleEvent::ReadyToSuspend)
+        );
+        assert_eq!(
+            KindleEvent::decode(powerd::SERVICE, "suspending", &[]),
+            Ok(KindleEvent::Suspending)
+        );
+        assert_eq!(
+            KindleEvent::decode(wifid::SERVICE, "cmConnected", &[]),
+            Ok(KindleEvent::WifiConnected { essid: None })
+        );
+        // A param of the wrong type only matters if it is needed
+        assert_eq!(
+            KindleEvent::decode(powerd::SERVICE, "outOfScreenSaver", &[s("1")]),
+            Ok(KindleEvent::ScreenSaverExited)
+        );
+        assert_eq!(
+            KindleEvent::decode(wifid::SERVICE, "cmConnected", &[num(0)]),
+            Ok(KindleEvent::WifiConnected { essid: None })
+        );
+        assert!(KindleEvent::decode(appmgrd::SERVICE, "appActivating", &[num(1)]).is_err());
+        assert_eq!(
+            KindleEvent::decode(
+                appmgrd::SERVICE,
+                "appActivating",
+                &[num(1), s("com.lab126.booklet.reader")]
+            ),
+            Ok(KindleEvent::AppActivating {
+                app: String::from("com.lab126.booklet.reader")
+            })
+        );
+
+        // Same name, other service
+        let unknown = LipcEvent {
+            source: String::from("com.example.other"),
+            name: String::from("suspending"),
+            params: vec![num(1)],
+        };
+        assert_eq!(
+            KindleEvent::from_event(&unknown),
+            Ok(KindleEvent::Unknown(unknown))
+        );
+    }
+}
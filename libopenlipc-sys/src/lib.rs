@@ -1,249 +1,125 @@
-#![allow(non_upper_case_globals)]
-#![allow(non_camel_case_types)]
-#![allow(non_snake_case)]
-#![allow(dead_code)]
-include!("./bindings.rs");
-
-use std::ffi::CStr;
-use std::ffi::CString;
-use std::os::raw::{c_char, c_int, c_void};
-
-pub struct rLIPC {
-    conn: *mut LIPC,
-}
-
-macro_rules! code_to_result {
-    ($value:expr) => {
-        if $value == LIPCcode_LIPC_OK {
-            Ok(())
-        } else {
-            Err(format!(
-                "Failed to subscribe: {}",
-                rLIPC::code_to_string($value)
-            ))
-        }
-    };
-}
-
-#[derive(Debug)]
+pub mod catalog;
+mod events;
+pub mod power;
+pub use events::KindleEvent;
+
+#[cfg(feature = "native")]
+mod native;
+#[cfg(feature = "native")]
+pub use native::rLIPC;
+
+#[cfg(feature = "dbus")]
+mod dbus;
+#[cfg(feature = "dbus")]
+pub use dbus::DbusLipc;
+
+#[cfg(feature = "mock")]
+mod mock;
+#[cfg(feature = "mock")]
+pub use mock::MockLipc;
+
+use catalog::{Event, HasharrayProp, IntProp, StrProp};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum LipcResult {
     NUM(i32),
     STR(String),
 }
 
-impl rLIPC {
-    /// Returns a new LIPC client if a connection was successful
-    /// Connects to the LIPC bus with no name.
-    pub fn new() -> Result<Self, String> {
-        let lipc;
-        unsafe {
-            lipc = LipcOpenNoName();
-        }
-        if lipc == (std::ptr::null_mut() as *mut c_void) {
-            return Err(String::from("Failed to open a connection!"));
-        }
-        Ok(Self { conn: lipc })
-    }
-
-    /// Register a callback for events broadcasted by `service`. Optionally,
-    /// you can filter to a single event by providing `name`.
-    ///
-    /// For callback, we pass (source, name, optional int param, optional str param).
-    /// an example callback payload would be
-    /// "com.lab126.appmgrd", "appActivating", Some(1), Some("com.lab126.booklet.reader")
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// use libopenlipc_sys::rLIPC;
-    /// let r = rLIPC::new().unwrap();
-    /// r.subscribe("com.lab126.powerd", Some("battLevelChanged"), |_, _, _, _| ());
-    /// // You will only get updates about battLevel in the callback
-    /// // battLevelChanged sends <int param> with the new battery value
-    /// ```
-    ///
-    /// ```
-    /// use libopenlipc_sys::rLIPC;
-    /// let r = rLIPC::new().unwrap();
-    /// r.subscribe("com.lab126.powerd", None, |_, _, _, _| ());
-    /// // You will get updates all power related events (screen on, off, etc)
-    /// ```
-    pub fn subscribe<F>(&self, service: &str, name: Option<&str>, callback: F) -> Result<(), String>
-    where
-        F: FnMut(&str, &str, Option<LipcResult>) + Send,
-    {
-        let _service = CString::new(service).unwrap();
-
-        let owned;
-        let c_name = match name {
-            None => std::ptr::null(),
-            Some(_name) => {
-                owned = CString::new(_name).unwrap();
-                owned.as_ptr()
-            }
-        };
-
-        let boxed_fn: Box<dyn FnMut(&str, &str, Option<LipcResult>) + Send> =
-            Box::new(callback) as _;
-        let double_box = Box::new(boxed_fn);
-        let ptr = Box::into_raw(double_box);
-        /*
-         * You can't pass a fn directly to C -- you can however pass a `Box::into_raw`
-         * This box however is of dynamic size and loses metadata -- so it's not easy to free later
-         * The other box (boxed_fn) is a fat pointer (which we can't pass to C) but it keeps
-         * metadata
-         * So we pass a thin pointer (into_raw) to a fat pointer (<dyn FnMut..>) to C
-         * then we have to undo this in the callback
-         */
+/// An event, with all of its params
+#[derive(Debug, Clone, PartialEq)]
+pub struct LipcEvent {
+    pub source: String,
+    pub name: String,
+    pub params: Vec<LipcResult>,
+}
 
-        let result;
-        unsafe {
-            /* We wait to cast to .as_ptr() here
-             * For a pointer to be valid, the thing it points to must still be around.
-             * For a value to exist past the expression it's introduced in, it must be bound to a variable.
-             * When the variable disappears, the value does too.
-             * We must store the CString for _service and c_name, then independently get pointers
-             * *to* them
-             */
-            result = code_to_result!(LipcSubscribeExt(
-                self.conn,
-                _service.as_ptr(),
-                c_name,
-                Some(ugly_callback),
-                ptr as *mut c_void,
-            ));
-        }
-        result
+impl LipcEvent {
+    /// The param handed to a `LipcCallback`: the first int param or, if there
+    /// are none, the first string param.
+    pub fn param(&self) -> Option<LipcResult> {
+        self.params
+            .iter()
+            .find(|p| matches!(p, LipcResult::NUM(_)))
+            .or_else(|| self.params.first())
+            .cloned()
     }
+}
 
-    /// Get the current value of a string property
-    /// ```
-    /// use libopenlipc_sys::rLIPC;
-    /// let r = rLIPC::new().unwrap();
-    /// let reader_status = r.get_str_prop("com.lab126.acxreaderplugin", "allReaderData").unwrap();
-    /// // reader_status would be a string containing JSON
-    /// ```
-    pub fn get_str_prop(&self, service: &str, prop: &str) -> Result<String, String> {
-        let mut handle: *mut c_char = std::ptr::null_mut();
-        let handle_ptr: *mut *mut c_char = &mut handle;
-
-        let service = CString::new(service).unwrap();
-        let prop = CString::new(prop).unwrap();
-        unsafe {
-            code_to_result!(LipcGetStringProperty(
-                self.conn,
-                service.as_ptr(),
-                prop.as_ptr(),
-                handle_ptr
-            ))?;
-        };
-
-        let val;
-        unsafe {
-            val = CStr::from_ptr(handle).to_str().unwrap().into();
-            // Made a copy, we can now free() the string
-            LipcFreeString(handle);
-        }
-        Ok(val)
+/// The value of a hasharray property: a list of hashes, each mapping names to
+/// ints or strings.
+pub type Hasharray = Vec<BTreeMap<String, LipcResult>>;
+
+/// Callback invoked for every event matching a subscription, with
+/// (source, event name, optional param).
+pub type LipcCallback = Box<dyn FnMut(&str, &str, Option<LipcResult>) + Send>;
+/// Callback invoked for every event matching a subscription, with all of the
+/// event's params.
+pub type EventHandler = Box<dyn FnMut(&LipcEvent) + Send>;
+
+/// The operations a LIPC client can perform. `rLIPC` implements it on top of
+/// `liblipc.so`; with the `mock` feature, `MockLipc` implements it in memory so
+/// code using the bus can be tested off-device.
+pub trait LipcBackend {
+    /// Register a callback for events broadcasted by `service`, optionally only
+    /// for the event `name`.
+    fn subscribe(
+        &self,
+        service: &str,
+        name: Option<&str>,
+        callback: LipcCallback,
+    ) -> Result<(), String>;
+    /// Same as `subscribe`, but the callback gets all of the event's params.
+    fn subscribe_events(
+        &self,
+        service: &str,
+        name: Option<&str>,
+        callback: EventHandler,
+    ) -> Result<(), String>;
+    fn get_int_prop(&self, service: &str, prop: &str) -> Result<i32, String>;
+    fn get_str_prop(&self, service: &str, prop: &str) -> Result<String, String>;
+    fn set_int_prop(&self, service: &str, prop: &str, value: i32) -> Result<(), String>;
+    fn set_str_prop(&self, service: &str, prop: &str, value: &str) -> Result<(), String>;
+    /// Broadcast the event `name` with `params`, in order.
+    fn send_event(&self, name: &str, params: &[LipcResult]) -> Result<(), String>;
+    /// Access the hasharray property `prop`, handing it `input`, which some
+    /// services read as a query. Blob values are left out.
+    fn access_hasharray_prop(
+        &self,
+        service: &str,
+        prop: &str,
+        input: Option<&Hasharray>,
+    ) -> Result<Hasharray, String> {
+        let _ = input;
+        Err(format!(
+            "Hasharray properties are not supported by this backend: {} {}",
+            service, prop
+        ))
     }
 
-    /// Get the current value of an int property
-    /// ```
-    /// use libopenlipc_sys::rLIPC;
-    /// let r = rLIPC::new().unwrap();
-    /// let reader_status = r.get_int_prop("com.lab126.powerd", "battLevel").unwrap();
-    /// // reader_status will contain the battery charge % (ie: 75).
-    /// ```
-    pub fn get_int_prop(&self, service: &str, prop: &str) -> Result<i32, String> {
-        let mut val: c_int = 0;
-        let service = CString::new(service).unwrap();
-        let prop = CString::new(prop).unwrap();
-        unsafe {
-            code_to_result!(LipcGetIntProperty(
-                self.conn,
-                service.as_ptr(),
-                prop.as_ptr(),
-                &mut val
-            ))?;
-        };
-
-        Ok(val)
+    /// Same as `get_int_prop`, for a property of the `catalog`
+    fn get_int(&self, prop: IntProp) -> Result<i32, String> {
+        self.get_int_prop(prop.service, prop.name)
     }
-
-    fn code_to_string(code: u32) -> String {
-        unsafe {
-            let cstr = CStr::from_ptr(LipcGetErrorString(code));
-            return String::from(cstr.to_str().unwrap());
-        }
+    fn get_str(&self, prop: StrProp) -> Result<String, String> {
+        self.get_str_prop(prop.service, prop.name)
     }
-}
-
-unsafe extern "C" fn ugly_callback(
-    _: *mut LIPC,
-    name: *const c_char,
-    event: *mut LIPCevent,
-    data: *mut c_void,
-) -> LIPCcode {
-    // Can't unwrap in this function
-    let source = LipcGetEventSource(event);
-    let _name = CStr::from_ptr(name).to_str().unwrap();
-    let _source = CStr::from_ptr(source).to_str().unwrap();
-
-    let _int_param: Option<i32>;
-    let _str_param: Option<String>;
-
-    {
-        let mut int_param: c_int = 0;
-        _int_param = match ReturnCodes::from_u32(LipcGetIntParam(event, &mut int_param)).unwrap() {
-            ReturnCodes::OK => Some(int_param),
-            ReturnCodes::ERROR_NO_SUCH_PARAM => None,
-            e => {
-                println!(
-                    "Error getting int param: {}",
-                    rLIPC::code_to_string(e as u32)
-                );
-                None
-            }
-        }
+    fn set_int(&self, prop: IntProp, value: i32) -> Result<(), String> {
+        self.set_int_prop(prop.service, prop.name, value)
     }
-
-    {
-        let mut handle: *mut c_char = std::ptr::null_mut();
-        let handle_ptr: *mut *mut c_char = &mut handle;
-        _str_param = match ReturnCodes::from_u32(LipcGetStringParam(event, handle_ptr)).unwrap() {
-            ReturnCodes::OK => {
-                let val = CStr::from_ptr(handle).to_str().unwrap().into();
-                Some(val)
-            }
-            ReturnCodes::ERROR_NO_SUCH_PARAM => None,
-            e => {
-                println!(
-                    "Error getting string param: {}",
-                    rLIPC::code_to_string(e as u32)
-                );
-                None
-            }
-        }
+    fn set_str(&self, prop: StrProp, value: &str) -> Result<(), String> {
+        self.set_str_prop(prop.service, prop.name, value)
     }
-
-    let f = data as *mut Box<dyn FnMut(&str, &str, Option<LipcResult>) + Send>;
-    let _res = if let Some(val) = _int_param {
-        Some(LipcResult::NUM(val))
-    } else {
-        _str_param.map(LipcResult::STR)
-    };
-
-    (*f)(_source, _name, _res);
-    0
-}
-
-impl Drop for rLIPC {
-    fn drop(&mut self) {
-        unsafe {
-            LipcClose(self.conn);
-        }
-        println!("Disconnected");
+    fn access_hasharray(
+        &self,
+        prop: HasharrayProp,
+        input: Option<&Hasharray>,
+    ) -> Result<Hasharray, String> {
+        self.access_hasharray_prop(prop.service, prop.name, input)
+    }
+    /// Same as `subscribe_events`, for an event of the `catalog`
+    fn subscribe_to(&self, event: Event, callback: EventHandler) -> Result<(), String> {
+        self.subscribe_events(event.service, Some(event.name), callback)
     }
 }
-
-unsafe impl Sync for rLIPC {}
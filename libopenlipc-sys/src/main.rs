@@ -1,34 +1,542 @@
-use libopenlipc_sys::rLIPC;
+use libopenlipc_sys::{Hasharray, LipcBackend, LipcEvent, LipcResult};
+use std::collections::BTreeMap;
 use std::io::{self, Write};
+use std::process::exit;
+use std::sync::mpsc;
+use std::time::Duration;
+
+const USAGE: &str = "Usage:
+    lipc-probe [--json] get [-i|-s] <service> <property>
+    lipc-probe set [-i|-s] <service> <property> <value>
+    lipc-probe [--json] hash <service> <property> [<key>=<value>...]
+    lipc-probe [--json] wait [-t <secs>] <service> [<event>...]
+    lipc-probe send <source> <event> [<param>...]
+    lipc-probe [--json] watch [-e <event>]... <service>...
+
+get and set guess the type of the property unless -i (int) or -s (string) is
+given. hash accesses a hasharray property, handing it a single hash made of the
+given keys, if any. wait prints the first of the events (or any event of the
+service) and fails after -t seconds. send broadcasts an event as <source>.
+watch prints events until interrupted, only the -e ones if given.
+
+Values that look like ints are sent as ints. With --json, every output is a
+single line of JSON.";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Guess,
+    Int,
+    Str,
+}
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Get {
+        kind: Kind,
+        service: String,
+        property: String,
+    },
+    Set {
+        kind: Kind,
+        service: String,
+        property: String,
+        value: String,
+    },
+    Hash {
+        service: String,
+        property: String,
+        input: Option<Hasharray>,
+    },
+    Wait {
+        timeout: Option<Duration>,
+        service: String,
+        events: Vec<String>,
+    },
+    Send {
+        source: String,
+        event: String,
+        params: Vec<LipcResult>,
+    },
+    Watch {
+        events: Vec<String>,
+        services: Vec<String>,
+    },
+}
+
+/// An int if it looks like one
+fn param(s: &str) -> LipcResult {
+    match s.parse() {
+        Ok(n) => LipcResult::NUM(n),
+        Err(_) => LipcResult::STR(s.to_string()),
+    }
+}
+
+/// Returns whether `--json` was given, and the command
+fn parse(args: &[String]) -> Result<(bool, Command), String> {
+    let json = args.first().is_some_and(|a| a == "--json");
+    let args = if json { &args[1..] } else { args };
+
+    // Flags of the command, then its positional arguments
+    let mut kind = Kind::Guess;
+    let mut timeout = None;
+    let mut events = vec![];
+    let mut rest = vec![];
+    let mut it = args.iter().skip(1);
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "-i" => kind = Kind::Int,
+            "-s" => kind = Kind::Str,
+            "-t" => {
+                let secs = it
+                    .next()
+                    .and_then(|s| s.parse().ok())
+                    .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                    .ok_or("-t needs a number of seconds")?;
+                timeout = Some(secs);
+            }
+            "-e" => events.push(it.next().ok_or("-e needs an event")?.clone()),
+            _ => rest.push(arg.clone()),
+        }
+    }
+
+    let command = match (args.first().map(String::as_str), rest.as_slice()) {
+        (Some("get"), [service, property]) => Command::Get {
+            kind,
+            service: service.clone(),
+            property: property.clone(),
+        },
+        (Some("set"), [service, property, value]) => {
+            if kind == Kind::Int && value.parse::<i32>().is_err() {
+                return Err(format!("Not an int: {}", value));
+            }
+            Command::Set {
+                kind,
+                service: service.clone(),
+                property: property.clone(),
+                value: value.clone(),
+            }
+        }
+        (Some("hash"), [service, property, pairs @ ..]) => {
+            let mut hash = BTreeMap::new();
+            for pair in pairs {
+                let (key, value) = pair
+                    .split_once('=')
+                    .ok_or_else(|| format!("Expected <key>=<value>: {}", pair))?;
+                hash.insert(key.to_string(), param(value));
+            }
+            Command::Hash {
+                service: service.clone(),
+                property: property.clone(),
+                input: if hash.is_empty() {
+                    None
+                } else {
+                    Some(vec![hash])
+                },
+            }
+        }
+        (Some("wait"), [service, names @ ..]) => Command::Wait {
+            timeout,
+            service: service.clone(),
+            events: names.to_vec(),
+        },
+        (Some("send"), [source, event, params @ ..]) => Command::Send {
+            source: source.clone(),
+            event: event.clone(),
+            params: params.iter().map(|p| param(p)).collect(),
+        },
+        (Some("watch"), services) if !services.is_empty() => Command::Watch {
+            events,
+            services: services.to_vec(),
+        },
+        _ => return Err(String::from(USAGE)),
+    };
+    Ok((json, command))
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_value(value: &LipcResult) -> String {
+    match value {
+        LipcResult::NUM(n) => n.to_string(),
+        LipcResult::STR(s) => json_string(s),
+    }
+}
+
+fn json_hasharray(hashes: &Hasharray) -> String {
+    let hashes: Vec<String> = hashes
+        .iter()
+        .map(|hash| {
+            let fields: Vec<String> = hash
+                .iter()
+                .map(|(k, v)| format!("{}:{}", json_string(k), json_value(v)))
+                .collect();
+            format!("{{{}}}", fields.join(","))
+        })
+        .collect();
+    format!("[{}]", hashes.join(","))
+}
+
+fn json_event(ev: &LipcEvent) -> String {
+    let params: Vec<String> = ev.params.iter().map(json_value).collect();
+    format!(
+        "{{\"source\":{},\"name\":{},\"params\":[{}]}}",
+        json_string(&ev.source),
+        json_string(&ev.name),
+        params.join(",")
+    )
+}
+
+/// `{ essid = "home", id = 1 }`, like lipc-hash-prop
+fn plain_hash(hash: &BTreeMap<String, LipcResult>) -> String {
+    let fields: Vec<String> = hash
+        .iter()
+        .map(|(k, v)| format!("{} = {}", k, json_value(v)))
+        .collect();
+    format!("{{ {} }}", fields.join(", "))
+}
+
+/// `[com.lab126.powerd] battLevelChanged 67`
+fn plain_event(ev: &LipcEvent) -> String {
+    let mut out = format!("[{}] {}", ev.source, ev.name);
+    for p in &ev.params {
+        match p {
+            LipcResult::NUM(n) => out.push_str(&format!(" {}", n)),
+            LipcResult::STR(s) => out.push_str(&format!(" {}", s)),
+        }
+    }
+    out
+}
+
+fn event_line(ev: &LipcEvent, json: bool) -> String {
+    if json {
+        json_event(ev)
+    } else {
+        plain_event(ev)
+    }
+}
+
+fn get(bus: &dyn LipcBackend, kind: Kind, service: &str, prop: &str) -> Result<LipcResult, String> {
+    match kind {
+        Kind::Int => bus.get_int_prop(service, prop).map(LipcResult::NUM),
+        Kind::Str => bus.get_str_prop(service, prop).map(LipcResult::STR),
+        Kind::Guess => bus
+            .get_int_prop(service, prop)
+            .map(LipcResult::NUM)
+            .or_else(|_| bus.get_str_prop(service, prop).map(LipcResult::STR)),
+    }
+}
+
+/// Runs every command but watch, which never returns
+fn execute(
+    bus: &dyn LipcBackend,
+    json: bool,
+    command: &Command,
+    out: &mut dyn Write,
+) -> Result<(), String> {
+    let line = match command {
+        Command::Get {
+            kind,
+            service,
+            property,
+        } => {
+            let value = get(bus, *kind, service, property)?;
+            match (json, value) {
+                (true, v) => format!(
+                    "{{\"service\":{},\"property\":{},\"value\":{}}}",
+                    json_string(service),
+                    json_string(property),
+                    json_value(&v)
+                ),
+                (false, LipcResult::NUM(n)) => n.to_string(),
+                (false, LipcResult::STR(s)) => s,
+            }
+        }
+        Command::Set {
+            kind,
+            service,
+            property,
+            value,
+        } => {
+            match (kind, param(value)) {
+                (Kind::Str, _) | (Kind::Guess, LipcResult::STR(_)) => {
+                    bus.set_str_prop(service, property, value)?
+                }
+                (_, LipcResult::NUM(n)) => bus.set_int_prop(service, property, n)?,
+                (Kind::Int, LipcResult::STR(_)) => unreachable!(),
+            }
+            return Ok(());
+        }
+        Command::Hash {
+            service,
+            property,
+            input,
+        } => {
+            let hashes = bus.access_hasharray_prop(service, property, input.as_ref())?;
+            if json {
+                json_hasharray(&hashes)
+            } else {
+                let lines: Vec<String> = hashes.iter().map(plain_hash).collect();
+                lines.join("\n")
+            }
+        }
+        Command::Wait {
+            timeout,
+            service,
+            events,
+        } => {
+            let (tx, rx) = mpsc::channel();
+            let names: Vec<Option<&str>> = if events.is_empty() {
+                vec![None]
+            } else {
+                events.iter().map(|e| Some(e.as_str())).collect()
+            };
+            for name in names {
+                let tx = tx.clone();
+                bus.subscribe_events(
+                    service,
+                    name,
+                    Box::new(move |ev| {
+                        let _ = tx.send(ev.clone());
+                    }),
+                )?;
+            }
+            let ev = match timeout {
+                Some(t) => rx
+                    .recv_timeout(*t)
+                    .map_err(|_| format!("No event from {} after {:?}", service, t))?,
+                None => rx.recv().map_err(|e| e.to_string())?,
+            };
+            event_line(&ev, json)
+        }
+        Command::Send { event, params, .. } => {
+            bus.send_event(event, params)?;
+            return Ok(());
+        }
+        Command::Watch { .. } => unreachable!(),
+    };
+    writeln!(out, "{}", line).map_err(|e| e.to_string())
+}
+
+fn watch(
+    bus: &dyn LipcBackend,
+    json: bool,
+    events: &[String],
+    services: &[String],
+) -> Result<(), String> {
+    let names: Vec<Option<&str>> = if events.is_empty() {
+        vec![None]
+    } else {
+        events.iter().map(|e| Some(e.as_str())).collect()
+    };
+    for service in services {
+        for name in &names {
+            bus.subscribe_events(
+                service,
+                *name,
+                Box::new(move |ev| {
+                    let mut stdout = io::stdout();
+                    let _ = writeln!(stdout, "{}", event_line(ev, json));
+                    let _ = stdout.flush();
+                }),
+            )?;
+        }
+    }
+    loop {
+        std::thread::sleep(Duration::from_secs(60));
+    }
+}
+
+#[cfg(feature = "native")]
+fn connect(name: Option<&str>) -> Result<Box<dyn LipcBackend>, String> {
+    use libopenlipc_sys::rLIPC;
+    Ok(Box::new(match name {
+        Some(name) => rLIPC::with_name(name)?,
+        None => rLIPC::new()?,
+    }))
+}
+
+#[cfg(all(feature = "dbus", not(feature = "native")))]
+fn connect(name: Option<&str>) -> Result<Box<dyn LipcBackend>, String> {
+    use libopenlipc_sys::DbusLipc;
+    Ok(Box::new(match name {
+        Some(name) => DbusLipc::with_name(name)?,
+        None => DbusLipc::new()?,
+    }))
+}
+
+#[cfg(not(any(feature = "native", feature = "dbus")))]
+fn connect(_name: Option<&str>) -> Result<Box<dyn LipcBackend>, String> {
+    Err(String::from(
+        "Built without a backend, enable the `native` or `dbus` feature",
+    ))
+}
 
 fn main() {
-    let r = rLIPC::new().unwrap();
-    let batt = r.get_int_prop("com.lab126.powerd", "battLevel").unwrap();
-    println!("Battery: {}", batt);
-    let batt_t = r.get_int_prop("com.lab126.powerd", "battTemperature");
-    match batt_t {
-        Ok(temp) => println!("BatteryTemp: {}", temp),
-        Err(err) => println!("Failed to get temp: {}", err),
-    }
-
-    println!("Subscribing to ALL power events");
-    let res = r.subscribe("com.lab126.powerd", None, |a, b, res| {
-        println!("[{}] {} || {:?}", a, b, res);
-
-        let reader_status = r
-            .get_str_prop("com.lab126.acxreaderplugin", "allReaderData")
-            .unwrap();
-        println!("allreader data: {}", reader_status);
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (json, command) = parse(&args).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1);
+    });
+    // Events are sent from the connection's name
+    let name = match &command {
+        Command::Send { source, .. } => Some(source.as_str()),
+        _ => None,
+    };
+    let result = connect(name).and_then(|bus| match &command {
+        Command::Watch { events, services } => watch(&*bus, json, events, services),
+        _ => execute(&*bus, json, &command, &mut io::stdout()),
     });
-    if res.is_err() {
-        println!("Failed to subscribe!! {:?}", res);
-        return;
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        exit(1);
     }
+}
 
-    println!("Subscribed, ctrl-c to stop");
-    loop {
-        std::thread::sleep(std::time::Duration::from_secs(1));
-        print!(".");
-        io::stdout().flush().unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &str) -> Result<(bool, Command), String> {
+        let args: Vec<String> = s.split_whitespace().map(String::from).collect();
+        parse(&args)
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            args("--json get -i com.lab126.powerd battLevel").unwrap(),
+            (
+                true,
+                Command::Get {
+                    kind: Kind::Int,
+                    service: String::from("com.lab126.powerd"),
+                    property: String::from("battLevel"),
+                }
+            )
+        );
+        assert_eq!(
+            args("wait -t 1.5 com.lab126.powerd goingToScreenSaver")
+                .unwrap()
+                .1,
+            Command::Wait {
+                timeout: Some(Duration::from_millis(1500)),
+                service: String::from("com.lab126.powerd"),
+                events: vec![String::from("goingToScreenSaver")],
+            }
+        );
+        assert_eq!(
+            args("send com.example.events done 1 ok").unwrap().1,
+            Command::Send {
+                source: String::from("com.example.events"),
+                event: String::from("done"),
+                params: vec![LipcResult::NUM(1), LipcResult::STR(String::from("ok"))],
+            }
+        );
+        match args("hash com.lab126.wifid scanList start=0 essid=home")
+            .unwrap()
+            .1
+        {
+            Command::Hash { input, .. } => {
+                let hash = &input.unwrap()[0];
+                assert_eq!(hash["start"], LipcResult::NUM(0));
+                assert_eq!(hash["essid"], LipcResult::STR(String::from("home")));
+            }
+            c => panic!("{:?}", c),
+        }
+
+        assert!(args("get com.lab126.powerd").is_err());
+        assert!(args("set -i com.lab126.powerd battLevel full").is_err());
+        assert!(args("hash com.lab126.wifid scanList start").is_err());
+        assert!(args("watch").is_err());
+        for secs in &["-1", "inf", "NaN"] {
+            let wait = format!("wait -t {} com.lab126.powerd", secs);
+            assert!(args(&wait).is_err(), "{}", secs);
+        }
+        assert!(args("").is_err());
+    }
+
+    #[test]
+    fn test_json() {
+        assert_eq!(json_string("a \"b\"\n\\"), r#""a \"b\"\n\\""#);
+        let ev = LipcEvent {
+            source: String::from("com.lab126.appmgrd"),
+            name: String::from("appActivating"),
+            params: vec![
+                LipcResult::NUM(1),
+                LipcResult::STR(String::from("com.lab126.booklet.reader")),
+            ],
+        };
+        assert_eq!(
+            json_event(&ev),
+            r#"{"source":"com.lab126.appmgrd","name":"appActivating","params":[1,"com.lab126.booklet.reader"]}"#
+        );
+        assert_eq!(
+            plain_event(&ev),
+            "[com.lab126.appmgrd] appActivating 1 com.lab126.booklet.reader"
+        );
+    }
+
+    #[cfg(feature = "mock")]
+    #[test]
+    fn test_execute() {
+        use libopenlipc_sys::MockLipc;
+
+        let bus = MockLipc::new();
+        let run = |json: bool, command: &str| {
+            let (_, command) = args(command).unwrap();
+            let mut out = vec![];
+            execute(&bus, json, &command, &mut out).map(|_| String::from_utf8(out).unwrap())
+        };
+
+        run(false, "set com.lab126.powerd battLevel 67").unwrap();
+        run(false, "set -s com.lab126.cmd wirelessEnable 1").unwrap();
+        assert_eq!(
+            run(false, "get com.lab126.powerd battLevel").unwrap(),
+            "67\n"
+        );
+        assert_eq!(
+            run(true, "get com.lab126.cmd wirelessEnable").unwrap(),
+            "{\"service\":\"com.lab126.cmd\",\"property\":\"wirelessEnable\",\"value\":\"1\"}\n"
+        );
+        assert!(run(false, "get -i com.lab126.cmd wirelessEnable").is_err());
+
+        let hash = [
+            (String::from("essid"), LipcResult::STR(String::from("home"))),
+            (String::from("id"), LipcResult::NUM(1)),
+        ]
+        .iter()
+        .cloned()
+        .collect();
+        bus.set_hasharray_prop("com.lab126.wifid", "profileData", vec![hash]);
+        assert_eq!(
+            run(false, "hash com.lab126.wifid profileData").unwrap(),
+            "{ essid = \"home\", id = 1 }\n"
+        );
+        assert_eq!(
+            run(true, "hash com.lab126.wifid profileData").unwrap(),
+            "[{\"essid\":\"home\",\"id\":1}]\n"
+        );
+
+        run(false, "send com.example.events done 1").unwrap();
+        assert_eq!(
+            bus.sent_events(),
+            vec![(String::from("done"), vec![LipcResult::NUM(1)])]
+        );
+        assert!(run(false, "wait -t 0.01 com.lab126.powerd goingToScreenSaver").is_err());
     }
 }
@@ -0,0 +1,322 @@
+use crate::{EventHandler, Hasharray, LipcBackend, LipcCallback, LipcEvent, LipcResult};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+struct Subscription {
+    service: String,
+    name: Option<String>,
+    callback: EventHandler,
+}
+
+/// In-memory LIPC bus, for testing code that talks to LIPC without a Kindle.
+/// Property values are scripted with `set_prop` and events are delivered to
+/// subscribers with `inject_event`. Events sent with `send_event` are recorded
+/// and can be inspected with `sent_events`.
+#[derive(Default)]
+pub struct MockLipc {
+    props: Mutex<HashMap<(String, String), LipcResult>>,
+    hasharrays: Mutex<HashMap<(String, String), Hasharray>>,
+    subscriptions: Mutex<Vec<Subscription>>,
+    sent: Mutex<Vec<(String, Vec<LipcResult>)>>,
+}
+
+impl MockLipc {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the value returned by `get_int_prop` / `get_str_prop` for `service`.`prop`
+    pub fn set_prop(&self, service: &str, prop: &str, value: LipcResult) {
+        self.props
+            .lock()
+            .unwrap()
+            .insert((service.to_string(), prop.to_string()), value);
+    }
+
+    /// Set the value returned by `access_hasharray_prop` for `service`.`prop`,
+    /// whatever the input
+    pub fn set_hasharray_prop(&self, service: &str, prop: &str, value: Hasharray) {
+        self.hasharrays
+            .lock()
+            .unwrap()
+            .insert((service.to_string(), prop.to_string()), value);
+    }
+
+    /// Deliver an event to every matching subscription, as if `source` had
+    /// broadcasted it. Returns the amount of callbacks that were called.
+    pub fn inject_event(&self, source: &str, name: &str, param: Option<LipcResult>) -> usize {
+        self.inject(&LipcEvent {
+            source: source.to_string(),
+            name: name.to_string(),
+            params: param.into_iter().collect(),
+        })
+    }
+
+    /// Same as `inject_event`, for events with any amount of params
+    pub fn inject(&self, event: &LipcEvent) -> usize {
+        // Callbacks are run without holding the lock, so they can subscribe or
+        // inject further events
+        let mut subs = std::mem::take(&mut *self.subscriptions.lock().unwrap());
+        let mut called = 0;
+        for sub in subs.iter_mut() {
+            if sub.service != event.source {
+                continue;
+            }
+            if sub.name.as_ref().is_some_and(|n| *n != event.name) {
+                continue;
+            }
+            (sub.callback)(event);
+            called += 1;
+        }
+
+        let mut current = self.subscriptions.lock().unwrap();
+        let added = std::mem::take(&mut *current);
+        *current = subs;
+        current.extend(added);
+        called
+    }
+
+    /// The (service, event name) pairs subscribed to so far; `None` means all
+    /// events of the service
+    pub fn subscriptions(&self) -> Vec<(String, Option<String>)> {
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|s| (s.service.clone(), s.name.clone()))
+            .collect()
+    }
+
+    /// Events sent through `send_event`, in order
+    pub fn sent_events(&self) -> Vec<(String, Vec<LipcResult>)> {
+        self.sent.lock().unwrap().clone()
+    }
+
+    fn get_prop(&self, service: &str, prop: &str) -> Result<LipcResult, String> {
+        self.props
+            .lock()
+            .unwrap()
+            .get(&(service.to_string(), prop.to_string()))
+            .cloned()
+            .ok_or_else(|| format!("No such property: {} {}", service, prop))
+    }
+}
+
+impl LipcBackend for MockLipc {
+    fn subscribe(
+        &self,
+        service: &str,
+        name: Option<&str>,
+        mut callback: LipcCallback,
+    ) -> Result<(), String> {
+        self.subscribe_events(
+            service,
+            name,
+            Box::new(move |ev| callback(&ev.source, &ev.name, ev.param())),
+        )
+    }
+
+    fn subscribe_events(
+        &self,
+        service: &str,
+        name: Option<&str>,
+        callback: EventHandler,
+    ) -> Result<(), String> {
+        self.subscriptions.lock().unwrap().push(Subscription {
+            service: service.to_string(),
+            name: name.map(String::from),
+            callback,
+        });
+        Ok(())
+    }
+
+    fn get_int_prop(&self, service: &str, prop: &str) -> Result<i32, String> {
+        match self.get_prop(service, prop)? {
+            LipcResult::NUM(val) => Ok(val),
+            LipcResult::STR(_) => Err(format!("{} {} is not an int property", service, prop)),
+        }
+    }
+
+    fn get_str_prop(&self, service: &str, prop: &str) -> Result<String, String> {
+        match self.get_prop(service, prop)? {
+            LipcResult::STR(val) => Ok(val),
+            LipcResult::NUM(_) => Err(format!("{} {} is not a string property", service, prop)),
+        }
+    }
+
+    fn set_int_prop(&self, service: &str, prop: &str, value: i32) -> Result<(), String> {
+        self.set_prop(service, prop, LipcResult::NUM(value));
+        Ok(())
+    }
+
+    fn set_str_prop(&self, service: &str, prop: &str, value: &str) -> Result<(), String> {
+        self.set_prop(service, prop, LipcResult::STR(value.to_string()));
+        Ok(())
+    }
+
+    fn send_event(&self, name: &str, params: &[LipcResult]) -> Result<(), String> {
+        self.sent
+            .lock()
+            .unwrap()
+            .push((name.to_string(), params.to_vec()));
+        Ok(())
+    }
+
+    fn access_hasharray_prop(
+        &self,
+        service: &str,
+        prop: &str,
+        _input: Option<&Hasharray>,
+    ) -> Result<Hasharray, String> {
+        self.hasharrays
+            .lock()
+            .unwrap()
+            .get(&(service.to_string(), prop.to_string()))
+            .cloned()
+            .ok_or_else(|| format!("No such property: {} {}", service, prop))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_scripted_props() {
+        let m = MockLipc::new();
+        m.set_prop("com.lab126.powerd", "battLevel", LipcResult::NUM(67));
+        m.set_prop(
+            "com.lab126.acxreaderplugin",
+            "allReaderData",
+            LipcResult::STR("{}".into()),
+        );
+
+        assert_eq!(m.get_int_prop("com.lab126.powerd", "battLevel"), Ok(67));
+        assert_eq!(
+            m.get_str_prop("com.lab126.acxreaderplugin", "allReaderData"),
+            Ok(String::from("{}"))
+        );
+        assert!(m.get_str_prop("com.lab126.powerd", "battLevel").is_err());
+        assert!(m
+            .get_int_prop("com.lab126.powerd", "battTemperature")
+            .is_err());
+
+        m.set_int_prop("com.lab126.powerd", "battLevel", 12)
+            .unwrap();
+        assert_eq!(m.get_int_prop("com.lab126.powerd", "battLevel"), Ok(12));
+    }
+
+    #[test]
+    fn test_inject_event() {
+        let m = MockLipc::new();
+        let seen = Arc::new(Mutex::new(vec![]));
+
+        let s = seen.clone();
+        m.subscribe(
+            "com.lab126.powerd",
+            Some("battLevelChanged"),
+            Box::new(move |source, name, res| {
+                s.lock()
+                    .unwrap()
+                    .push((source.to_string(), name.to_string(), res))
+            }),
+        )
+        .unwrap();
+        let s = seen.clone();
+        m.subscribe(
+            "com.lab126.powerd",
+            None,
+            Box::new(move |source, name, res| {
+                s.lock()
+                    .unwrap()
+                    .push((source.to_string(), name.to_string(), res))
+            }),
+        )
+        .unwrap();
+
+        assert_eq!(
+            m.inject_event(
+                "com.lab126.powerd",
+                "battLevelChanged",
+                Some(LipcResult::NUM(5))
+            ),
+            2
+        );
+        assert_eq!(
+            m.inject_event("com.lab126.powerd", "goingToScreenSaver", None),
+            1
+        );
+        assert_eq!(m.inject_event("com.lab126.wifid", "cmConnected", None), 0);
+        assert_eq!(seen.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_inject_params() {
+        let m = MockLipc::new();
+        let seen = Arc::new(Mutex::new(vec![]));
+
+        let s = seen.clone();
+        m.subscribe(
+            "com.lab126.appmgrd",
+            None,
+            Box::new(move |_, _, res| s.lock().unwrap().push(res)),
+        )
+        .unwrap();
+        let s = seen.clone();
+        m.subscribe_events(
+            "com.lab126.appmgrd",
+            None,
+            Box::new(move |ev| s.lock().unwrap().push(ev.params.first().cloned())),
+        )
+        .unwrap();
+
+        m.inject(&LipcEvent {
+            source: String::from("com.lab126.appmgrd"),
+            name: String::from("appActivating"),
+            params: vec![
+                LipcResult::STR(String::from("com.lab126.booklet.reader")),
+                LipcResult::NUM(1),
+            ],
+        });
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                Some(LipcResult::NUM(1)),
+                Some(LipcResult::STR(String::from("com.lab126.booklet.reader")))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sent_events() {
+        let m = MockLipc::new();
+        m.send_event("ping", &[LipcResult::NUM(1), LipcResult::STR("a".into())])
+            .unwrap();
+        assert_eq!(
+            m.sent_events(),
+            vec![(
+                String::from("ping"),
+                vec![LipcResult::NUM(1), LipcResult::STR("a".into())]
+            )]
+        );
+    }
+
+    #[test]
+    fn test_hasharray() {
+        let m = MockLipc::new();
+        let hash = [(String::from("id"), LipcResult::NUM(1))]
+            .iter()
+            .cloned()
+            .collect();
+        m.set_hasharray_prop("com.lab126.wifid", "profileData", vec![hash]);
+
+        let value = m
+            .access_hasharray_prop("com.lab126.wifid", "profileData", None)
+            .unwrap();
+        assert_eq!(value[0]["id"], LipcResult::NUM(1));
+        assert!(m
+            .access_hasharray_prop("com.lab126.wifid", "scanList", None)
+            .is_err());
+    }
+}
@@ -0,0 +1,631 @@
+#![allow(non_upper_case_globals)]
+#![allow(non_camel_case_types)]
+#![allow(non_snake_case)]
+#![allow(dead_code)]
+include!("./bindings.rs");
+
+use crate::{EventHandler, Hasharray, LipcBackend, LipcCallback, LipcEvent, LipcResult};
+use log::{debug, error};
+use std::collections::BTreeMap;
+use std::ffi::CStr;
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int, c_void};
+
+/// Same as `LipcCallback`, but the closure is allowed to borrow
+type Callback<'a> = Box<dyn FnMut(&str, &str, Option<LipcResult>) + Send + 'a>;
+/// Same as `EventHandler`, but the closure is allowed to borrow
+type EventCallback<'a> = Box<dyn FnMut(&LipcEvent) + Send + 'a>;
+
+pub struct rLIPC {
+    conn: *mut LIPC,
+}
+
+/// `Ok` for `LIPC_OK`, otherwise an error saying what failed, as given by the
+/// format string and arguments following the code
+macro_rules! code_to_result {
+    ($value:expr, $($what:tt)+) => {
+        match $value {
+            LIPCcode_LIPC_OK => Ok(()),
+            code => Err(format!(
+                "Failed to {}: {}",
+                format_args!($($what)+),
+                rLIPC::code_to_string(code)
+            )),
+        }
+    };
+}
+
+impl rLIPC {
+    /// Returns a new LIPC client if a connection was successful
+    /// Connects to the LIPC bus with no name.
+    pub fn new() -> Result<Self, String> {
+        let lipc;
+        unsafe {
+            lipc = LipcOpenNoName();
+        }
+        if lipc.is_null() {
+            return Err(String::from("Failed to open a connection!"));
+        }
+        Ok(Self { conn: lipc })
+    }
+
+    /// Returns a new LIPC client registered on the bus as `service`.
+    /// A named connection is required to send events, as the service name
+    /// becomes the source of the events.
+    pub fn with_name(service: &str) -> Result<Self, String> {
+        let service = CString::new(service).unwrap();
+        let lipc;
+        unsafe {
+            lipc = LipcOpen(service.as_ptr());
+        }
+        if lipc.is_null() {
+            return Err(String::from("Failed to open a connection!"));
+        }
+        Ok(Self { conn: lipc })
+    }
+
+    /// Register a callback for events broadcasted by `service`. Optionally,
+    /// you can filter to a single event by providing `name`.
+    ///
+    /// For callback, we pass (source, name, optional int param, optional str param).
+    /// an example callback payload would be
+    /// "com.lab126.appmgrd", "appActivating", Some(1), Some("com.lab126.booklet.reader")
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use libopenlipc_sys::rLIPC;
+    /// let r = rLIPC::new().unwrap();
+    /// r.subscribe("com.lab126.powerd", Some("battLevelChanged"), |_, _, _, _| ());
+    /// // You will only get updates about battLevel in the callback
+    /// // battLevelChanged sends <int param> with the new battery value
+    /// ```
+    ///
+    /// ```
+    /// use libopenlipc_sys::rLIPC;
+    /// let r = rLIPC::new().unwrap();
+    /// r.subscribe("com.lab126.powerd", None, |_, _, _, _| ());
+    /// // You will get updates all power related events (screen on, off, etc)
+    /// ```
+    pub fn subscribe<F>(&self, service: &str, name: Option<&str>, callback: F) -> Result<(), String>
+    where
+        F: FnMut(&str, &str, Option<LipcResult>) + Send,
+    {
+        let boxed_fn: Callback<'_> = Box::new(callback) as _;
+        let double_box = Box::new(boxed_fn);
+        let ptr = Box::into_raw(double_box);
+        /*
+         * You can't pass a fn directly to C -- you can however pass a `Box::into_raw`
+         * This box however is of dynamic size and loses metadata -- so it's not easy to free later
+         * The other box (boxed_fn) is a fat pointer (which we can't pass to C) but it keeps
+         * metadata
+         * So we pass a thin pointer (into_raw) to a fat pointer (<dyn FnMut..>) to C
+         * then we have to undo this in the callback
+         */
+        self.subscribe_ext(service, name, Some(ugly_callback), ptr as *mut c_void)
+    }
+
+    /// Same as `subscribe`, but the callback gets the event with all of its
+    /// params, in order.
+    ///
+    /// ```
+    /// use libopenlipc_sys::rLIPC;
+    /// let r = rLIPC::new().unwrap();
+    /// r.subscribe_events("com.lab126.appmgrd", None, |ev| println!("{:?}", ev.params));
+    /// // appActivating prints [NUM(1), STR("com.lab126.booklet.reader")]
+    /// ```
+    pub fn subscribe_events<F>(
+        &self,
+        service: &str,
+        name: Option<&str>,
+        callback: F,
+    ) -> Result<(), String>
+    where
+        F: FnMut(&LipcEvent) + Send,
+    {
+        // Same double boxing as in `subscribe`
+        let boxed_fn: EventCallback<'_> = Box::new(callback) as _;
+        let ptr = Box::into_raw(Box::new(boxed_fn));
+        self.subscribe_ext(service, name, Some(events_callback), ptr as *mut c_void)
+    }
+
+    fn subscribe_ext(
+        &self,
+        service: &str,
+        name: Option<&str>,
+        callback: LipcEventCallback,
+        data: *mut c_void,
+    ) -> Result<(), String> {
+        let _service = CString::new(service).unwrap();
+
+        let owned;
+        let c_name = match name {
+            None => std::ptr::null(),
+            Some(_name) => {
+                owned = CString::new(_name).unwrap();
+                owned.as_ptr()
+            }
+        };
+
+        let result;
+        unsafe {
+            /* We wait to cast to .as_ptr() here
+             * For a pointer to be valid, the thing it points to must still be around.
+             * For a value to exist past the expression it's introduced in, it must be bound to a variable.
+             * When the variable disappears, the value does too.
+             * We must store the CString for _service and c_name, then independently get pointers
+             * *to* them
+             */
+            result = code_to_result!(
+                LipcSubscribeExt(self.conn, _service.as_ptr(), c_name, callback, data),
+                "subscribe to {}",
+                service
+            );
+        }
+        result
+    }
+
+    /// Get the current value of a string property
+    /// ```
+    /// use libopenlipc_sys::rLIPC;
+    /// let r = rLIPC::new().unwrap();
+    /// let reader_status = r.get_str_prop("com.lab126.acxreaderplugin", "allReaderData").unwrap();
+    /// // reader_status would be a string containing JSON
+    /// ```
+    pub fn get_str_prop(&self, service: &str, prop: &str) -> Result<String, String> {
+        let mut handle: *mut c_char = std::ptr::null_mut();
+        let handle_ptr: *mut *mut c_char = &mut handle;
+
+        let c_service = CString::new(service).unwrap();
+        let c_prop = CString::new(prop).unwrap();
+        unsafe {
+            code_to_result!(
+                LipcGetStringProperty(self.conn, c_service.as_ptr(), c_prop.as_ptr(), handle_ptr),
+                "get {}.{}",
+                service,
+                prop
+            )?;
+        };
+
+        let val;
+        unsafe {
+            val = CStr::from_ptr(handle).to_str().unwrap().into();
+            // Made a copy, we can now free() the string
+            LipcFreeString(handle);
+        }
+        Ok(val)
+    }
+
+    /// Get the current value of an int property
+    /// ```
+    /// use libopenlipc_sys::rLIPC;
+    /// let r = rLIPC::new().unwrap();
+    /// let reader_status = r.get_int_prop("com.lab126.powerd", "battLevel").unwrap();
+    /// // reader_status will contain the battery charge % (ie: 75).
+    /// ```
+    pub fn get_int_prop(&self, service: &str, prop: &str) -> Result<i32, String> {
+        let mut val: c_int = 0;
+        let c_service = CString::new(service).unwrap();
+        let c_prop = CString::new(prop).unwrap();
+        unsafe {
+            code_to_result!(
+                LipcGetIntProperty(self.conn, c_service.as_ptr(), c_prop.as_ptr(), &mut val),
+                "get {}.{}",
+                service,
+                prop
+            )?;
+        };
+
+        Ok(val)
+    }
+
+    /// Set the value of an int property
+    /// ```
+    /// use libopenlipc_sys::rLIPC;
+    /// let r = rLIPC::new().unwrap();
+    /// r.set_int_prop("com.lab126.powerd", "preventScreenSaver", 1).unwrap();
+    /// ```
+    pub fn set_int_prop(&self, service: &str, prop: &str, value: i32) -> Result<(), String> {
+        let c_service = CString::new(service).unwrap();
+        let c_prop = CString::new(prop).unwrap();
+        unsafe {
+            code_to_result!(
+                LipcSetIntProperty(self.conn, c_service.as_ptr(), c_prop.as_ptr(), value),
+                "set {}.{}",
+                service,
+                prop
+            )
+        }
+    }
+
+    /// Set the value of a string property
+    pub fn set_str_prop(&self, service: &str, prop: &str, value: &str) -> Result<(), String> {
+        let c_service = CString::new(service).unwrap();
+        let c_prop = CString::new(prop).unwrap();
+        let c_value = CString::new(value).unwrap();
+        unsafe {
+            code_to_result!(
+                LipcSetStringProperty(
+                    self.conn,
+                    c_service.as_ptr(),
+                    c_prop.as_ptr(),
+                    c_value.as_ptr()
+                ),
+                "set {}.{}",
+                service,
+                prop
+            )
+        }
+    }
+
+    /// Broadcast the event `name` with `params`, in order. The source of the event
+    /// is the name of this connection, so the client must be created with
+    /// `rLIPC::with_name`.
+    /// ```
+    /// use libopenlipc_sys::{rLIPC, LipcResult};
+    /// let r = rLIPC::with_name("com.example.events").unwrap();
+    /// r.send_event("somethingHappened", &[LipcResult::NUM(1)]).unwrap();
+    /// ```
+    pub fn send_event(&self, name: &str, params: &[LipcResult]) -> Result<(), String> {
+        let c_name = CString::new(name).unwrap();
+        let event;
+        unsafe {
+            event = LipcNewEvent(self.conn, c_name.as_ptr());
+        }
+        if event.is_null() {
+            return Err(String::from(
+                "Failed to create event, is the connection named?",
+            ));
+        }
+
+        let mut result = Ok(());
+        for param in params {
+            result = match param {
+                LipcResult::NUM(val) => unsafe {
+                    code_to_result!(LipcAddIntParam(event, *val), "add a param to {}", name)
+                },
+                LipcResult::STR(val) => {
+                    let val = CString::new(val.as_str()).unwrap();
+                    unsafe {
+                        code_to_result!(
+                            LipcAddStringParam(event, val.as_ptr()),
+                            "add a param to {}",
+                            name
+                        )
+                    }
+                }
+            };
+            if result.is_err() {
+                break;
+            }
+        }
+
+        unsafe {
+            if result.is_ok() {
+                result = code_to_result!(LipcSendEvent(self.conn, event), "send {}", name);
+            }
+            LipcEventFree(event);
+        }
+        result
+    }
+
+    /// Access a hasharray property, handing it `input` if given. Blob values
+    /// are left out of the result.
+    /// ```
+    /// use libopenlipc_sys::rLIPC;
+    /// let r = rLIPC::new().unwrap();
+    /// let profiles = r.access_hasharray_prop("com.lab126.wifid", "profileData", None).unwrap();
+    /// // One hash per known network, with its essid, secured, etc.
+    /// ```
+    pub fn access_hasharray_prop(
+        &self,
+        service: &str,
+        prop: &str,
+        input: Option<&Hasharray>,
+    ) -> Result<Hasharray, String> {
+        let c_service = CString::new(service).unwrap();
+        let c_prop = CString::new(prop).unwrap();
+        unsafe {
+            let ha_in = match input {
+                Some(hashes) => new_hasharray(self.conn, hashes)?,
+                None => std::ptr::null_mut(),
+            };
+            let mut ha_out: *mut LIPCha = std::ptr::null_mut();
+            let result = code_to_result!(
+                LipcAccessHasharrayProperty(
+                    self.conn,
+                    c_service.as_ptr(),
+                    c_prop.as_ptr(),
+                    ha_in,
+                    &mut ha_out
+                ),
+                "access {}.{}",
+                service,
+                prop
+            );
+            if !ha_in.is_null() {
+                LipcHasharrayDestroy(ha_in);
+            }
+            result?;
+            if ha_out.is_null() {
+                return Ok(vec![]);
+            }
+            let out = read_hasharray(ha_out);
+            LipcHasharrayDestroy(ha_out);
+            out
+        }
+    }
+
+    fn code_to_string(code: u32) -> String {
+        unsafe {
+            let cstr = CStr::from_ptr(LipcGetErrorString(code));
+            String::from(cstr.to_str().unwrap())
+        }
+    }
+}
+
+unsafe extern "C" fn ugly_callback(
+    _: *mut LIPC,
+    name: *const c_char,
+    event: *mut LIPCevent,
+    data: *mut c_void,
+) -> LIPCcode {
+    // Can't unwrap in this function
+    let source = LipcGetEventSource(event);
+    let _name = CStr::from_ptr(name).to_str().unwrap();
+    let _source = CStr::from_ptr(source).to_str().unwrap();
+
+    let _int_param: Option<i32>;
+    let _str_param: Option<String>;
+
+    {
+        let mut int_param: c_int = 0;
+        _int_param = match ReturnCodes::from_u32(LipcGetIntParam(event, &mut int_param)).unwrap() {
+            ReturnCodes::OK => Some(int_param),
+            ReturnCodes::ERROR_NO_SUCH_PARAM => None,
+            e => {
+                error!(
+                    "Error getting int param: {}",
+                    rLIPC::code_to_string(e as u32)
+                );
+                None
+            }
+        }
+    }
+
+    {
+        let mut handle: *mut c_char = std::ptr::null_mut();
+        let handle_ptr: *mut *mut c_char = &mut handle;
+        _str_param = match ReturnCodes::from_u32(LipcGetStringParam(event, handle_ptr)).unwrap() {
+            ReturnCodes::OK => {
+                let val = CStr::from_ptr(handle).to_str().unwrap().into();
+                Some(val)
+            }
+            ReturnCodes::ERROR_NO_SUCH_PARAM => None,
+            e => {
+                error!(
+                    "Error getting string param: {}",
+                    rLIPC::code_to_string(e as u32)
+                );
+                None
+            }
+        }
+    }
+
+    let f = data as *mut LipcCallback;
+    let _res = if let Some(val) = _int_param {
+        Some(LipcResult::NUM(val))
+    } else {
+        _str_param.map(LipcResult::STR)
+    };
+
+    (*f)(_source, _name, _res);
+    0
+}
+
+/// Reads every param of `event`. Params are read in order, each one with
+/// whichever of LipcGetIntParam / LipcGetStringParam accepts it.
+unsafe fn read_params(event: *mut LIPCevent) -> Vec<LipcResult> {
+    let mut params = vec![];
+    loop {
+        let mut int_param: c_int = 0;
+        if LipcGetIntParam(event, &mut int_param) == LIPCcode_LIPC_OK {
+            params.push(LipcResult::NUM(int_param));
+            continue;
+        }
+
+        let mut handle: *mut c_char = std::ptr::null_mut();
+        if LipcGetStringParam(event, &mut handle) == LIPCcode_LIPC_OK {
+            params.push(LipcResult::STR(
+                CStr::from_ptr(handle).to_string_lossy().into_owned(),
+            ));
+            continue;
+        }
+        break;
+    }
+    params
+}
+
+/// Builds a hasharray out of `hashes`, to be destroyed by the caller
+unsafe fn new_hasharray(conn: *mut LIPC, hashes: &Hasharray) -> Result<*mut LIPCha, String> {
+    let ha = LipcHasharrayNew(conn);
+    if ha.is_null() {
+        return Err(String::from("Failed to create a hasharray"));
+    }
+    let mut result = Ok(());
+    for hash in hashes {
+        let mut index: size_t = 0;
+        result = code_to_result!(LipcHasharrayAddHash(ha, &mut index), "add a hash");
+        for (key, value) in hash {
+            if result.is_err() {
+                break;
+            }
+            let key = CString::new(key.as_str()).unwrap();
+            result = match value {
+                LipcResult::NUM(val) => {
+                    code_to_result!(
+                        LipcHasharrayPutInt(ha, index as c_int, key.as_ptr(), *val),
+                        "put {:?} in hash {}",
+                        key,
+                        index
+                    )
+                }
+                LipcResult::STR(val) => {
+                    let val = CString::new(val.as_str()).unwrap();
+                    code_to_result!(
+                        LipcHasharrayPutString(ha, index as c_int, key.as_ptr(), val.as_ptr()),
+                        "put {:?} in hash {}",
+                        key,
+                        index
+                    )
+                }
+            };
+        }
+        if result.is_err() {
+            break;
+        }
+    }
+    if let Err(e) = result {
+        LipcHasharrayDestroy(ha);
+        return Err(e);
+    }
+    Ok(ha)
+}
+
+/// Reads every int and string value of `ha`
+unsafe fn read_hasharray(ha: *mut LIPCha) -> Result<Hasharray, String> {
+    let count = LipcHasharrayGetHashCount(ha);
+    if count < 0 {
+        return Err(String::from("Failed to read the hasharray"));
+    }
+    let mut out = vec![];
+    for index in 0..count {
+        // Called with a count of 0, it only tells how many keys there are
+        let mut n: size_t = 0;
+        code_to_result!(
+            LipcHasharrayKeys(ha, index, std::ptr::null_mut(), &mut n),
+            "read the keys of hash {}",
+            index
+        )?;
+        let mut keys: Vec<*const c_char> = vec![std::ptr::null(); n as usize];
+        code_to_result!(
+            LipcHasharrayKeys(ha, index, keys.as_mut_ptr(), &mut n),
+            "read the keys of hash {}",
+            index
+        )?;
+
+        let mut hash = BTreeMap::new();
+        for key in keys.into_iter().take(n as usize) {
+            let mut kind: LIPCHasharrayType = 0;
+            let mut size: size_t = 0;
+            code_to_result!(
+                LipcHasharrayCheckKey(ha, index, key, &mut kind, &mut size),
+                "read hash {}",
+                index
+            )?;
+            let value = match kind {
+                LIPCHasharrayType_LIPC_HASHARRAY_INT => {
+                    let mut val: c_int = 0;
+                    code_to_result!(
+                        LipcHasharrayGetInt(ha, index, key, &mut val),
+                        "read hash {}",
+                        index
+                    )?;
+                    LipcResult::NUM(val)
+                }
+                LIPCHasharrayType_LIPC_HASHARRAY_STRING => {
+                    // Owned by the hasharray
+                    let mut val: *mut c_char = std::ptr::null_mut();
+                    code_to_result!(
+                        LipcHasharrayGetString(ha, index, key, &mut val),
+                        "read hash {}",
+                        index
+                    )?;
+                    LipcResult::STR(CStr::from_ptr(val).to_string_lossy().into_owned())
+                }
+                _ => continue,
+            };
+            hash.insert(CStr::from_ptr(key).to_string_lossy().into_owned(), value);
+        }
+        out.push(hash);
+    }
+    Ok(out)
+}
+
+unsafe extern "C" fn events_callback(
+    _: *mut LIPC,
+    name: *const c_char,
+    event: *mut LIPCevent,
+    data: *mut c_void,
+) -> LIPCcode {
+    let source = LipcGetEventSource(event);
+    let ev = LipcEvent {
+        source: CStr::from_ptr(source).to_string_lossy().into_owned(),
+        name: CStr::from_ptr(name).to_string_lossy().into_owned(),
+        params: read_params(event),
+    };
+
+    let f = data as *mut EventHandler;
+    (*f)(&ev);
+    0
+}
+
+impl Drop for rLIPC {
+    fn drop(&mut self) {
+        unsafe {
+            LipcClose(self.conn);
+        }
+        debug!("Disconnected");
+    }
+}
+
+unsafe impl Sync for rLIPC {}
+
+impl LipcBackend for rLIPC {
+    fn subscribe(
+        &self,
+        service: &str,
+        name: Option<&str>,
+        callback: LipcCallback,
+    ) -> Result<(), String> {
+        rLIPC::subscribe(self, service, name, callback)
+    }
+
+    fn subscribe_events(
+        &self,
+        service: &str,
+        name: Option<&str>,
+        callback: EventHandler,
+    ) -> Result<(), String> {
+        rLIPC::subscribe_events(self, service, name, callback)
+    }
+
+    fn get_int_prop(&self, service: &str, prop: &str) -> Result<i32, String> {
+        rLIPC::get_int_prop(self, service, prop)
+    }
+
+    fn get_str_prop(&self, service: &str, prop: &str) -> Result<String, String> {
+        rLIPC::get_str_prop(self, service, prop)
+    }
+
+    fn set_int_prop(&self, service: &str, prop: &str, value: i32) -> Result<(), String> {
+        rLIPC::set_int_prop(self, service, prop, value)
+    }
+
+    fn set_str_prop(&self, service: &str, prop: &str, value: &str) -> Result<(), String> {
+        rLIPC::set_str_prop(self, service, prop, value)
+    }
+
+    fn send_event(&self, name: &str, params: &[LipcResult]) -> Result<(), String> {
+        rLIPC::send_event(self, name, params)
+    }
+
+    fn access_hasharray_prop(
+        &self,
+        service: &str,
+        prop: &str,
+        input: Option<&Hasharray>,
+    ) -> Result<Hasharray, String> {
+        rLIPC::access_hasharray_prop(self, service, prop, input)
+    }
+}
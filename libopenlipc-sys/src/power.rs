@@ -0,0 +1,119 @@
+//! Keeping the screen on, and waking the device, on top of powerd's
+//! properties:
+//!
+//! ```no_run
+//! use libopenlipc_sys::power::Power;
+//! # fn f<B: libopenlipc_sys::LipcBackend>(r: &B) -> Result<(), String> {
+//! let power = Power::new(r);
+//! power.wake_up()?;
+//! let awake = power.keep_awake()?;
+//! // The screensaver stays away until `awake` is dropped
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::catalog::powerd;
+use crate::LipcBackend;
+
+pub struct Power<'a, B: LipcBackend> {
+    r: &'a B,
+}
+
+impl<'a, B: LipcBackend> Power<'a, B> {
+    pub fn new(r: &'a B) -> Self {
+        Power { r }
+    }
+
+    pub fn is_screen_saver_prevented(&self) -> Result<bool, String> {
+        Ok(self.r.get_int(powerd::PREVENT_SCREEN_SAVER)? != 0)
+    }
+
+    /// Stays on until set back, even across restarts of the caller: see
+    /// `keep_awake` for something that cleans up after itself
+    pub fn prevent_screen_saver(&self, prevent: bool) -> Result<(), String> {
+        self.r
+            .set_int(powerd::PREVENT_SCREEN_SAVER, if prevent { 1 } else { 0 })
+    }
+
+    /// Whether the screen is on, as opposed to showing the screensaver or
+    /// about to suspend
+    pub fn is_active(&self) -> Result<bool, String> {
+        Ok(self.r.get_str(powerd::STATE)? == "active")
+    }
+
+    /// Leaves the screensaver
+    pub fn wake_up(&self) -> Result<(), String> {
+        self.r.set_int(powerd::WAKE_UP, 1)
+    }
+
+    /// Starts the countdown to the screensaver over, as a touch would
+    pub fn reset_screen_saver_timer(&self) -> Result<(), String> {
+        self.r.set_int(powerd::TOUCH_SCREEN_SAVER_TIMEOUT, 1)
+    }
+
+    /// Goes to the screensaver if the screen is on, and leaves it otherwise
+    pub fn press_power_button(&self) -> Result<(), String> {
+        self.r.set_int(powerd::POWER_BUTTON, 1)
+    }
+
+    /// Holds off suspending for `secs` seconds; only honoured while powerd
+    /// is about to suspend
+    pub fn defer_suspend(&self, secs: i32) -> Result<(), String> {
+        self.r.set_int(powerd::DEFER_SUSPEND, secs)
+    }
+
+    /// Wakes the device from suspend after `secs` seconds
+    pub fn wake_in(&self, secs: i32) -> Result<(), String> {
+        self.r.set_int(powerd::RTC_WAKEUP, secs)
+    }
+
+    /// Prevents the screensaver until the returned guard is dropped, which
+    /// lets it come again. The setting found here is not what gets put
+    /// back: after a crash, it is the one left behind by the crashed guard.
+    pub fn keep_awake(&self) -> Result<KeepAwake<'a, B>, String> {
+        self.prevent_screen_saver(true)?;
+        Ok(KeepAwake { r: self.r })
+    }
+}
+
+/// The screensaver is prevented while this lives
+pub struct KeepAwake<'a, B: LipcBackend> {
+    r: &'a B,
+}
+
+impl<B: LipcBackend> Drop for KeepAwake<'_, B> {
+    fn drop(&mut self) {
+        // Nowhere to report a failure, the next drop or restart tries again
+        let _ = self.r.set_int(powerd::PREVENT_SCREEN_SAVER, 0);
+    }
+}
+
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    use super::*;
+    use crate::{LipcResult, MockLipc};
+
+    #[test]
+    fn test_keep_awake() {
+        let r = MockLipc::new();
+        let power = Power::new(&r);
+        let awake = power.keep_awake().unwrap();
+        assert!(power.is_screen_saver_prevented().unwrap());
+        drop(awake);
+        assert!(!power.is_screen_saver_prevented().unwrap());
+
+        // Left prevented by a guard that never got dropped, as after a crash
+        power.prevent_screen_saver(true).unwrap();
+        drop(power.keep_awake().unwrap());
+        assert!(!power.is_screen_saver_prevented().unwrap());
+
+        r.set_prop(
+            powerd::SERVICE,
+            "state",
+            LipcResult::STR(String::from("screenSaver")),
+        );
+        assert!(!power.is_active().unwrap());
+        power.wake_up().unwrap();
+        assert_eq!(r.get_int(powerd::WAKE_UP), Ok(1));
+    }
+}
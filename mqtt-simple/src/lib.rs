@@ -1,44 +1,35 @@
-#[cfg(test)]
-mod tests {
-    use super::*;
-    #[test]
-    fn test_bigendian_conversion() {
-        let expected = vec![0, 11];
-        assert_eq!(Protocol::to_big_endian(11), expected);
-    }
-    #[test]
-    fn test_connect_payload() {
-        let expected = vec![
-            16, 23, 0, 4, 77, 81, 84, 84, 4, 2, 0, 5, 0, 11, 99, 108, 105, 101, 110, 116, 95, 110,
-            97, 109, 101,
-        ];
-        assert_eq!(Protocol::connect_payload("client_name", 5), expected);
-    }
-
-    #[test]
-    fn test_publish_payload() {
-        let expected = vec![
-            48, 22, 0, 10, 115, 111, 109, 101, 95, 116, 111, 112, 105, 99, 109, 121, 32, 109, 101,
-            115, 115, 97, 103, 101,
-        ];
-        assert_eq!(
-            Protocol::publish_payload("some_topic", "my message", false, QoS::AtMostOnce, 0),
-            expected
-        );
-    }
-}
-
+use log::{debug, warn};
 use std::io::prelude::*;
 use std::io::{self, Read};
 use std::net::TcpStream;
+use std::time::{Duration, Instant};
 
 pub struct Client {
     name: String,
     server: std::net::SocketAddr,
+    will: Option<Will>,
+}
+
+/// What the broker publishes for a client that goes away without saying
+/// goodbye
+struct Will {
+    topic: String,
+    message: String,
+    retain: bool,
 }
 pub struct ConnectedClient {
     socket: TcpStream,
     pid: u16,
+    keepalive: u8,
+    /// The broker only counts what it gets from us towards the keepalive
+    last_write: Instant,
+}
+
+/// A message received on a subscribed topic
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Vec<u8>,
 }
 struct Protocol {}
 
@@ -68,27 +59,38 @@ impl Client {
         Ok(Client {
             name,
             server: format!("{}:1883", server).parse()?,
+            will: None,
         })
     }
 
+    /// Has the broker publish `message` to `topic` when the connection is
+    /// lost, rather than closed by dropping the `ConnectedClient`
+    pub fn set_will(&mut self, topic: &str, message: &str, retain: bool) {
+        self.will = Some(Will {
+            topic: topic.to_string(),
+            message: message.to_string(),
+            retain,
+        });
+    }
+
     pub fn connect(
         &mut self,
         keepalive: u8,
     ) -> Result<ConnectedClient, Box<dyn std::error::Error>> {
-        let payload = Protocol::connect_payload(self.name.as_ref(), keepalive);
+        let payload = Protocol::connect_payload(self.name.as_ref(), keepalive, self.will.as_ref());
         let mut stream =
             TcpStream::connect_timeout(&self.server, std::time::Duration::from_secs(3))?;
-        stream.write(payload.as_ref())?;
+        stream.write_all(payload.as_ref())?;
 
-        let mut buf = vec![0 as u8; 4];
+        let mut buf = [0u8; 4];
         stream.read_exact(&mut buf)?;
-        assert!(buf[0] == 0x20);
-        assert!(buf[1] == 0x02);
-        assert!(buf[3] == 0x00);
+        Protocol::parse_connack(&buf)?;
 
         Ok(ConnectedClient {
             socket: stream,
             pid: 0,
+            keepalive,
+            last_write: Instant::now(),
         })
     }
 }
@@ -101,67 +103,140 @@ impl ConnectedClient {
         qos: QoS,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let payload = Protocol::publish_payload(topic, msg, retain, qos, self.pid);
-        self.socket.write(payload.as_ref())?;
+        self.write(payload.as_ref())?;
         self.pid += 1;
 
-        self.drain_ping();
+        self.drain_ping()?;
         Ok(())
     }
 
-    fn drain_ping(&mut self) {
-        let mut buf: Vec<u8> = Vec::new();
-        self.socket.set_nonblocking(true).unwrap();
-        match self.socket.read_to_end(&mut buf) {
-            Ok(_) => (),
-            Err(e) => {
-                if e.kind() != io::ErrorKind::WouldBlock {
-                    panic!("{}", e)
+    /// Subscribes to `topic`, which can have wildcards. Messages are read
+    /// with `next_message`; QoS 2 is not supported.
+    pub fn subscribe(&mut self, topic: &str, qos: QoS) -> Result<(), Box<dyn std::error::Error>> {
+        if qos == QoS::ExactlyOnce {
+            return Err("QoS 2 subscriptions are not supported".into());
+        }
+        // 0 is not a valid packet id
+        self.pid = self.pid.wrapping_add(1).max(1);
+        let payload = Protocol::subscribe_payload(topic, qos, self.pid);
+        self.write(payload.as_ref())?;
+        Ok(())
+    }
+
+    /// Waits for the next message on the subscribed topics, pinging the
+    /// broker whenever nothing was sent to it for `keepalive` seconds, however
+    /// much is received
+    pub fn next_message(&mut self) -> Result<Message, Box<dyn std::error::Error>> {
+        loop {
+            if self.keepalive > 0 {
+                let due = self.last_write + Duration::from_secs(self.keepalive as u64);
+                let now = Instant::now();
+                if now >= due {
+                    self.write(&[0xc0, 0x0])?;
+                    continue;
                 }
+                self.socket.set_read_timeout(Some(due - now))?;
+            }
+            let mut header = [0u8; 1];
+            match self.socket.read_exact(&mut header) {
+                Ok(()) => (),
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    continue
+                }
+                Err(e) => return Err(e.into()),
+            }
+            // Once a packet started, the rest of it is on its way
+            self.socket.set_read_timeout(None)?;
+            let body = Protocol::read_body(&mut self.socket)?;
+            match header[0] >> 4 {
+                3 => {
+                    let (msg, pid) = Protocol::parse_publish(header[0], &body)?;
+                    if let Some(pid) = pid {
+                        let mut ack = vec![0x40, 0x2];
+                        ack.extend(Protocol::to_big_endian(pid));
+                        self.write(&ack)?;
+                    }
+                    return Ok(msg);
+                }
+                // SUBACK and PINGRESP
+                9 | 13 => (),
+                t => debug!("Ignoring packet of type {}", t),
             }
-        };
-        self.socket.set_nonblocking(false).unwrap();
-        if buf.len() > 0 {
-            println!("Stuff on the queue!! {:?}", buf);
         }
     }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.socket.write_all(bytes)?;
+        self.last_write = Instant::now();
+        Ok(())
+    }
+
+    /// Discards what the broker sent, such as ping responses, without
+    /// waiting for more
+    fn drain_ping(&mut self) -> io::Result<()> {
+        let mut buf: Vec<u8> = Vec::new();
+        self.socket.set_nonblocking(true)?;
+        let res = self.socket.read_to_end(&mut buf);
+        self.socket.set_nonblocking(false)?;
+        match res {
+            Ok(_) => (),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+            Err(e) => return Err(e),
+        }
+        if !buf.is_empty() {
+            debug!("Discarding {} unread bytes: {:?}", buf.len(), buf);
+        }
+        Ok(())
+    }
 }
 
 impl Drop for ConnectedClient {
     fn drop(&mut self) {
-        let res = self.socket.write(vec![0xe0, 0x0].as_ref());
-        if res.is_err() {
-            println!("Error disconnecting! {:?}", res);
+        if let Err(e) = self.socket.write_all(&[0xe0, 0x0]) {
+            warn!("Error disconnecting: {}", e);
         }
     }
 }
 impl Protocol {
-    fn connect_payload(client_id: &str, keepalive: u8) -> Vec<u8> {
+    fn connect_payload(client_id: &str, keepalive: u8, will: Option<&Will>) -> Vec<u8> {
         let mut premsg: Vec<u8> = Vec::new();
         premsg.push(0x10);
 
         let mut msg = vec![0x0, 0x4, b'M', b'Q', b'T', b'T', 4, 2, 0, 0];
 
-        let mut size: u8 = 10 + 2 + client_id.len() as u8;
+        let mut size = 10 + 2 + client_id.len();
         let clean_session = 1;
         msg[7] = clean_session << 1;
+        if let Some(will) = will {
+            size += 2 + will.topic.len() + 2 + will.message.len();
+            // Will flag, QoS 0
+            msg[7] |= 1 << 2 | (will.retain as u8) << 5;
+        }
 
-        // keepalive is u8 so keepalive >> 8 is always 0
+        // keepalive is u8 so keepalive >> 8 is always 0, leaving msg[8] alone,
         // and keepalive & 0xFF is always keepalive
-        msg[8] |= 0;
         msg[9] |= keepalive;
 
         while size > 0x7f {
-            println!("Size stuff {}", size);
-            premsg.push((size & 0x7f) | 0x0);
+            premsg.push(((size & 0x7f) | 0x80) as u8);
             size >>= 7;
         }
-        premsg.push(size);
+        premsg.push(size as u8);
 
         let mut payload: Vec<u8> = vec![];
         payload.extend(premsg);
         payload.extend(msg);
         payload.extend(Protocol::to_big_endian(client_id.len() as u16));
         payload.extend(client_id.as_bytes());
+        if let Some(will) = will {
+            payload.extend(Protocol::to_big_endian(will.topic.len() as u16));
+            payload.extend(will.topic.as_bytes());
+            payload.extend(Protocol::to_big_endian(will.message.len() as u16));
+            payload.extend(will.message.as_bytes());
+        }
         payload
     }
 
@@ -190,7 +265,260 @@ impl Protocol {
         pkt
     }
 
+    fn subscribe_payload(topic: &str, qos: QoS, pid: u16) -> Vec<u8> {
+        let mut pkt: Vec<u8> = vec![0x82];
+
+        let mut size = 2 + 2 + topic.len() + 1;
+        while size > 0x7f {
+            pkt.push(((size & 0x7f) | 0x80) as u8);
+            size >>= 7;
+        }
+        pkt.push(size as u8);
+        pkt.extend(Protocol::to_big_endian(pid));
+        pkt.extend(Protocol::to_big_endian(topic.len() as u16));
+        pkt.extend(topic.as_bytes());
+        pkt.push(qos as u8);
+        pkt
+    }
+
+    /// Whether a CONNACK accepts the connection, and why not otherwise
+    fn parse_connack(packet: &[u8; 4]) -> Result<(), String> {
+        if packet[0] != 0x20 || packet[1] != 0x02 {
+            return Err(format!("Expected a CONNACK, got {:?}", packet));
+        }
+        let reason = match packet[3] {
+            0 => return Ok(()),
+            1 => "unacceptable protocol version",
+            2 => "client id rejected",
+            3 => "server unavailable",
+            4 => "bad user name or password",
+            5 => "not authorized",
+            _ => "unknown reason",
+        };
+        Err(format!("Connection refused: {} ({})", reason, packet[3]))
+    }
+
+    /// Reads the remaining length of a packet, then that many bytes
+    fn read_body<R: Read>(r: &mut R) -> io::Result<Vec<u8>> {
+        let mut size = 0usize;
+        for shift in (0..28).step_by(7) {
+            let mut b = [0u8; 1];
+            r.read_exact(&mut b)?;
+            size |= ((b[0] & 0x7f) as usize) << shift;
+            if b[0] & 0x80 == 0 {
+                let mut body = vec![0u8; size];
+                r.read_exact(&mut body)?;
+                return Ok(body);
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Remaining length too long",
+        ))
+    }
+
+    /// The message in a PUBLISH packet, and its packet id if it has to be
+    /// acknowledged
+    fn parse_publish(header: u8, body: &[u8]) -> Result<(Message, Option<u16>), String> {
+        let qos = (header >> 1) & 0x3;
+        let malformed = || String::from("Malformed PUBLISH packet");
+        let topic_len = u16::from_be_bytes([
+            *body.first().ok_or_else(malformed)?,
+            *body.get(1).ok_or_else(malformed)?,
+        ]) as usize;
+        let topic = body.get(2..2 + topic_len).ok_or_else(malformed)?;
+        let topic = String::from_utf8(topic.to_vec()).map_err(|_| malformed())?;
+        let mut rest = &body[2 + topic_len..];
+        let mut pid = None;
+        if qos > 0 {
+            let id = rest.get(..2).ok_or_else(malformed)?;
+            pid = Some(u16::from_be_bytes([id[0], id[1]]));
+            rest = &rest[2..];
+        }
+        Ok((
+            Message {
+                topic,
+                payload: rest.to_vec(),
+            },
+            // Only QoS 1 is acknowledged with a PUBACK
+            pid.filter(|_| qos == 1),
+        ))
+    }
+
     fn to_big_endian(n: u16) -> Vec<u8> {
         vec![(n >> 8) as u8, (n & 0xFF) as u8]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_bigendian_conversion() {
+        let expected = vec![0, 11];
+        assert_eq!(Protocol::to_big_endian(11), expected);
+    }
+    #[test]
+    fn test_connect_payload() {
+        let expected = vec![
+            16, 23, 0, 4, 77, 81, 84, 84, 4, 2, 0, 5, 0, 11, 99, 108, 105, 101, 110, 116, 95, 110,
+            97, 109, 101,
+        ];
+        assert_eq!(Protocol::connect_payload("client_name", 5, None), expected);
+
+        let will = Will {
+            topic: String::from("t"),
+            message: String::from("off"),
+            retain: true,
+        };
+        let expected = vec![
+            16, 21, 0, 4, 77, 81, 84, 84, 4, 0x26, 0, 5, 0, 1, 99, 0, 1, 116, 0, 3, 111, 102, 102,
+        ];
+        assert_eq!(Protocol::connect_payload("c", 5, Some(&will)), expected);
+    }
+
+    #[test]
+    fn test_publish_payload() {
+        let expected = vec![
+            48, 22, 0, 10, 115, 111, 109, 101, 95, 116, 111, 112, 105, 99, 109, 121, 32, 109, 101,
+            115, 115, 97, 103, 101,
+        ];
+        assert_eq!(
+            Protocol::publish_payload("some_topic", "my message", false, QoS::AtMostOnce, 0),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_long_remaining_length() {
+        // Over 127 bytes, the remaining length takes a second byte and the
+        // first one has its continuation bit set
+        let client_id = "c".repeat(120);
+        let payload = Protocol::connect_payload(&client_id, 5, None);
+        assert_eq!(payload[..3], [0x10, 0x84, 0x1]);
+        let body = Protocol::read_body(&mut std::io::Cursor::new(&payload[1..])).unwrap();
+        assert_eq!(body.len(), 132);
+        assert_eq!(&body[body.len() - 120..], client_id.as_bytes());
+
+        let msg = "m".repeat(200);
+        let payload = Protocol::publish_payload("t", &msg, false, QoS::AtMostOnce, 0);
+        assert_eq!(payload[..3], [0x30, 0xcb, 0x1]);
+        let body = Protocol::read_body(&mut std::io::Cursor::new(&payload[1..])).unwrap();
+        assert_eq!(body.len(), 203);
+    }
+
+    #[test]
+    fn test_parse_connack() {
+        assert_eq!(Protocol::parse_connack(&[0x20, 2, 0, 0]), Ok(()));
+        assert_eq!(
+            Protocol::parse_connack(&[0x20, 2, 0, 4]),
+            Err(String::from(
+                "Connection refused: bad user name or password (4)"
+            ))
+        );
+        assert!(Protocol::parse_connack(&[0x30, 2, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_keepalive_while_receiving() {
+        use std::net::TcpListener;
+        use std::thread;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let broker = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut connect = [0u8; 1];
+            stream.read_exact(&mut connect).unwrap();
+            Protocol::read_body(&mut stream).unwrap();
+            stream.write_all(&[0x20, 2, 0, 0]).unwrap();
+
+            // A message every 100ms, more often than the keepalive
+            let mut reader = stream.try_clone().unwrap();
+            let sender = thread::spawn(move || {
+                for _ in 0..15 {
+                    let publish = Protocol::publish_payload("t", "m", false, QoS::AtMostOnce, 0);
+                    if stream.write_all(&publish).is_err() {
+                        break;
+                    }
+                    thread::sleep(Duration::from_millis(100));
+                }
+            });
+            reader
+                .set_read_timeout(Some(Duration::from_secs(3)))
+                .unwrap();
+            let mut header = [0u8; 1];
+            let got = reader.read_exact(&mut header).ok().map(|_| header[0]);
+            sender.join().unwrap();
+            got
+        });
+
+        let mut client = Client {
+            name: String::from("c"),
+            server: format!("127.0.0.1:{}", port).parse().unwrap(),
+            will: None,
+        };
+        let mut client = client.connect(1).unwrap();
+        for _ in 0..15 {
+            client.next_message().unwrap();
+        }
+        // PINGREQ, though messages kept coming
+        assert_eq!(broker.join().unwrap(), Some(0xc0));
+    }
+
+    #[test]
+    fn test_publish_after_reset() {
+        use std::net::TcpListener;
+        use std::thread;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let broker = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut connect = [0u8; 1];
+            stream.read_exact(&mut connect).unwrap();
+            Protocol::read_body(&mut stream).unwrap();
+            stream.write_all(&[0x20, 2, 0, 0]).unwrap();
+            // Closing with the publish unread resets the connection
+            thread::sleep(Duration::from_millis(200));
+        });
+
+        let mut client = Client {
+            name: String::from("c"),
+            server: format!("127.0.0.1:{}", port).parse().unwrap(),
+            will: None,
+        };
+        let mut client = client.connect(0).unwrap();
+        client.publish("t", "1", false, QoS::AtMostOnce).unwrap();
+        broker.join().unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert!(client.publish("t", "2", false, QoS::AtMostOnce).is_err());
+    }
+
+    #[test]
+    fn test_subscribe_payload() {
+        let expected = vec![130, 9, 0, 1, 0, 4, 97, 47, 98, 47, 1];
+        assert_eq!(
+            Protocol::subscribe_payload("a/b/", QoS::AtLeastOnce, 1)[..],
+            expected[..]
+        );
+    }
+
+    #[test]
+    fn test_parse_publish() {
+        // QoS 1, packet id 7, 200 bytes of payload
+        let mut packet = vec![0x32, 0xcf, 0x1, 0, 3, b'a', b'/', b'b', 0, 7];
+        packet.extend(vec![0xffu8; 200]);
+        let mut r = std::io::Cursor::new(&packet[1..]);
+        let body = Protocol::read_body(&mut r).unwrap();
+        assert_eq!(body.len(), 207);
+        let (msg, pid) = Protocol::parse_publish(packet[0], &body).unwrap();
+        assert_eq!(msg.topic, "a/b");
+        assert_eq!(msg.payload, vec![0xffu8; 200]);
+        assert_eq!(pid, Some(7));
+
+        let (msg, pid) = Protocol::parse_publish(0x30, &[0, 1, b'a', b'h', b'i']).unwrap();
+        assert_eq!((msg.payload.as_slice(), pid), (&b"hi"[..], None));
+        assert!(Protocol::parse_publish(0x30, &[0, 9, b'a']).is_err());
+    }
+}